@@ -1,12 +1,16 @@
 //! This module implements the central application object.
 
 use std::{convert::Into, sync::Arc};
+use std::backtrace::Backtrace;
+use std::error::Error;
 use std::sync::RwLock;
 use std::fmt;
 use std::collections::HashMap;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::net::ToSocketAddrs;
+use std::panic::resume_unwind;
 
 use serde_json::{Value};
 use serde::Serialize;
@@ -21,12 +25,17 @@ use types::{
     PencilError,
         PenHTTPError,
         PenUserError,
-
+        PenResponse,
     UserError,
     PencilResult,
     ViewFunc,
     HTTPErrorHandler,
     UserErrorHandler,
+    ErrorHandlers,
+    register_typed_error_handler,
+    http_error_handler_adapter,
+    user_error_handler_adapter,
+    teardown_request_adapter,
     BeforeRequestFunc,
     AfterRequestFunc,
     TeardownRequestFunc,
@@ -35,20 +44,31 @@ use wrappers::{
     Request,
     Response,
 };
-use helpers::{PathBound, send_from_directory_range, redirect};
+use helpers::{PathBound, SymlinkPolicy, send_from_directory_conditional, send_from_directory_index, send_asset, redirect};
+use assets::AssetSource;
+use manifest::{StaticManifest, StaticUrlStyle, StaticUrlHelper, current_script_root, static_url, with_script_root};
 use config::Config;
 use logging;
-use serving::run_server;
+use serving::{run_server, run_tls_server};
+#[cfg(unix)]
+use serving::{run_unix_server, run_listen_fds_server};
 use routing::{Map, Rule, Matcher};
 use testing::PencilClient;
 use http_errors::{HTTPError, NotFound, InternalServerError};
 use templating::{render_template, render_template_string, load_template};
 use module::Module;
-use typemap::{ShareMap, Key};
-use hyper::header::{IfModifiedSince, LastModified, HttpDate, CacheControl, CacheDirective};
-use time;
+use middleware::{Middleware, call_middlewares};
+use renderers::{ErrorRenderer, HTMLErrorRenderer, renderer_by_name};
+use panics::catch_panic;
+use debug;
+use tls::TlsConfig;
+use proxy::ProxyFix;
+#[cfg(unix)]
+use unix::UnixSocketConfig;
+use typemap::ShareMap;
+use hyper::header::{CacheControl, CacheDirective};
 
-const DEFAULT_THREADS: usize = 15;
+pub const DEFAULT_THREADS: usize = 15;
 
 
 /// The pencil type.  It acts as the central application object.  Once it is created it
@@ -78,6 +98,8 @@ pub struct Pencil {
     pub url_map: Map,
     /// All the attached modules in a hashmap by name.
     pub modules: HashMap<String, Module>,
+    /// The proxy headers trusted when the application runs behind a reverse proxy.
+    pub proxy_fix: Option<ProxyFix>,
     /// A dictionary of all view functions registered.  The key will be endpoint.
     view_functions: HashMap<String, ViewFunc>,
     before_request_funcs: Vec<Box<BeforeRequestFunc>>,
@@ -85,12 +107,24 @@ pub struct Pencil {
     teardown_request_funcs: Vec<Box<TeardownRequestFunc>>,
     http_error_handlers: HashMap<u16, Box<HTTPErrorHandler>>,
     user_error_handlers: HashMap<String, Box<UserErrorHandler>>,
+    error_handlers: ErrorHandlers,
+    middlewares: Vec<Box<dyn Middleware>>,
+    error_renderer: Option<Box<dyn ErrorRenderer>>,
+    static_cache_controls: HashMap<String, Vec<CacheDirective>>,
+    static_directory_index: Option<bool>,
+    static_assets: Option<Box<dyn AssetSource>>,
+    static_manifest: Arc<RwLock<StaticManifest>>,
+    symlink_policy: SymlinkPolicy,
+    /// The asset source templates are loaded from before the template folders.
+    #[doc(hidden)]
+    pub template_assets: Option<Box<dyn AssetSource>>,
 }
 
 fn default_config() -> Config {
     let mut config = Config::new();
     config.set("DEBUG", Value::Bool(false));
     config.set("TESTING", Value::Bool(false));
+    config.set("APPLICATION_ROOT", Value::String(String::from("/")));
     config
 }
 
@@ -121,12 +155,22 @@ impl Pencil {
             handlebars_registry: Arc::new(RwLock::new(Handlebars::new())),
             url_map: Map::new(),
             modules: HashMap::new(),
+            proxy_fix: None,
             view_functions: HashMap::new(),
             before_request_funcs: vec![],
             after_request_funcs: vec![],
             teardown_request_funcs: vec![],
             http_error_handlers: HashMap::new(),
             user_error_handlers: HashMap::new(),
+            error_handlers: vec![],
+            middlewares: vec![],
+            error_renderer: None,
+            static_cache_controls: HashMap::new(),
+            static_directory_index: None,
+            static_assets: None,
+            static_manifest: Arc::new(RwLock::new(StaticManifest::new())),
+            symlink_policy: SymlinkPolicy::default(),
+            template_assets: None,
         }
     }
 
@@ -142,6 +186,22 @@ impl Pencil {
         self.config.get_boolean("TESTING", false)
     }
 
+    /// Whether panics in view functions are re-raised instead of being
+    /// turned into internal server errors.  This field is configured from
+    /// the config with the `PROPAGATE_PANICS` configuration key.  Defaults
+    /// to the testing flag.
+    pub fn propagate_panics(&self) -> bool {
+        self.config.get_boolean("PROPAGATE_PANICS", self.is_testing())
+    }
+
+    /// Whether responses with a body in memory get an ETag and are made
+    /// conditional to `GET` and `HEAD` requests, see
+    /// `Response::make_conditional`.  This field is configured from the
+    /// config with the `USE_ETAGS` configuration key.  Defaults to `False`.
+    pub fn use_etags(&self) -> bool {
+        self.config.get_boolean("USE_ETAGS", false)
+    }
+
     /// Set the debug flag.  This field is configured from the config
     /// with the `DEBUG` configuration key.  Set this to `True` to
     /// enable debugging of the application.
@@ -156,6 +216,23 @@ impl Pencil {
         self.config.set("TESTING", Value::Bool(flag));
     }
 
+    /// The path the application is mounted at.  This field is configured
+    /// from the config with the `APPLICATION_ROOT` configuration key.
+    /// Defaults to `"/"`.
+    pub fn application_root(&self) -> String {
+        match self.config.get("APPLICATION_ROOT") {
+            Some(Value::String(root)) => root.clone(),
+            _ => String::from("/"),
+        }
+    }
+
+    /// Set the path the application is mounted at, e.g. `"/service-a"`.
+    /// URL rules are declared without it, requests outside of it are not
+    /// found and all generated URLs include it.
+    pub fn set_application_root(&mut self, root: &str) {
+        self.config.set("APPLICATION_ROOT", Value::String(root.to_string()));
+    }
+
     /// Set the template debug flag. This makes the templates
     /// live-reload every time they are rendered.
     pub fn set_template_debug(&mut self, flag: bool) {
@@ -225,25 +302,183 @@ impl Pencil {
         module.register(self);
     }
 
-    /// Enables static file handling.
+    /// Enables static file handling.  Static files get an `ETag` and a
+    /// `Last-Modified` header from their size and modification time and
+    /// conditional and range requests are answered, see
+    /// `send_from_directory_conditional`.  This registers the `static_url`
+    /// template helper too, see `static_url`.
     pub fn enable_static_file_handling(&mut self) {
         let mut rule = self.static_url_path.clone();
         rule = rule + "/<filename:path>";
         let rule_str: &str = &rule;
         self.route(rule_str, &[Method::Get], "static", send_app_static_file);
+        let root_rule = self.static_url_path.clone() + "/";
+        self.route(&root_rule as &str, &[Method::Get], "static", send_app_static_file);
+        let helper = StaticUrlHelper {
+            static_url_path: self.static_url_path.clone(),
+            manifest: self.static_manifest.clone(),
+        };
+        if let Ok(mut registry) = self.handlebars_registry.write() {
+            registry.register_helper("static_url", Box::new(helper));
+        }
     }
 
-    /// Enables static file handling with caching. (304 Not Modified + Max-Age) The static files are considered
-    /// "not modified" since the server was started.
+    /// The URL path of a static file of the application.  After
+    /// `hash_static_files` or `load_static_manifest`, the URL carries the
+    /// hash of the file content, so it changes whenever the file does and
+    /// can be cached forever.  Templates get the same URL from
+    /// `{{static_url "css/app.css"}}`.  The URL includes the script root of
+    /// the request being handled, or the application root outside of
+    /// requests, like the URLs of `url_for`.
+    pub fn static_url(&self, filename: &str) -> String {
+        let script_root = current_script_root().unwrap_or_else(|| self.application_root());
+        match self.static_manifest.read() {
+            Ok(manifest) => static_url(&script_root, &self.static_url_path, &manifest, filename),
+            Err(_) => static_url(&script_root, &self.static_url_path, &StaticManifest::new(), filename),
+        }
+    }
+
+    /// Hashes the static files of the application for `static_url`.  Call
+    /// this at startup, after the files are in place.  Requests for the
+    /// versioned URLs are answered with `Cache-Control: immutable`.
+    pub fn hash_static_files(&mut self, style: StaticUrlStyle) -> Result<(), PencilError> {
+        let manifest = match self.static_assets {
+            Some(ref source) => StaticManifest::hash_assets(source.as_ref(), style),
+            None => {
+                let mut static_path = PathBuf::from(&self.root_path);
+                static_path.push(&self.static_folder);
+                let static_path_str = static_path.to_str()
+                    .ok_or_else(|| UserError::new("The static folder path is not valid unicode"))?;
+                StaticManifest::hash_directory(static_path_str, style)?
+            },
+        };
+        self.set_static_manifest(manifest);
+        Ok(())
+    }
+
+    /// Loads a manifest written by a bundler for `static_url` instead of
+    /// hashing the static files.  The manifest is a JSON object
+    /// mapping file names to the hashed names of the files in the static
+    /// folder, like `{"app.css": "app.3f9a1c2b.css"}`.  The path is relative
+    /// to the root path of the application.
+    pub fn load_static_manifest(&mut self, path: &str) -> Result<(), PencilError> {
+        let mut source = String::new();
+        File::open(Path::new(&self.root_path).join(path))?.read_to_string(&mut source)?;
+        let manifest = StaticManifest::from_json(&source)?;
+        self.set_static_manifest(manifest);
+        Ok(())
+    }
+
+    /// Sets the versioned names of the static files for `static_url`.
+    pub fn set_static_manifest(&mut self, manifest: StaticManifest) {
+        if let Ok(mut static_manifest) = self.static_manifest.write() {
+            *static_manifest = manifest;
+        }
+    }
+
+    /// Enables static file handling with caching: static files without a
+    /// more specific policy are sent with `Cache-Control: max-age`.
     pub fn enable_static_cached_file_handling(&mut self, max_age: ::std::time::Duration) {
-        let mut rule = self.static_url_path.clone();
-        rule = rule + "/<filename:path>";
-        let rule_str: &str = &rule;
-        let mut tm = time::now_utc();
-        tm.tm_nsec = 0;
-        self.extensions.insert::<TimeAtServerStartKey>(tm);
-        self.extensions.insert::<MaxAgeKey>(max_age);
-        self.route(rule_str, &[Method::Get], "static", send_app_static_file_with_cache);
+        if max_age.as_secs() > 0 {
+            self.set_static_cache_control("*", vec![CacheDirective::MaxAge(max_age.as_secs() as u32)]);
+        }
+        self.enable_static_file_handling();
+    }
+
+    /// Sets the `Cache-Control` directives for static files with an
+    /// extension like `"css"`, or for all other static files with `"*"`.
+    /// This applies to the static files of modules too.
+    ///
+    /// ```rust,no_run
+    /// use sharp_pencil::Pencil;
+    /// use sharp_pencil::CacheDirective::{MaxAge, NoCache, Public};
+    ///
+    /// let mut app = Pencil::new("/web/demo");
+    /// app.set_static_cache_control("html", vec![NoCache]);
+    /// app.set_static_cache_control("*", vec![Public, MaxAge(86400)]);
+    /// app.enable_static_file_handling();
+    /// ```
+    pub fn set_static_cache_control(&mut self, extension: &str, directives: Vec<CacheDirective>) {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        self.static_cache_controls.insert(extension, directives);
+    }
+
+    /// Answers requests for directories in the static folders with their
+    /// `index.html` file and, if `autoindex` is set, lists directories
+    /// without one.  This applies to the static folders of modules too, see
+    /// `send_from_directory_index`.
+    pub fn enable_static_directory_index(&mut self, autoindex: bool) {
+        self.static_directory_index = Some(autoindex);
+    }
+
+    /// Sets how symlinks are treated when sending files from directories,
+    /// like the static files of the application and its modules.  By
+    /// default symlinks are only followed to files inside the directory.
+    pub fn set_symlink_policy(&mut self, policy: SymlinkPolicy) {
+        self.symlink_policy = policy;
+    }
+
+    /// The policy for symlinks when sending files from directories.
+    pub fn symlink_policy(&self) -> SymlinkPolicy {
+        self.symlink_policy
+    }
+
+    /// Serves the static files of the application from an asset source, like
+    /// files embedded into the binary, instead of the static folder.  See
+    /// `EmbeddedAssets`.
+    pub fn set_static_assets<S: AssetSource + 'static>(&mut self, source: S) {
+        self.static_assets = Some(Box::new(source));
+    }
+
+    /// Loads templates from an asset source, like files embedded into the
+    /// binary, before looking into the template folders.
+    pub fn set_template_assets<S: AssetSource + 'static>(&mut self, source: S) {
+        self.template_assets = Some(Box::new(source));
+    }
+
+    /// Sends a file from a static folder, with the static file policies of
+    /// the application applied.
+    #[doc(hidden)]
+    pub fn send_static_file(&self, request: &Request, directory: &str, filename: &str) -> PencilResult {
+        let mut response = match self.static_directory_index {
+            Some(autoindex) => send_from_directory_index(request, directory, filename, autoindex)?,
+            None => send_from_directory_conditional(request, directory, filename, false)?,
+        };
+        self.apply_static_cache_control(filename, &mut response);
+        Ok(response)
+    }
+
+    /// Set the `Cache-Control` header of a static file response.
+    fn apply_static_cache_control(&self, filename: &str, response: &mut Response) {
+        if response.status_code == 301 {
+            return;
+        }
+        // Directories are answered with their index page.
+        let filename = if filename.is_empty() || filename.ends_with('/') {
+            filename.to_owned() + "index.html"
+        } else {
+            filename.to_owned()
+        };
+        if let Some(cache_control) = self.get_static_cache_control(&filename) {
+            response.headers.set(cache_control);
+        }
+    }
+
+    /// The `Cache-Control` header for a static file, if a policy applies.
+    pub fn get_static_cache_control(&self, filename: &str) -> Option<CacheControl> {
+        let extension = Path::new(filename).extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        extension.and_then(|extension| self.static_cache_controls.get(&extension))
+            .or_else(|| self.static_cache_controls.get("*"))
+            .map(|directives| CacheControl(directives.clone()))
+    }
+
+    /// Enables reverse proxy support.  Before routing, the remote address,
+    /// scheme, host, port and script root of each request are taken from
+    /// the `X-Forwarded-*` or `Forwarded` headers trusted by `proxy_fix`.
+    pub fn enable_proxy_fix(&mut self, proxy_fix: ProxyFix) {
+        self.proxy_fix = Some(proxy_fix);
     }
 
     /// Registers a function to run before each request.
@@ -260,21 +495,106 @@ impl Pencil {
     /// Registers a function to run at the end of each request,
     /// regardless of whether there was an error or not.
     pub fn teardown_request<F: Fn(Option<&PencilError>) + Send + Sync + 'static>(&mut self, f: F) {
+        self.teardown_request_funcs.push(teardown_request_adapter(f));
+    }
+
+    /// Registers a function to run at the end of each request, like
+    /// `teardown_request`, but the function also receives the request.
+    pub fn teardown<F: Fn(&Request, Option<&PencilError>) + Send + Sync + 'static>(&mut self, f: F) {
         self.teardown_request_funcs.push(Box::new(f));
     }
 
+    /// Registers a middleware wrapping the handling of each request.
+    /// Middleware registered first is the outermost.
+    pub fn middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    /// Sets the renderer for HTTP errors without an error handler.  It takes
+    /// precedence over the `ERROR_RENDERER` configuration key.
+    pub fn set_error_renderer<R: ErrorRenderer + 'static>(&mut self, renderer: R) {
+        self.error_renderer = Some(Box::new(renderer));
+    }
+
     /// Registers a function as one http error handler.
     /// Same to `httperrorhandler`.
     pub fn register_http_error_handler<F: Fn(HTTPError) -> PencilResult + Send + Sync + 'static>(&mut self, status_code: u16, f: F) {
-        self.http_error_handlers.insert(status_code, Box::new(f));
+        self.http_error_handlers.insert(status_code, http_error_handler_adapter(f));
     }
 
     /// Registers a function as one user error handler.
     /// Same to `usererrorhandler`.
     pub fn register_user_error_handler<F: Fn(UserError) -> PencilResult + Send + Sync + 'static>(&mut self, error_desc: &str, f: F) {
+        self.user_error_handlers.insert(error_desc.to_string(), user_error_handler_adapter(f));
+    }
+
+    /// Registers a function as one http error handler that also receives
+    /// the request, e.g. to answer with JSON or HTML depending on it:
+    ///
+    /// ```rust,no_run
+    /// use sharp_pencil::{Pencil, Request, Response};
+    /// use sharp_pencil::HTTPError;
+    ///
+    ///
+    /// fn main() {
+    ///     let mut app = Pencil::new("/web/demo");
+    ///     app.http_error_handler(404, |error: HTTPError, request: &Request| {
+    ///         let mut response = Response::from(format!("{} does not exist", request.path()));
+    ///         response.status_code = error.code();
+    ///         Ok(response)
+    ///     });
+    /// }
+    /// ```
+    pub fn http_error_handler<F: Fn(HTTPError, &Request) -> PencilResult + Send + Sync + 'static>(&mut self, status_code: u16, f: F) {
+        self.http_error_handlers.insert(status_code, Box::new(f));
+    }
+
+    /// Registers a function as one user error handler that also receives
+    /// the request.
+    pub fn user_error_handler<F: Fn(UserError, &Request) -> PencilResult + Send + Sync + 'static>(&mut self, error_desc: &str, f: F) {
         self.user_error_handlers.insert(error_desc.to_string(), Box::new(f));
     }
 
+    /// Registers a handler for errors of type `E`.  Errors returned from
+    /// views are matched by their type, the handlers of the module of the
+    /// view are tried first:
+    ///
+    /// ```rust,no_run
+    /// use std::num::ParseIntError;
+    ///
+    /// use sharp_pencil::{Pencil, PencilResult, Request, Response};
+    ///
+    ///
+    /// fn user(request: &mut Request) -> PencilResult {
+    ///     let id: u32 = request.view_args["id"].parse()?;
+    ///     Ok(Response::from(format!("User {}", id)))
+    /// }
+    ///
+    ///
+    /// fn main() {
+    ///     let mut app = Pencil::new("/web/demo");
+    ///     app.get("/user/<id:string>", "user", user);
+    ///     app.register_error_handler(|e: &ParseIntError, _: &Request| {
+    ///         let mut response = Response::from(format!("Bad id: {}", e));
+    ///         response.status_code = 400;
+    ///         Ok(response)
+    ///     });
+    /// }
+    /// ```
+    pub fn register_error_handler<E, F>(&mut self, f: F)
+        where E: Error + 'static, F: Fn(&E, &Request) -> PencilResult + Send + Sync + 'static
+    {
+        register_typed_error_handler(&mut self.error_handlers, f);
+    }
+
+    /// Registers a handler for errors of type `E`.
+    /// Same to `register_error_handler`.
+    pub fn errorhandler<E, F>(&mut self, f: F)
+        where E: Error + 'static, F: Fn(&E, &Request) -> PencilResult + Send + Sync + 'static
+    {
+        self.register_error_handler(f);
+    }
+
     /// Registers a function as one http error handler.  Example:
     ///
     /// ```rust,no_run
@@ -329,10 +649,9 @@ impl Pencil {
     /// ```
     ///
     /// The problem with this is that you have to do it in all of your views, it brings
-    /// a lot of redundance, so pencil provides another solution, currently I still
-    /// haven't got any better idea on how to store user error handlers, this feature is
-    /// really just experimental, if you have any good idea, please wake me up.  Here is
-    /// one simple example:
+    /// a lot of redundance, so pencil provides another solution.  User error handlers
+    /// are looked up by the error description, prefer `register_error_handler`, which
+    /// looks handlers up by the error type.  Here is one simple example:
     ///
     /// ```rust,no_run
     /// use std::convert;
@@ -382,13 +701,23 @@ impl Pencil {
     /// Creates a test client for this application, you can use it
     /// like this:
     ///
-    /// ```ignore
+    /// ```rust
+    /// use sharp_pencil::{Pencil, Request, PencilResult, Response};
+    /// use sharp_pencil::testing::read_body;
+    ///
+    ///
+    /// fn hello(_: &mut Request) -> PencilResult {
+    ///     Ok(Response::from("Hello"))
+    /// }
+    ///
+    /// let mut app = Pencil::new("/web/hello");
+    /// app.get("/", "hello", hello);
     /// let client = app.test_client();
-    /// let response = client.get('/');
-    /// assert!(response.code, 200);
+    /// let response = client.get("/");
+    /// assert_eq!(response.status_code, 200);
+    /// assert_eq!(read_body(response), b"Hello");
     /// ```
-    #[allow(dead_code)]
-    fn test_client(&self) -> PencilClient {
+    pub fn test_client(&self) -> PencilClient<'_> {
         PencilClient::new(self)
     }
 
@@ -472,11 +801,11 @@ impl Pencil {
     fn do_teardown_request(&self, request: &Request, e: Option<&PencilError>) {
         if let Some(module) = self.get_module(request.module_name()) {
             for func in module.teardown_request_funcs.iter().rev() {
-                func(e);
+                func(request, e);
             }
         }
         for func in self.teardown_request_funcs.iter().rev() {
-            func(e);
+            func(request, e);
         }
     }
 
@@ -485,51 +814,121 @@ impl Pencil {
         match e {
             PenHTTPError(e) => self.handle_http_error(request, e),
             PenUserError(e) => self.handle_user_error(request, e),
+            PenResponse(response) => Ok(*response),
+            e => self.handle_typed_error(request, e),
         }
     }
 
+    /// Handles an error with the handler registered for its type.
+    fn handle_typed_error(&self, request: &Request, e: PencilError) -> PencilResult {
+        {
+            let error = e.as_error();
+            if let Some(module) = self.get_module(request.module_name()) {
+                for (_, handler) in &module.error_handlers {
+                    if let Some(result) = handler(error, request) {
+                        return result;
+                    }
+                }
+            }
+            for (_, handler) in &self.error_handlers {
+                if let Some(result) = handler(error, request) {
+                    return result;
+                }
+            }
+        }
+        Err(e)
+    }
+
     /// Handles an User error.
     fn handle_user_error(&self, request: &Request, e: UserError) -> PencilResult {
         if let Some(module) = self.get_module(request.module_name()) {
             if let Some(handler) = module.user_error_handlers.get(&e.desc) {
-                return handler(e);
+                return handler(e, request);
             }
         }
         if let Some(handler) = self.user_error_handlers.get(&e.desc) {
-            return handler(e);
+            return handler(e, request);
         }
-        Err(PenUserError(e))
+        self.handle_typed_error(request, PenUserError(e))
     }
 
     /// Handles an HTTP error.
     fn handle_http_error(&self, request: &Request, e: HTTPError) -> PencilResult {
+        match self.find_http_error_handler(request, e.code()) {
+            Some(handler) => {
+                // Keep headers like `Allow` unless the handler set them itself.
+                let headers = e.headers();
+                let mut response = handler(e, request)?;
+                for header in headers.iter() {
+                    if response.headers.get_raw(header.name()).is_none() {
+                        response.headers.set_raw(header.name().to_owned(), vec![header.value_string().into_bytes()]);
+                    }
+                }
+                Ok(response)
+            },
+            None => Ok(self.error_renderer(request).render(&e, request)),
+        }
+    }
+
+    /// The handler for HTTP errors with `status_code`: the one of the module
+    /// or the one of the application.
+    fn find_http_error_handler(&self, request: &Request, status_code: u16) -> Option<&HTTPErrorHandler> {
         if let Some(module) = self.get_module(request.module_name()) {
-            if let Some(handler) = module.http_error_handlers.get(&e.code()) {
-                return handler(e);
+            if let Some(handler) = module.http_error_handlers.get(&status_code) {
+                return Some(&**handler);
             }
         }
-        if let Some(handler) = self.http_error_handlers.get(&e.code()) {
-            return handler(e);
+        self.http_error_handlers.get(&status_code).map(|handler| &**handler)
+    }
+
+    /// The renderer for HTTP errors of the request: the one of the module,
+    /// the one of the application or the one named in the config.
+    fn error_renderer(&self, request: &Request) -> &dyn ErrorRenderer {
+        if let Some(module) = self.get_module(request.module_name()) {
+            if let Some(ref renderer) = module.error_renderer {
+                return &**renderer;
+            }
+        }
+        if let Some(ref renderer) = self.error_renderer {
+            return &**renderer;
+        }
+        match self.config.get("ERROR_RENDERER").and_then(|name| name.as_str()) {
+            Some(name) => match renderer_by_name(name) {
+                Some(renderer) => renderer,
+                None => {
+                    warn!("Unknown error renderer: {}", name);
+                    &HTMLErrorRenderer
+                },
+            },
+            None => &HTMLErrorRenderer,
         }
-        Ok(e.to_response())
     }
 
     /// Default error handing that kicks in when an error occurs that is not
     /// handled.
     fn handle_error(&self, request: &Request, e: &PencilError) -> Response {
         self.log_error(request, e);
+        if self.is_debug() {
+            return debug::error_page(request, e, &Backtrace::force_capture());
+        }
         let internal_server_error = InternalServerError;
         if let Ok(response) = self.handle_http_error(request, internal_server_error) {
-            return response;
+            response
         } else {
             let e = InternalServerError;
-            return e.to_response();
+            self.error_renderer(request).render(&e, request)
         }
     }
 
     /// Logs an error.
     fn log_error(&self, request: &Request, e: &PencilError) {
-        error!("Error on {} [{}]: {}", request.path(), request.method(), e);
+        let mut chain = debug::error_chain(e.as_error()).into_iter();
+        let mut msg = format!("Error on {} [{}]: {}", request.url(), request.method(), chain.next().unwrap_or_default());
+        for cause in chain {
+            msg.push_str("\n  caused by: ");
+            msg.push_str(&cause);
+        }
+        error!("{}", msg);
     }
 
     /// Dispatches the request and performs request pre and postprocessing
@@ -582,6 +981,8 @@ impl Pencil {
     /// Renders a template from the template folder with the given context.
     /// The template name is the name of the template to be rendered.
     /// The context is the variables that should be available in the template.
+    /// Rendering errors are returned as `PenError` boxing a
+    /// `handlebars::RenderError`, register a handler for that type to handle them.
     pub fn render_template<T: Serialize>(&self, template_name: &str, context: &T)
     -> PencilResult
     {
@@ -593,6 +994,8 @@ impl Pencil {
     /// with the given context.
     /// The source is the sourcecode of the template to be rendered.
     /// The context is the variables that should be available in the template.
+    /// Rendering errors are returned as `PenError` boxing a
+    /// `handlebars::TemplateRenderError`, register a handler for that type to handle them.
     pub fn render_template_string<T: Serialize>(&self, source: &str, context: &T)
     -> PencilResult
     {
@@ -601,18 +1004,67 @@ impl Pencil {
 
     /// The actual application handler.
     pub fn handle_request(&self, request: &mut Request) -> Response {
-        request.match_request();
-        match self.full_dispatch_request(request) {
-            Ok(response) => {
+        if let Some(ref proxy_fix) = self.proxy_fix {
+            request.apply_proxy_fix(proxy_fix);
+        }
+        if request.mount(&self.application_root()) {
+            request.match_request();
+        } else {
+            request.routing_error = Some(NotFound);
+        }
+        let mut middlewares: Vec<&dyn Middleware> = self.middlewares.iter().map(|m| &**m).collect();
+        if let Some(module) = self.get_module(request.module_name()) {
+            middlewares.extend(module.middlewares.iter().map(|m| &**m));
+        }
+        let script_root = request.script_root.clone();
+        let mut response = with_script_root(&script_root, || {
+            call_middlewares(&middlewares, request, &|request: &mut Request| self.dispatch_and_teardown(request))
+        });
+        if self.use_etags() && (request.method == Method::Get || request.method == Method::Head) {
+            response.make_conditional(request);
+        }
+        response
+    }
+
+    /// Dispatch the request, handle errors and tear down.  A panic is
+    /// handled like an internal server error.
+    fn dispatch_and_teardown(&self, request: &mut Request) -> Response {
+        match catch_panic(self.is_debug(), || self.full_dispatch_request(request)) {
+            Ok(Ok(response)) => {
                 self.do_teardown_request(request, None);
-                return response;
+                response
             },
-            Err(e) => {
+            Ok(Err(e)) => {
                 let response = self.handle_error(request, &e);
                 self.do_teardown_request(request, Some(&e));
-                return response;
+                response
+            },
+            Err(panic) => {
+                error!("Panic on {} [{}]: {}", request.path(), request.method(), panic);
+                let error = InternalServerError.with_description(&panic.to_string());
+                let e = PenHTTPError(error.clone());
+                if self.propagate_panics() {
+                    self.do_teardown_request(request, Some(&e));
+                    resume_unwind(panic.payload);
+                }
+                let response = if self.is_debug() {
+                    debug::panic_page(request, &panic)
+                } else {
+                    // The 500 handler gets the panic, the default error page
+                    // doesn't show it to clients.
+                    let error = match self.find_http_error_handler(request, 500) {
+                        Some(_) => error,
+                        None => InternalServerError,
+                    };
+                    match catch_panic(false, || self.handle_http_error(request, error)) {
+                        Ok(Ok(response)) => response,
+                        _ => InternalServerError.to_response(),
+                    }
+                };
+                self.do_teardown_request(request, Some(&e));
+                response
             }
-        };
+        }
     }
 
     /// Runs the application on a hyper HTTP server.
@@ -624,6 +1076,55 @@ impl Pencil {
     pub fn run_threads<A: ToSocketAddrs>(self, addr: A, threads: usize) {
         run_server(self, addr, threads);
     }
+
+    /// Runs the application on a hyper HTTPS server.  The certificate chain
+    /// and the private key are loaded from PEM files and reloaded whenever
+    /// they change on disk.
+    ///
+    /// ```rust,no_run
+    /// let app = sharp_pencil::Pencil::new("/web/demo");
+    /// app.run_tls("0.0.0.0:443", "/etc/demo/cert.pem", "/etc/demo/key.pem");
+    /// ```
+    pub fn run_tls<A: ToSocketAddrs>(self, addr: A, cert_path: &str, key_path: &str) {
+        run_tls_server(self, addr, TlsConfig::new(cert_path, key_path), DEFAULT_THREADS);
+    }
+
+    /// Runs the application on a hyper HTTPS server with the given TLS configuration.
+    pub fn run_tls_threads<A: ToSocketAddrs>(self, addr: A, tls_config: TlsConfig, threads: usize) {
+        run_tls_server(self, addr, tls_config, threads);
+    }
+
+    /// Runs the application on a Unix domain socket, e.g. behind a local
+    /// reverse proxy.  A stale socket file at `path` is replaced.
+    ///
+    /// ```rust,no_run
+    /// let app = sharp_pencil::Pencil::new("/web/demo");
+    /// app.run_unix("/run/demo/demo.sock");
+    /// ```
+    #[cfg(unix)]
+    pub fn run_unix(self, path: &str) {
+        run_unix_server(self, UnixSocketConfig::new(path), DEFAULT_THREADS);
+    }
+
+    /// Runs the application on a Unix domain socket with the given socket configuration.
+    #[cfg(unix)]
+    pub fn run_unix_threads(self, unix_config: UnixSocketConfig, threads: usize) {
+        run_unix_server(self, unix_config, threads);
+    }
+
+    /// Runs the application on the sockets passed by the service manager
+    /// (systemd-style socket activation with `LISTEN_PID` and `LISTEN_FDS`).
+    /// Both TCP and Unix domain sockets are supported.
+    #[cfg(unix)]
+    pub fn run_listen_fds(self) {
+        run_listen_fds_server(self, DEFAULT_THREADS);
+    }
+
+    /// Runs the application on the sockets passed by the service manager.
+    #[cfg(unix)]
+    pub fn run_listen_fds_threads(self, threads: usize) {
+        run_listen_fds_server(self, threads);
+    }
 }
 
 impl hyper::server::Handler for Pencil {
@@ -670,62 +1171,31 @@ fn send_app_static_file(request: &mut Request) -> PencilResult {
     let mut static_path = PathBuf::from(&request.app.root_path);
     static_path.push(&request.app.static_folder);
     let static_path_str = static_path.to_str().unwrap();
-    let filename = request.view_args.get("filename").unwrap();
-    send_from_directory_range(static_path_str, filename, false, request.headers().get())
-}
-
-
-
-fn check_if_cached(req: &mut Request) -> Option<PencilResult> {
-
-    let mod_time = req.app.extensions.get::<TimeAtServerStartKey>().expect("TimeAtServerStartKey should've been set up.");
-
-    match req.headers().get::<IfModifiedSince>() {
-        Some(&IfModifiedSince(HttpDate(tm))) if tm >= *mod_time => {
-            let mut cached_resp = Response::new_empty();
-            cached_resp.status_code = 304;
-            return Some(Ok(cached_resp));
+    let filename = request.view_args.get("filename").map_or("", |filename| filename.as_str());
+    let resolved = match request.app.static_manifest.read() {
+        Ok(manifest) => manifest.resolve(filename, request.args().get::<str>("v")).map(|file| file.to_owned()),
+        Err(_) => None,
+    };
+    let mut response = match request.app.static_assets {
+        Some(ref source) => {
+            let file = resolved.as_ref().map_or(filename, |file| file.as_str());
+            let mut response = send_asset(request, source.as_ref(), file)?;
+            request.app.apply_static_cache_control(file, &mut response);
+            response
         },
-        None => { // No caching requested
-            return None;
+        None => {
+            let file = resolved.as_ref().map_or(filename, |file| file.as_str());
+            request.app.send_static_file(request, static_path_str, file)?
         },
-        Some(_) => { // Stale cache
-            return None;
-        }
-    }
-}
-
-struct MaxAgeKey;
-
-impl Key for MaxAgeKey {
-    type Value = ::std::time::Duration;
-}
-
-struct TimeAtServerStartKey;
-
-impl Key for TimeAtServerStartKey {
-    type Value = time::Tm;
-}
-
-/// View function used internally to send static files from the static folder
-/// to the browser. Using cache, and serving 304 Not Modified if possible.
-fn send_app_static_file_with_cache(request: &mut Request) -> PencilResult {
-    if let Some(resp) = check_if_cached(request) {
-        return resp;
+    };
+    // A versioned URL always has the same content.
+    if resolved.is_some() && (response.status_code / 100 == 2 || response.status_code == 304) {
+        response.headers.set(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(31_536_000),
+            CacheDirective::Extension(String::from("immutable"), None),
+        ]));
     }
-    let mut static_path = PathBuf::from(&request.app.root_path);
-    static_path.push(&request.app.static_folder);
-    let static_path_str = static_path.to_str().unwrap();
-    let filename = request.view_args.get("filename").unwrap();
-    let resp = send_from_directory_range(static_path_str, filename, false, request.headers().get());
-    resp.map(|mut r| {
-        let mod_time = request.app.extensions.get::<TimeAtServerStartKey>().expect("TimeAtServerStartKey should've been set up.");
-        r.headers.set(LastModified(HttpDate(*mod_time)));
-        let max_age = request.app.extensions.get::<MaxAgeKey>().expect("MaxAgeKey should've been set up.").as_secs();
-        if max_age > 0 {
-            r.headers.set(CacheControl(vec![CacheDirective::MaxAge(max_age as u32)]));
-        }
-        r
-    })
+    Ok(response)
 }
 
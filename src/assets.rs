@@ -0,0 +1,244 @@
+//! This module implements asset sources, like files embedded into the binary.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use mime::Mime;
+use mime_guess::guess_mime_type;
+
+use helpers::{safe_join_with, SymlinkPolicy};
+use httputils::generate_etag;
+
+
+/// A file from an asset source.
+#[derive(Clone, Debug)]
+pub struct Asset {
+    /// The contents of the file.
+    pub data: Cow<'static, [u8]>,
+    /// The mimetype of the file.
+    pub mimetype: Mime,
+    /// The strong ETag value of the file, without quotes.
+    pub etag: String,
+    /// The modification time in seconds since the epoch, if known.
+    pub modified: Option<i64>,
+}
+
+impl Asset {
+    /// The size of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+
+/// A source of static files or templates other than the static and template
+/// folders, like files embedded into the binary.  Paths are relative and use
+/// `/` as separator.
+pub trait AssetSource: Send + Sync {
+    /// Get the file at `path`, if the source has one.
+    fn get(&self, path: &str) -> Option<Asset>;
+
+    /// The paths of the files of the source, if it can list them.
+    fn list(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+
+/// One file embedded with `embed_directory`.
+#[derive(Clone, Copy, Debug)]
+pub struct EmbeddedFile {
+    /// The path of the file relative to the embedded directory.
+    pub path: &'static str,
+    /// The contents of the file.
+    pub data: &'static [u8],
+    /// The mimetype of the file.
+    pub mimetype: &'static str,
+    /// The strong ETag value of the file, computed at build time.
+    pub etag: &'static str,
+    /// The modification time of the file at build time.
+    pub modified: Option<i64>,
+}
+
+
+/// Files embedded into the binary at compile time.  Create these with
+/// `embed_directory` in the build script and `include_assets!`:
+///
+/// ```rust,ignore
+/// // build.rs
+/// extern crate sharp_pencil;
+///
+/// fn main() {
+///     sharp_pencil::embed_directory("static", "static").unwrap();
+///     sharp_pencil::embed_directory("templates", "templates").unwrap();
+/// }
+/// ```
+///
+/// ```rust,ignore
+/// // main.rs
+/// #[macro_use]
+/// extern crate sharp_pencil;
+///
+/// use sharp_pencil::Pencil;
+///
+/// fn main() {
+///     let mut app = Pencil::new("/web/app");
+///     app.set_static_assets(include_assets!("static"));
+///     app.set_template_assets(include_assets!("templates"));
+///     app.enable_static_file_handling();
+///     app.register_template("index.html");
+///     app.run("127.0.0.1:5000");
+/// }
+/// ```
+///
+/// Debug builds read the files from the embedded directory as long as it
+/// exists, so edits show up without rebuilding.
+pub struct EmbeddedAssets {
+    files: HashMap<&'static str, EmbeddedFile>,
+    live_directory: Option<PathBuf>,
+}
+
+impl EmbeddedAssets {
+    /// Create the source from embedded files.
+    pub fn new(files: &[EmbeddedFile]) -> EmbeddedAssets {
+        EmbeddedAssets {
+            files: files.iter().map(|file| (file.path, *file)).collect(),
+            live_directory: None,
+        }
+    }
+
+    /// Read the files from `directory` instead while it exists, falling back
+    /// to the embedded files.
+    pub fn live(mut self, directory: &str) -> EmbeddedAssets {
+        self.live_directory = Some(PathBuf::from(directory));
+        self
+    }
+
+    /// The paths of the embedded files, see `AssetSource::list`.
+    pub fn paths(&self) -> Vec<&'static str> {
+        let mut paths: Vec<&'static str> = self.files.keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    fn get_live(&self, path: &str) -> Option<Asset> {
+        let directory = self.live_directory.as_ref()?.to_str()?;
+        let filepath = safe_join_with(directory, path, SymlinkPolicy::default())?;
+        let mut data = Vec::new();
+        File::open(&filepath).and_then(|mut file| file.read_to_end(&mut data)).ok()?;
+        let modified = fs::metadata(&filepath).ok().and_then(|metadata| modified_secs(&metadata));
+        Some(Asset {
+            etag: generate_etag(&data),
+            data: Cow::Owned(data),
+            mimetype: guess_mime_type(&filepath),
+            modified,
+        })
+    }
+}
+
+impl AssetSource for EmbeddedAssets {
+    fn get(&self, path: &str) -> Option<Asset> {
+        if let Some(asset) = self.get_live(path) {
+            return Some(asset);
+        }
+        self.files.get(path).map(|file| {
+            Asset {
+                data: Cow::Borrowed(file.data),
+                mimetype: file.mimetype.parse().unwrap_or_else(|_| guess_mime_type(file.path)),
+                etag: file.etag.to_owned(),
+                modified: file.modified,
+            }
+        })
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.paths().into_iter().map(String::from).collect()
+    }
+}
+
+impl fmt::Debug for EmbeddedAssets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<EmbeddedAssets {} files>", self.files.len())
+    }
+}
+
+
+/// Include the files embedded by `embed_directory` under `name` as
+/// `EmbeddedAssets`.
+#[macro_export]
+macro_rules! include_assets {
+    ($name:expr) => {
+        include!(concat!(env!("OUT_DIR"), "/", $name, ".rs"))
+    }
+}
+
+
+/// Embed the files of `directory` into the binary.  Call this from the build
+/// script of your crate, the files can then be included as `EmbeddedAssets`
+/// with `include_assets!(name)`.  The mimetypes and ETags of the files are
+/// computed here, and the build script reruns when the files change.
+pub fn embed_directory(directory: &str, name: &str) -> io::Result<()> {
+    let out_dir = env::var("OUT_DIR")
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "OUT_DIR is not set, call this from a build script"))?;
+    let directory = fs::canonicalize(directory)?;
+    let mut files = Vec::new();
+    collect_files(&directory, &mut files)?;
+    files.sort();
+
+    let mut source = String::from("{\n    let assets = ::sharp_pencil::EmbeddedAssets::new(&[\n");
+    for filepath in &files {
+        let relative = filepath.strip_prefix(&directory).unwrap_or(filepath);
+        let path = relative.iter()
+            .map(|component| component.to_string_lossy().into_owned())
+            .collect::<Vec<String>>()
+            .join("/");
+        let mut data = Vec::new();
+        File::open(filepath)?.read_to_end(&mut data)?;
+        let modified = match modified_secs(&fs::metadata(filepath)?) {
+            Some(modified) => format!("Some({})", modified),
+            None => String::from("None"),
+        };
+        source.push_str(&format!(
+            "        ::sharp_pencil::EmbeddedFile {{ path: {:?}, data: include_bytes!({:?}), mimetype: {:?}, etag: {:?}, modified: {} }},\n",
+            path, filepath, guess_mime_type(filepath).to_string(), generate_etag(&data), modified));
+        println!("cargo:rerun-if-changed={}", filepath.display());
+    }
+    source.push_str("    ]);\n");
+    source.push_str(&format!("    if cfg!(debug_assertions) {{ assets.live({:?}) }} else {{ assets }}\n}}\n", directory));
+    println!("cargo:rerun-if-changed={}", directory.display());
+
+    let mut out_file = File::create(Path::new(&out_dir).join(format!("{}.rs", name)))?;
+    out_file.write_all(source.as_bytes())
+}
+
+/// Collect the files below `directory` recursively.
+fn collect_files(directory: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            println!("cargo:rerun-if-changed={}", path.display());
+            collect_files(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The modification time of a file in seconds since the epoch.
+fn modified_secs(metadata: &fs::Metadata) -> Option<i64> {
+    metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs() as i64)
+}
@@ -0,0 +1,172 @@
+//! This module implements the listings of static directories.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use serde_json::{Map, Value};
+use time;
+use url::percent_encoding::{percent_decode, utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use helpers::SymlinkPolicy;
+use http_errors::{NotAcceptable, NotFound};
+use json::jsonify;
+use types::{PenHTTPError, PencilResult};
+use wrappers::Request;
+
+
+/// The template replacing the built-in listing template if registered.
+const AUTOINDEX_TEMPLATE: &str = "autoindex.html";
+
+const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Index of {{path}}</title></head>
+<body>
+<h1>Index of {{path}}</h1>
+<table>
+<thead><tr>
+<th><a href="?sort=name&amp;order={{next_order.name}}">Name</a></th>
+<th><a href="?sort=size&amp;order={{next_order.size}}">Size</a></th>
+<th><a href="?sort=mtime&amp;order={{next_order.mtime}}">Last modified</a></th>
+</tr></thead>
+<tbody>
+{{#if parent}}<tr><td><a href="../">../</a></td><td></td><td></td></tr>
+{{/if}}{{#each entries}}<tr><td><a href="{{href}}">{{name}}{{#if is_dir}}/{{/if}}</a></td><td>{{#unless is_dir}}{{size}}{{/unless}}</td><td>{{modified}}</td></tr>
+{{/each}}</tbody>
+</table>
+</body>
+</html>
+"#;
+
+
+/// One entry of a directory listing.
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    mtime: Option<i64>,
+}
+
+impl Entry {
+    fn to_json(&self) -> Value {
+        let mut href = utf8_percent_encode(&self.name, PATH_SEGMENT_ENCODE_SET).to_string();
+        if self.is_dir {
+            href.push('/');
+        }
+        let modified = match self.mtime {
+            Some(secs) => time::strftime("%Y-%m-%d %H:%M", &time::at_utc(time::Timespec::new(secs, 0)))
+                .unwrap_or_default(),
+            None => String::new(),
+        };
+        let mut entry = Map::new();
+        entry.insert(String::from("name"), Value::from(self.name.clone()));
+        entry.insert(String::from("href"), Value::from(href));
+        entry.insert(String::from("is_dir"), Value::from(self.is_dir));
+        entry.insert(String::from("size"), Value::from(self.size));
+        entry.insert(String::from("mtime"), self.mtime.map_or(Value::Null, Value::from));
+        entry.insert(String::from("modified"), Value::from(modified));
+        Value::Object(entry)
+    }
+}
+
+
+/// Lists `directory`, which is inside `root`.  Entries resolving outside
+/// `root`, like symlinks pointing elsewhere, are left out, and so are all
+/// symlinks with `SymlinkPolicy::Deny`.  The listing is
+/// sorted by the `sort` (`name`, `size` or `mtime`) and `order` (`asc` or
+/// `desc`) query arguments, directories first, and is rendered as HTML or
+/// JSON depending on the `Accept` header.
+pub fn render_listing(request: &Request, root: &Path, directory: &Path, policy: SymlinkPolicy) -> PencilResult {
+    let root = fs::canonicalize(root).map_err(|_| PenHTTPError(NotFound))?;
+    let directory = fs::canonicalize(directory).map_err(|_| PenHTTPError(NotFound))?;
+    if !directory.starts_with(&root) {
+        return Err(PenHTTPError(NotFound));
+    }
+
+    let mut entries = Vec::new();
+    for dir_entry in fs::read_dir(&directory)? {
+        let path = dir_entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        match fs::canonicalize(&path) {
+            Ok(ref resolved) if resolved.starts_with(&root) => (),
+            _ => continue,
+        }
+        let is_symlink = path.symlink_metadata()
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(true);
+        if is_symlink && policy == SymlinkPolicy::Deny {
+            continue;
+        }
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let is_dir = metadata.is_dir();
+        let mtime = metadata.modified().ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs() as i64);
+        entries.push(Entry {
+            name,
+            is_dir,
+            size: if is_dir { 0 } else { metadata.len() },
+            mtime,
+        });
+    }
+
+    let args = request.args();
+    let sort = match args.get::<str>("sort") {
+        Some("size") => "size",
+        Some("mtime") => "mtime",
+        _ => "name",
+    };
+    let order = match args.get::<str>("order") {
+        Some("desc") => "desc",
+        _ => "asc",
+    };
+    entries.sort_by(|a, b| {
+        let ordering = match sort {
+            "size" => a.size.cmp(&b.size),
+            "mtime" => a.mtime.cmp(&b.mtime),
+            _ => Ordering::Equal,
+        }.then_with(|| a.name.cmp(&b.name));
+        let ordering = if order == "desc" { ordering.reverse() } else { ordering };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    });
+    // Clicking the column the listing is sorted by reverses the order.
+    let mut next_order = Map::new();
+    for &column in &["name", "size", "mtime"] {
+        let next = if column == sort && order == "asc" { "desc" } else { "asc" };
+        next_order.insert(String::from(column), Value::from(next));
+    }
+
+    let path = request.path();
+    let mut listing = Map::new();
+    listing.insert(String::from("path"), Value::from(percent_decode(path.as_bytes()).decode_utf8_lossy().into_owned()));
+    listing.insert(String::from("parent"), Value::from(directory != root));
+    listing.insert(String::from("sort"), Value::from(sort));
+    listing.insert(String::from("order"), Value::from(order));
+    listing.insert(String::from("next_order"), Value::Object(next_order));
+    listing.insert(String::from("entries"), Value::Array(entries.iter().map(Entry::to_json).collect()));
+    let listing = Value::Object(listing);
+    let mut response = match request.accept_mimetypes().best_match(&["text/html", "application/json"]) {
+        Some("text/html") => {
+            let registered = request.app.handlebars_registry.read()
+                .map(|registry| registry.has_template(AUTOINDEX_TEMPLATE))
+                .unwrap_or(false);
+            if registered {
+                request.app.render_template(AUTOINDEX_TEMPLATE, &listing)?
+            } else {
+                request.app.render_template_string(DEFAULT_TEMPLATE, &listing)?
+            }
+        },
+        Some(_) => jsonify(&listing)?,
+        None => return Err(PenHTTPError(NotAcceptable.with_raw_header("Vary", "Accept"))),
+    };
+    response.add_vary("Accept");
+    Ok(response)
+}
@@ -0,0 +1,371 @@
+//! This module implements compressing responses on the fly.
+
+use std::io::{self, Write};
+
+use brotli::CompressorWriter;
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use hyper::header::{CacheControl, CacheDirective, ContentEncoding, ContentLength, ContentRange,
+                    ContentType, ETag, EntityTag, Headers, IfMatch, IfNoneMatch, IfRange};
+
+use middleware::Middleware;
+use wrappers::{BodyWrite, Request, Response, ResponseBody};
+
+
+/// The brotli quality, fast enough to compress while sending.
+const BROTLI_QUALITY: u32 = 5;
+/// The brotli window size, as a power of two.
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+
+/// A content coding the middleware can produce.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Coding {
+    /// The brotli format (RFC 7932).
+    Brotli,
+    /// The gzip format (RFC 1952).
+    Gzip,
+    /// The zlib format (RFC 1950), which HTTP calls `deflate`.
+    Deflate,
+}
+
+/// The codings in the order the server prefers them.
+const CODINGS: &[Coding] = &[Coding::Brotli, Coding::Gzip, Coding::Deflate];
+
+impl Coding {
+    /// The name of the coding in `Content-Encoding`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Coding::Brotli => "br",
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+}
+
+
+/// Compresses everything written to it with one of the codings.  Flushing
+/// sends everything written so far, so streamed bodies arrive in time.
+pub enum Encoder<W: Write> {
+    Brotli(Box<CompressorWriter<W>>),
+    Gzip(GzEncoder<W>),
+    Deflate(ZlibEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Create an encoder writing the compressed stream to `inner`.
+    pub fn new(coding: Coding, inner: W) -> Encoder<W> {
+        match coding {
+            Coding::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(inner, BROTLI_BUFFER_SIZE, BROTLI_QUALITY, BROTLI_WINDOW))),
+            Coding::Gzip => Encoder::Gzip(GzEncoder::new(inner, Compression::default())),
+            Coding::Deflate => Encoder::Deflate(ZlibEncoder::new(inner, Compression::default())),
+        }
+    }
+
+    /// End the stream and return the inner writer.  Brotli doesn't report
+    /// errors writing the end of the stream, the next write to the inner
+    /// writer does.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Encoder::Brotli(ref mut encoder) => encoder.write(buf),
+            Encoder::Gzip(ref mut encoder) => encoder.write(buf),
+            Encoder::Deflate(ref mut encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Encoder::Brotli(ref mut encoder) => encoder.flush(),
+            Encoder::Gzip(ref mut encoder) => encoder.flush(),
+            Encoder::Deflate(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Compress `data` in one go.
+pub fn compress(coding: Coding, data: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::new(coding, Vec::new());
+    encoder.write_all(data).expect("Writing to a vector can't fail");
+    encoder.finish().expect("Writing to a vector can't fail")
+}
+
+
+/// A body compressed while it is written, used for bodies not in memory.
+struct CompressedBody {
+    coding: Coding,
+    body: Box<dyn BodyWrite>,
+}
+
+impl BodyWrite for CompressedBody {
+    fn write_body(&mut self, body: &mut ResponseBody) -> io::Result<()> {
+        let mut encoder = Encoder::new(self.coding, body);
+        self.body.write_body(&mut ResponseBody::new(&mut encoder))?;
+        encoder.finish().map(|_| ())
+    }
+}
+
+
+/// Middleware compressing responses with brotli, gzip or deflate, whichever
+/// the `Accept-Encoding` header of the client prefers:
+///
+/// ```rust,no_run
+/// use sharp_pencil::{Compress, Pencil};
+///
+/// fn main() {
+///     let mut app = Pencil::new("/web/demo");
+///     app.middleware(Compress::new());
+/// }
+/// ```
+///
+/// Bodies in memory are compressed right away and get a new
+/// `Content-Length`, other bodies like files are compressed while they are
+/// sent.  Small bodies, responses with a `Content-Encoding` or a
+/// `Content-Range`, responses marked `no-transform` and mimetypes not in
+/// `mimetypes` are left alone.  Compressed responses get a `Vary:
+/// Accept-Encoding` header and an ETag with the coding appended, so they are
+/// not confused with the uncompressed ones.  The coding is removed from the
+/// ETags in the conditional headers of the request before the view sees
+/// them, so views validating them against their own ETags still answer
+/// `304 Not Modified` for compressed copies.
+#[derive(Clone, Debug)]
+pub struct Compress {
+    /// Bodies with fewer bytes than this are not compressed.
+    pub min_size: usize,
+    /// Mimetypes that are compressed.  An entry ending with a slash like
+    /// `text/` matches all mimetypes of that type, an entry starting with a
+    /// plus like `+json` matches all mimetypes with that suffix.
+    pub mimetypes: Vec<String>,
+}
+
+impl Default for Compress {
+    fn default() -> Compress {
+        Compress::new()
+    }
+}
+
+impl Compress {
+    /// Compress text, JSON, JavaScript, XML and SVG bodies of at least 500 bytes.
+    pub fn new() -> Compress {
+        let mimetypes = ["text/", "application/json", "application/javascript", "application/xml",
+                         "image/svg+xml", "+json", "+xml"];
+        Compress {
+            min_size: 500,
+            mimetypes: mimetypes.iter().map(|mimetype| mimetype.to_string()).collect(),
+        }
+    }
+
+    /// Whether the mimetype is worth compressing.
+    pub fn is_compressible(&self, mimetype: &str) -> bool {
+        let mimetype = mimetype.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        self.mimetypes.iter().any(|pattern| {
+            if pattern.ends_with('/') {
+                mimetype.starts_with(&pattern[..])
+            } else if pattern.starts_with('+') {
+                mimetype.ends_with(&pattern[..])
+            } else {
+                mimetype == *pattern
+            }
+        })
+    }
+
+    /// Whether the response can be compressed at all.
+    fn is_eligible(&self, response: &Response) -> bool {
+        if response.status_code < 200 || response.status_code == 204 || response.status_code == 304 {
+            return false;
+        }
+        if response.body.is_none() || response.headers.has::<ContentEncoding>() || response.headers.has::<ContentRange>() {
+            return false;
+        }
+        if let Some(CacheControl(directives)) = response.headers.get::<CacheControl>() {
+            if directives.contains(&CacheDirective::NoTransform) {
+                return false;
+            }
+        }
+        let size = match response.body.as_ref().and_then(|body| body.bytes()) {
+            Some(bytes) => Some(bytes.len()),
+            None => response.content_length(),
+        };
+        if size.is_some_and(|size| size < self.min_size) {
+            return false;
+        }
+        match response.headers.get::<ContentType>() {
+            Some(ContentType(mimetype)) => self.is_compressible(&mimetype.to_string()),
+            None => false,
+        }
+    }
+
+    /// The coding the client prefers, if any.
+    fn negotiate(&self, request: &Request) -> Option<Coding> {
+        let accept = request.accept_encodings();
+        if !accept.provided() {
+            return None;
+        }
+        let mut offered: Vec<&str> = CODINGS.iter().map(|coding| coding.name()).collect();
+        offered.push("identity");
+        let best = accept.best_match(&offered)?;
+        CODINGS.iter().cloned().find(|coding| coding.name() == best)
+    }
+
+    /// Compress the response with the coding.
+    fn compress_response(&self, response: &mut Response, coding: Coding) {
+        let body = match response.body.take() {
+            Some(body) => body,
+            None => return,
+        };
+        match body.bytes().map(|bytes| compress(coding, bytes)) {
+            Some(compressed) => {
+                response.set_content_length(compressed.len());
+                response.body = Some(Box::new(compressed));
+            },
+            None => {
+                response.headers.remove::<ContentLength>();
+                response.body = Some(Box::new(CompressedBody { coding, body }));
+            },
+        }
+        response.headers.set_raw("Content-Encoding", vec![coding.name().as_bytes().to_vec()]);
+        add_etag_coding(response, coding);
+    }
+}
+
+/// Append the coding to the ETag of a compressed response.
+fn add_etag_coding(response: &mut Response, coding: Coding) {
+    if let Some(etag) = response.get_etag().cloned() {
+        let tag = format!("{}-{}", etag.tag(), coding.name());
+        response.headers.set(ETag(EntityTag::new(etag.weak, tag)));
+    }
+}
+
+/// Remove the coding appended by `add_etag_coding`, if it was appended.
+fn strip_etag_coding(etag: &EntityTag, coding: Coding, stripped: &mut bool) -> EntityTag {
+    match etag.tag().strip_suffix(&format!("-{}", coding.name())[..]) {
+        Some(tag) => {
+            *stripped = true;
+            EntityTag::new(etag.weak, tag.to_owned())
+        },
+        None => etag.clone(),
+    }
+}
+
+/// Remove the coding from the ETags of the conditional headers, returns
+/// whether any ETag had it.
+fn strip_conditional_coding(headers: &mut Headers, coding: Coding) -> bool {
+    let mut stripped = false;
+    if let Some(IfNoneMatch::Items(etags)) = headers.get::<IfNoneMatch>().cloned() {
+        let etags = etags.iter().map(|etag| strip_etag_coding(etag, coding, &mut stripped)).collect();
+        headers.set(IfNoneMatch::Items(etags));
+    }
+    if let Some(IfMatch::Items(etags)) = headers.get::<IfMatch>().cloned() {
+        let etags = etags.iter().map(|etag| strip_etag_coding(etag, coding, &mut stripped)).collect();
+        headers.set(IfMatch::Items(etags));
+    }
+    if let Some(IfRange::EntityTag(etag)) = headers.get::<IfRange>().cloned() {
+        headers.set(IfRange::EntityTag(strip_etag_coding(&etag, coding, &mut stripped)));
+    }
+    stripped
+}
+
+impl Middleware for Compress {
+    fn call(&self, request: &mut Request, next: &dyn Fn(&mut Request) -> Response) -> Response {
+        let coding = self.negotiate(request);
+        // The application compares the conditional headers with the ETag of
+        // the compressed response again, it gets them back unchanged.
+        let original_headers = request.headers.clone();
+        let stripped = coding.is_some_and(|coding| strip_conditional_coding(&mut request.headers, coding));
+        let mut response = next(request);
+        request.headers = original_headers;
+        if response.status_code == 304 {
+            // Keep the ETag of the compressed copy the client has.
+            if let Some(coding) = coding.filter(|_| stripped) {
+                add_etag_coding(&mut response, coding);
+                response.add_vary("Accept-Encoding");
+            }
+            return response;
+        }
+        if !self.is_eligible(&response) {
+            return response;
+        }
+        response.add_vary("Accept-Encoding");
+        if let Some(coding) = coding {
+            self.compress_response(&mut response, coding);
+        }
+        response
+    }
+}
+
+
+#[cfg(test)]
+fn decompress(coding: Coding, data: &[u8]) -> Vec<u8> {
+    use std::io::Read;
+
+    let mut decompressed = Vec::new();
+    match coding {
+        Coding::Brotli => brotli::Decompressor::new(data, BROTLI_BUFFER_SIZE).read_to_end(&mut decompressed),
+        Coding::Gzip => flate2::read::GzDecoder::new(data).read_to_end(&mut decompressed),
+        Coding::Deflate => flate2::read::ZlibDecoder::new(data).read_to_end(&mut decompressed),
+    }.unwrap();
+    decompressed
+}
+
+#[test]
+fn test_compress_round_trip() {
+    let repetitive = b"<tr><td>row</td></tr>\n".repeat(10_000);
+    let varied: Vec<u8> = (0..200_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    for &coding in CODINGS {
+        assert!(decompress(coding, &compress(coding, b"")).is_empty());
+        let compressed = compress(coding, &repetitive);
+        assert!(compressed.len() < repetitive.len() / 20);
+        assert!(decompress(coding, &compressed) == repetitive);
+        assert!(decompress(coding, &compress(coding, &varied)) == varied);
+    }
+}
+
+#[test]
+fn test_flushed_stream() {
+    use std::io::Read;
+
+    for &coding in CODINGS {
+        let mut encoder = Encoder::new(coding, Vec::new());
+        encoder.write_all(b"data: first\n\n").unwrap();
+        encoder.flush().unwrap();
+        // Everything written before the flush can be decompressed already.
+        let partial = match encoder {
+            Encoder::Brotli(ref encoder) => encoder.get_ref().clone(),
+            Encoder::Gzip(ref encoder) => encoder.get_ref().clone(),
+            Encoder::Deflate(ref encoder) => encoder.get_ref().clone(),
+        };
+        let mut decompressed = vec![0; 13];
+        match coding {
+            Coding::Brotli => brotli::Decompressor::new(&partial[..], BROTLI_BUFFER_SIZE).read_exact(&mut decompressed),
+            Coding::Gzip => flate2::read::GzDecoder::new(&partial[..]).read_exact(&mut decompressed),
+            Coding::Deflate => flate2::read::ZlibDecoder::new(&partial[..]).read_exact(&mut decompressed),
+        }.unwrap();
+        assert!(decompressed == b"data: first\n\n");
+        encoder.write_all(b"data: second\n\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(decompress(coding, &compressed) == b"data: first\n\ndata: second\n\n");
+    }
+}
+
+#[test]
+fn test_strip_conditional_coding() {
+    let mut headers = Headers::new();
+    headers.set(IfNoneMatch::Items(vec![EntityTag::new(false, String::from("abc-gzip")),
+                                        EntityTag::new(true, String::from("def-br"))]));
+    headers.set(IfRange::EntityTag(EntityTag::new(false, String::from("abc-gzip"))));
+    assert!(strip_conditional_coding(&mut headers, Coding::Gzip));
+    assert!(headers.get::<IfNoneMatch>() == Some(&IfNoneMatch::Items(vec![EntityTag::new(false, String::from("abc")),
+                                                                          EntityTag::new(true, String::from("def-br"))])));
+    assert!(headers.get::<IfRange>() == Some(&IfRange::EntityTag(EntityTag::new(false, String::from("abc")))));
+    assert!(!strip_conditional_coding(&mut headers, Coding::Deflate));
+}
@@ -58,6 +58,11 @@ impl Config {
         self.config.get(&key.to_string())
     }
 
+    /// An iterator over all keys and values.
+    pub fn iter(&self) -> serde_json::map::Iter<'_> {
+        self.config.iter()
+    }
+
     /// Get a boolean configuration value.  If the key doesn't exist
     /// or the value is not a `Json::Boolean`, the default value
     /// will be returned.
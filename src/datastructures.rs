@@ -113,3 +113,176 @@ impl<T> MultiDict<T> {
         self.map.values()
     }
 }
+
+
+/// How the values of an `Accept*` header match the values offered by the
+/// server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AcceptKind {
+    MimeType,
+    Language,
+    Charset,
+    Encoding,
+}
+
+/// The values of an `Accept`, `Accept-Language`, `Accept-Charset` or
+/// `Accept-Encoding` header with their qualities, best first.  Without the
+/// header every value is acceptable.
+#[derive(Clone, Debug)]
+pub struct Accept {
+    kind: AcceptKind,
+    provided: bool,
+    values: Vec<(String, f32)>,
+}
+
+impl Accept {
+    /// Parse the value of an `Accept` header.
+    pub fn mimetypes(header: Option<&str>) -> Accept {
+        Accept::parse(AcceptKind::MimeType, header)
+    }
+
+    /// Parse the value of an `Accept-Language` header.
+    pub fn languages(header: Option<&str>) -> Accept {
+        Accept::parse(AcceptKind::Language, header)
+    }
+
+    /// Parse the value of an `Accept-Charset` header.
+    pub fn charsets(header: Option<&str>) -> Accept {
+        Accept::parse(AcceptKind::Charset, header)
+    }
+
+    /// Parse the value of an `Accept-Encoding` header.
+    pub fn encodings(header: Option<&str>) -> Accept {
+        Accept::parse(AcceptKind::Encoding, header)
+    }
+
+    fn parse(kind: AcceptKind, header: Option<&str>) -> Accept {
+        let mut values = Vec::new();
+        for item in header.unwrap_or("").split(',') {
+            let mut parts = item.split(';');
+            let value = parts.next().unwrap_or("").trim();
+            if value.is_empty() {
+                continue;
+            }
+            let mut quality = Some(1.0);
+            for param in parts {
+                let mut param = param.splitn(2, '=');
+                if param.next().unwrap_or("").trim().eq_ignore_ascii_case("q") {
+                    quality = param.next().and_then(|q| q.trim().parse::<f32>().ok())
+                                           .filter(|q| (0.0..=1.0).contains(q));
+                }
+            }
+            // Values with a malformed quality are ignored.
+            if let Some(quality) = quality {
+                values.push((value.to_ascii_lowercase(), quality));
+            }
+        }
+        values.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        Accept {
+            kind,
+            provided: header.is_some(),
+            values,
+        }
+    }
+
+    /// Whether the client sent the header.
+    pub fn provided(&self) -> bool {
+        self.provided
+    }
+
+    /// The best value, if any.
+    pub fn best(&self) -> Option<&str> {
+        self.values.first().map(|(value, _)| &value[..])
+    }
+
+    /// An iterator of `(value, quality)` pairs, best first.
+    pub fn iter(&self) -> ::std::slice::Iter<'_, (String, f32)> {
+        self.values.iter()
+    }
+
+    /// The number of values.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Whether the header has no values.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The quality of `value`, taken from the most specific matching value
+    /// of the header.  Zero if the client doesn't accept it.
+    pub fn quality(&self, value: &str) -> f32 {
+        if !self.provided {
+            return 1.0;
+        }
+        let value = value.to_ascii_lowercase();
+        let mut best: Option<(u8, f32)> = None;
+        for &(ref accepted, quality) in &self.values {
+            if let Some(specificity) = self.specificity(accepted, &value) {
+                match best {
+                    Some((best_specificity, _)) if best_specificity >= specificity => (),
+                    _ => best = Some((specificity, quality)),
+                }
+            }
+        }
+        match best {
+            Some((_, quality)) => quality,
+            // Servers may always use the identity encoding unless it was refused.
+            None if self.kind == AcceptKind::Encoding && value == "identity" => 1.0,
+            None => 0.0,
+        }
+    }
+
+    /// How specific `accepted` matches `value`, `None` if it doesn't.
+    fn specificity(&self, accepted: &str, value: &str) -> Option<u8> {
+        if accepted == value {
+            return Some(3);
+        }
+        if accepted == "*" || accepted == "*/*" {
+            return Some(0);
+        }
+        match self.kind {
+            AcceptKind::MimeType => {
+                let accepted_type = accepted.trim_end_matches("/*");
+                if accepted.ends_with("/*") && value.split('/').next() == Some(accepted_type) {
+                    Some(1)
+                } else {
+                    None
+                }
+            },
+            AcceptKind::Language => {
+                let accepted = accepted.replace('_', "-");
+                let value = value.replace('_', "-");
+                if accepted == value {
+                    Some(3)
+                } else if value.starts_with(&(accepted.clone() + "-")) {
+                    Some(2)
+                } else if accepted.starts_with(&(value + "-")) {
+                    Some(1)
+                } else {
+                    None
+                }
+            },
+            AcceptKind::Charset | AcceptKind::Encoding => None,
+        }
+    }
+
+    /// The value of `matches` the client accepts with the highest quality.
+    /// On a tie the earlier value of `matches` wins, so list the values the
+    /// server prefers first.
+    pub fn best_match<'m>(&self, matches: &[&'m str]) -> Option<&'m str> {
+        let mut best: Option<(&'m str, f32)> = None;
+        for &value in matches {
+            let quality = self.quality(value);
+            if quality <= 0.0 {
+                continue;
+            }
+            match best {
+                Some((_, best_quality)) if best_quality >= quality => (),
+                _ => best = Some((value, quality)),
+            }
+        }
+        best.map(|(value, _)| value)
+    }
+}
@@ -0,0 +1,191 @@
+//! This module implements the error page shown in debug mode.
+
+use std::backtrace::Backtrace;
+use std::error::Error;
+use std::fmt::Write;
+
+use serde_json::Value;
+
+use config::Config;
+use datastructures::MultiDict;
+use helpers::escape;
+use panics::Panic;
+use types::PencilError;
+use wrappers::{Request, Response};
+
+
+/// Config keys containing one of these are not shown.
+const SECRET_KEY_PARTS: &[&str] = &["SECRET", "PASSWORD", "PASSWD", "TOKEN", "KEY", "CREDENTIAL", "PRIVATE"];
+
+/// Request headers carrying credentials, their values are not shown.
+const SECRET_HEADERS: &[&str] = &["Authorization", "Cookie", "Proxy-Authorization"];
+
+/// Whether the config value of `key` has to be hidden.
+pub fn is_secret_key(key: &str) -> bool {
+    let key = key.to_ascii_uppercase();
+    SECRET_KEY_PARTS.iter().any(|part| key.contains(part))
+}
+
+/// Whether the value of the request header `name` has to be hidden.
+pub fn is_secret_header(name: &str) -> bool {
+    SECRET_HEADERS.iter().any(|secret| secret.eq_ignore_ascii_case(name))
+}
+
+/// The error and its sources, outermost first.
+pub fn error_chain(e: &dyn Error) -> Vec<String> {
+    let mut chain = vec![e.to_string()];
+    let mut source = e.source();
+    while let Some(e) = source {
+        chain.push(e.to_string());
+        source = e.source();
+    }
+    chain
+}
+
+
+/// The debug page for an error no handler took care of.  Errors don't
+/// carry a backtrace, `backtrace` is the one of the place handling it.
+pub fn error_page(request: &Request, e: &PencilError, backtrace: &Backtrace) -> Response {
+    render(request, "Unhandled error", &error_chain(e.as_error()), None, Some(&backtrace.to_string()))
+}
+
+/// The debug page for a panic.
+pub fn panic_page(request: &Request, panic: &Panic) -> Response {
+    let chain = vec![panic.message.clone()];
+    render(request, "Panic", &chain, panic.location.as_ref().map(|l| &l[..]), panic.backtrace.as_ref().map(|b| &b[..]))
+}
+
+fn render(request: &Request, title: &str, chain: &[String], location: Option<&str>, backtrace: Option<&str>) -> Response {
+    let mut body = String::new();
+    let _ = write!(body, "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{title}: {error} // Pencil debugger</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; color: #222; }}
+h1 {{ color: #b00; }}
+table {{ border-collapse: collapse; margin-bottom: 1.5em; }}
+th, td {{ text-align: left; vertical-align: top; padding: 0.2em 1em 0.2em 0; font-family: monospace; }}
+pre {{ background: #f4f4f4; padding: 1em; overflow: auto; }}
+.hint {{ color: #777; }}
+</style>
+</head>
+<body>
+<h1>{title}: {error}</h1>
+",
+        title = escape(title.to_string()),
+        error = escape(chain.first().cloned().unwrap_or_default()));
+    for cause in chain.iter().skip(1) {
+        let _ = writeln!(body, "<p>Caused by: {}</p>", escape(cause.clone()));
+    }
+    if let Some(location) = location {
+        let _ = writeln!(body, "<p>At <code>{}</code></p>", escape(location.to_string()));
+    }
+    if let Some(backtrace) = backtrace {
+        let _ = writeln!(body, "<h2>Backtrace</h2>\n<pre>{}</pre>", escape(backtrace.to_string()));
+    }
+
+    body.push_str("<h2>Request</h2>\n");
+    let remote_addr = request.remote_addr.to_string();
+    write_table(&mut body, &[
+        ("Method", request.method().to_string()),
+        ("URL", request.url()),
+        ("Script root", request.script_root.clone()),
+        ("Remote address", remote_addr),
+    ]);
+    body.push_str("<h3>Headers</h3>\n");
+    let headers: Vec<(String, String)> = request.headers().iter()
+        .map(|header| {
+            let value = if is_secret_header(header.name()) {
+                String::from("********")
+            } else {
+                header.value_string()
+            };
+            (header.name().to_string(), value)
+        })
+        .collect();
+    write_table(&mut body, &headers);
+    body.push_str("<h3>Query arguments</h3>\n");
+    write_table(&mut body, &multi_dict_rows(request.args()));
+    body.push_str("<h3>Form</h3>\n");
+    write_table(&mut body, &multi_dict_rows(request.form()));
+
+    body.push_str("<h2>Routing</h2>\n");
+    match request.url_rule {
+        Some(ref rule) => {
+            let matcher = match rule.matcher.rule {
+                Some(ref rule) => rule.clone(),
+                None => rule.matcher.regex.as_str().to_string(),
+            };
+            write_table(&mut body, &[("Rule", matcher), ("Endpoint", rule.endpoint.clone())]);
+        },
+        None => body.push_str("<p class=\"hint\">No rule matched.</p>\n"),
+    }
+    body.push_str("<h3>View arguments</h3>\n");
+    let mut view_args: Vec<(String, String)> = request.view_args.iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    view_args.sort();
+    write_table(&mut body, &view_args);
+
+    body.push_str("<h2>Config</h2>\n");
+    write_table(&mut body, &config_rows(&request.app.config));
+    body.push_str("<p class=\"hint\">This page is only shown in debug mode.  \
+                   Never run an application in debug mode in production.</p>\n</body>\n</html>\n");
+
+    let mut response = Response::from(body);
+    response.status_code = 500;
+    response.set_content_type("text/html; charset=utf-8");
+    response
+}
+
+fn write_table<K: AsRef<str>>(body: &mut String, rows: &[(K, String)]) {
+    if rows.is_empty() {
+        body.push_str("<p class=\"hint\">None</p>\n");
+        return;
+    }
+    body.push_str("<table>\n");
+    for (key, value) in rows {
+        let _ = writeln!(body, "<tr><th>{}</th><td>{}</td></tr>",
+                         escape(key.as_ref().to_string()), escape(value.clone()));
+    }
+    body.push_str("</table>\n");
+}
+
+fn multi_dict_rows(dict: &MultiDict<String>) -> Vec<(String, String)> {
+    let mut rows: Vec<(String, String)> = dict.listiter()
+        .flat_map(|(key, values)| values.iter().map(move |value| (key.clone(), value.clone())))
+        .collect();
+    rows.sort();
+    rows
+}
+
+fn config_rows(config: &Config) -> Vec<(String, String)> {
+    let mut rows: Vec<(String, String)> = config.iter().map(|(key, value)| {
+        let value = if is_secret_key(key) {
+            String::from("********")
+        } else {
+            match *value {
+                Value::String(ref s) => s.clone(),
+                ref value => value.to_string(),
+            }
+        };
+        (key.clone(), value)
+    }).collect();
+    rows.sort();
+    rows
+}
+
+
+#[test]
+fn test_is_secret_key() {
+    assert!(is_secret_key("SECRET_KEY"));
+    assert!(is_secret_key("database_password"));
+    assert!(is_secret_key("API_TOKEN"));
+    assert!(!is_secret_key("DEBUG"));
+    assert!(!is_secret_key("APPLICATION_ROOT"));
+    assert!(is_secret_header("authorization"));
+    assert!(is_secret_header("Cookie"));
+    assert!(!is_secret_header("Accept"));
+}
@@ -0,0 +1,212 @@
+//! This module implements dispatching to several applications sharing one
+//! server.
+
+use std::fmt;
+use std::net::ToSocketAddrs;
+
+use hyper;
+use hyper::server::Request as HTTPRequest;
+use hyper::server::Response as HTTPResponse;
+use hyper::status::StatusCode;
+
+use app::{Pencil, DEFAULT_THREADS};
+use proxy::ProxyFix;
+use wrappers::{Request, Response};
+use serving::{run_server, run_tls_server};
+#[cfg(unix)]
+use serving::{run_unix_server, run_listen_fds_server};
+use tls::TlsConfig;
+#[cfg(unix)]
+use unix::UnixSocketConfig;
+
+
+/// One mounted application.
+struct Mount {
+    host: Option<String>,
+    prefix: String,
+    app: Pencil,
+}
+
+impl Mount {
+    /// How specific the mount is if it matches: mounts with a host first,
+    /// then by the length of the prefix.
+    fn matches(&self, hostname: Option<&str>, path: &str) -> Option<(bool, usize)> {
+        if let Some(ref host) = self.host {
+            match hostname {
+                Some(hostname) if hostname.eq_ignore_ascii_case(host) => (),
+                _ => return None,
+            }
+        }
+        let prefix = &self.prefix;
+        if prefix.is_empty() || path == prefix || path.starts_with(&(prefix.clone() + "/")) {
+            Some((self.host.is_some(), prefix.len()))
+        } else {
+            None
+        }
+    }
+}
+
+
+/// Dispatches requests to several `Pencil` applications by path prefix or
+/// host, so independent applications can share one server.  Every
+/// application keeps its own configuration, routes and error handlers.
+/// The matched prefix becomes part of the script root of the request, so
+/// the mounted applications build their URLs with it.  Requests no mount
+/// matches go to the default application.
+///
+/// ```rust,no_run
+/// use sharp_pencil::{Dispatcher, Pencil};
+///
+/// fn main() {
+///     let public = Pencil::new("/web/public");
+///     let admin = Pencil::new("/web/admin");
+///     let api = Pencil::new("/web/api");
+///     let mut dispatcher = Dispatcher::new(public);
+///     dispatcher.mount("/admin", admin);
+///     dispatcher.mount_host("api.example.com", api);
+///     dispatcher.run("127.0.0.1:5000");
+/// }
+/// ```
+///
+/// Host matching uses the `Host` header the server received, behind a
+/// reverse proxy enable `Dispatcher::enable_proxy_fix` to match the host the
+/// client requested.  The longest matching prefix wins, mounts with a host
+/// take precedence over mounts without one.
+pub struct Dispatcher {
+    default: Pencil,
+    mounts: Vec<Mount>,
+    proxy_fix: Option<ProxyFix>,
+}
+
+impl Dispatcher {
+    /// Create a dispatcher sending all requests to `default` until other
+    /// applications are mounted.
+    pub fn new(default: Pencil) -> Dispatcher {
+        Dispatcher {
+            default,
+            mounts: Vec::new(),
+            proxy_fix: None,
+        }
+    }
+
+    /// Mount an application at a path prefix like `/admin`.
+    pub fn mount(&mut self, prefix: &str, app: Pencil) {
+        self.add_mount(None, prefix, app);
+    }
+
+    /// Mount an application for all requests to a host.
+    pub fn mount_host(&mut self, host: &str, app: Pencil) {
+        self.add_mount(Some(host), "", app);
+    }
+
+    /// Mount an application at a path prefix of a host.
+    pub fn mount_host_prefix(&mut self, host: &str, prefix: &str, app: Pencil) {
+        self.add_mount(Some(host), prefix, app);
+    }
+
+    fn add_mount(&mut self, host: Option<&str>, prefix: &str, app: Pencil) {
+        let prefix = prefix.trim_end_matches('/');
+        if !prefix.is_empty() && !prefix.starts_with('/') {
+            panic!("Mount prefix must start with a slash: {}", prefix);
+        }
+        self.mounts.push(Mount {
+            host: host.map(|host| host.to_ascii_lowercase()),
+            prefix: prefix.to_string(),
+            app,
+        });
+    }
+
+    /// The default application and all mounted applications.
+    pub fn applications(&self) -> impl Iterator<Item=&Pencil> {
+        Some(&self.default).into_iter().chain(self.mounts.iter().map(|mount| &mount.app))
+    }
+
+    /// Find the application for the host and path and the prefix it is mounted at.
+    pub fn find_application(&self, hostname: Option<&str>, path: &str) -> (&Pencil, &str) {
+        let mut best: Option<((bool, usize), &Mount)> = None;
+        for mount in &self.mounts {
+            if let Some(rank) = mount.matches(hostname, path) {
+                match best {
+                    Some((best_rank, _)) if best_rank >= rank => (),
+                    _ => best = Some((rank, mount)),
+                }
+            }
+        }
+        match best {
+            Some((_, mount)) => (&mount.app, &mount.prefix),
+            None => (&self.default, ""),
+        }
+    }
+
+    /// Enables reverse proxy support for all applications, see
+    /// `Pencil::enable_proxy_fix`.  The headers are applied before the
+    /// application is chosen, so mounts match the forwarded host.  The
+    /// proxy fix of the mounted applications is not applied again.
+    pub fn enable_proxy_fix(&mut self, proxy_fix: ProxyFix) {
+        self.proxy_fix = Some(proxy_fix);
+    }
+
+    /// Handle a request with the application it is mounted to.  The request
+    /// may be created for any of the applications.
+    pub fn handle_request<'r>(&'r self, request: &mut Request<'r, '_, '_>) -> Response {
+        if let Some(ref proxy_fix) = self.proxy_fix {
+            request.apply_proxy_fix(proxy_fix);
+        }
+        let path = request.path();
+        let (app, prefix) = self.find_application(Some(&request.host.hostname), &path);
+        request.app = app;
+        request.mount(prefix);
+        app.handle_request(request)
+    }
+
+    /// Runs the dispatcher on a hyper HTTP server.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) {
+        run_server(self, addr, DEFAULT_THREADS);
+    }
+
+    /// Runs the dispatcher on a hyper HTTP server.
+    pub fn run_threads<A: ToSocketAddrs>(self, addr: A, threads: usize) {
+        run_server(self, addr, threads);
+    }
+
+    /// Runs the dispatcher on a hyper HTTPS server with the given TLS configuration.
+    pub fn run_tls_threads<A: ToSocketAddrs>(self, addr: A, tls_config: TlsConfig, threads: usize) {
+        run_tls_server(self, addr, tls_config, threads);
+    }
+
+    /// Runs the dispatcher on a Unix domain socket with the given socket configuration.
+    #[cfg(unix)]
+    pub fn run_unix_threads(self, unix_config: UnixSocketConfig, threads: usize) {
+        run_unix_server(self, unix_config, threads);
+    }
+
+    /// Runs the dispatcher on the sockets passed by the service manager.
+    #[cfg(unix)]
+    pub fn run_listen_fds_threads(self, threads: usize) {
+        run_listen_fds_server(self, threads);
+    }
+}
+
+impl hyper::server::Handler for Dispatcher {
+    fn handle(&self, req: HTTPRequest, mut res: HTTPResponse) {
+        debug!("Request: {}", req.uri);
+        match Request::new(&self.default, req) {
+            Ok(mut request) => {
+                let response = self.handle_request(&mut request);
+                response.write(request.method(), res);
+            }
+            Err(_) => {
+                *res.status_mut() = StatusCode::BadRequest;
+                if let Ok(w) = res.start() {
+                    let _ = w.end();
+                }
+            }
+        };
+    }
+}
+
+impl fmt::Debug for Dispatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<Dispatcher with {} mounts>", self.mounts.len())
+    }
+}
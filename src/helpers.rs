@@ -1,26 +1,39 @@
 //! This module implements various helpers.
 
-use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::fs::{self, File, Metadata};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use std::io::{Seek, Read};
-use std::io::SeekFrom::{End, Start};          
+use std::io::{self, Seek, Read, Write};
+use std::io::SeekFrom::{End, Start};
 use hyper::header::{Location, ContentType, Range, ContentRange, ContentLength};
+use hyper::header::{AcceptRanges, RangeUnit, ByteRangeSpec};
+use hyper::header::{ETag, EntityTag, LastModified, HttpDate, IfRange};
 use hyper::header::ByteRangeSpec::{FromTo, Last, AllFrom};
 use hyper::header::ContentRangeSpec::{Bytes};
 
 use mime_guess::guess_mime_type;
 use mime::Mime;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use time;
 
-use wrappers::Response;
+use wrappers::{Request, Response, ResponseBody, BodyWrite};
+use autoindex::render_listing;
+use assets::AssetSource;
+use json::jsonify;
 use types::{
+    PencilError,
     PenHTTPError,
+    PenResponse,
     PencilResult,
     UserError,
 };
 use http_errors::{
     HTTPError,
         NotFound,
+        NotAcceptable,
+        RequestedRangeNotSatisfiable,
 };
 
 
@@ -55,20 +68,74 @@ pub trait PathBound {
 }
 
 
-/// Safely join directory and filename, otherwise this returns None.
+/// Safely join directory and filename, otherwise this returns None.  The
+/// filename is normalized: `.` components are dropped and `..` components
+/// remove the previous one, so `a/../b` joins as `b`.  Absolute filenames,
+/// `..` components escaping the directory, backslashes and NUL bytes are
+/// rejected.  This does not look at the file system, symlinks are checked
+/// by `safe_join_with`.
 pub fn safe_join(directory: &str, filename: &str) -> Option<PathBuf> {
-    let directory = Path::new(directory);
-    let filename = Path::new(filename);
-    match filename.to_str() {
-        Some(filename_str) => {
-            if filename.is_absolute() | (filename_str == "..") | (filename_str.starts_with("../")) {
-                None
-            } else {
-                Some(directory.join(filename_str))
+    if filename.contains('\0') || filename.contains('\\') {
+        return None;
+    }
+    let mut parts = Vec::new();
+    for component in Path::new(filename).components() {
+        match component {
+            Component::Normal(part) => parts.push(part),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                parts.pop()?;
+            },
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    let mut path = PathBuf::from(directory);
+    path.extend(parts);
+    Some(path)
+}
+
+
+/// How symlinks below a directory are treated when joining paths with
+/// `safe_join_with`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Follow all symlinks, even ones pointing outside the directory.
+    Follow,
+    /// Follow symlinks as long as the file they resolve to is inside the
+    /// directory.  This is the default.
+    #[default]
+    WithinRoot,
+    /// Don't follow any symlinks below the directory.
+    Deny,
+}
+
+/// Safely join directory and filename like `safe_join`, and check the
+/// symlinks on the way to the file against `policy`.  Except for
+/// `SymlinkPolicy::Follow`, this returns None for files that don't exist.
+/// The files sent from directories, like the static files of the
+/// application and its modules, are looked up with this and the policy set
+/// with `Pencil::set_symlink_policy`.
+pub fn safe_join_with(directory: &str, filename: &str, policy: SymlinkPolicy) -> Option<PathBuf> {
+    let path = safe_join(directory, filename)?;
+    match policy {
+        SymlinkPolicy::Follow => (),
+        SymlinkPolicy::WithinRoot => {
+            let root = fs::canonicalize(directory).ok()?;
+            if !fs::canonicalize(&path).ok()?.starts_with(&root) {
+                return None;
+            }
+        },
+        SymlinkPolicy::Deny => {
+            let mut current = PathBuf::from(directory);
+            for component in path.strip_prefix(directory).ok()?.components() {
+                current.push(component);
+                if fs::symlink_metadata(&current).ok()?.file_type().is_symlink() {
+                    return None;
+                }
             }
         },
-        None => None,
     }
+    Some(path)
 }
 
 
@@ -78,6 +145,61 @@ pub fn abort(code: u16) -> PencilResult {
 }
 
 
+/// Stop handling the request and send `response` instead.  Unlike returning
+/// the response, this works from helper functions called with `?`:
+///
+/// ```rust,no_run
+/// use sharp_pencil::{Request, PencilError, PencilResult, Response, abort_with};
+///
+///
+/// fn require_token(request: &Request) -> Result<(), PencilError> {
+///     if request.args().get::<String>("token").is_none() {
+///         let mut response = Response::from("A token is required.");
+///         response.status_code = 401;
+///         abort_with(response)?;
+///     }
+///     Ok(())
+/// }
+///
+///
+/// fn view(request: &mut Request) -> PencilResult {
+///     require_token(request)?;
+///     Ok(Response::from("Welcome"))
+/// }
+/// ```
+pub fn abort_with(response: Response) -> PencilResult {
+    Err(PenResponse(Box::new(response)))
+}
+
+
+/// Responds with `context` as JSON or with the template rendered with
+/// `context`, whichever the `Accept` header of the client prefers.  HTML wins
+/// a tie.  Returns `NotAcceptable` if the client accepts neither.  The
+/// response varies on `Accept`:
+///
+/// ```rust,no_run
+/// use std::collections::BTreeMap;
+///
+/// use sharp_pencil::{Request, PencilResult, negotiate};
+///
+///
+/// fn user(request: &mut Request) -> PencilResult {
+///     let mut context = BTreeMap::new();
+///     context.insert("name", "admin");
+///     negotiate(request, "user.html", &context)
+/// }
+/// ```
+pub fn negotiate<T: Serialize>(request: &Request, template_name: &str, context: &T) -> PencilResult {
+    let mut response = match request.accept_mimetypes().best_match(&["text/html", "application/json"]) {
+        Some("text/html") => request.app.render_template(template_name, context)?,
+        Some(_) => jsonify(context)?,
+        None => return Err(PenHTTPError(NotAcceptable.with_raw_header("Vary", "Accept"))),
+    };
+    response.add_vary("Accept");
+    Ok(response)
+}
+
+
 /// Returns a response that redirects the client to the target location.
 pub fn redirect(location: &str, code: u16) -> PencilResult {
     let mut response = Response::from(format!(
@@ -103,7 +225,8 @@ pub fn escape(s: String) -> String {
 /// Sends the contents of a file to the client.  Please never pass filenames to this
 /// function from user sources without checking them first.  Set `as_attachment` to
 /// `true` if you want to send this file with a `Content-Disposition: attachment`
-/// header.  This will return `NotFound` if filepath is not one file.
+/// header.  This will return `NotFound` if filepath is not one file.  See
+/// `SendFile` for sending readers and choosing the download name.
 pub fn send_file(filepath: &str, mimetype: Mime, as_attachment: bool) -> PencilResult {
     let filepath = Path::new(filepath);
     if !filepath.is_file() {
@@ -115,32 +238,21 @@ pub fn send_file(filepath: &str, mimetype: Mime, as_attachment: bool) -> PencilR
             return Err(UserError::new(format!("couldn't open {}: {}", filepath.display(), e)).into());
         }
     };
+    let metadata = file.metadata().map_err(|_| PenHTTPError(HTTPError::InternalServerError))?;
     let mut response: Response = file.into();
+    set_file_validators(&mut response, &metadata);
     response.headers.set(ContentType(mimetype));
     if as_attachment {
-        match filepath.file_name() {
-            Some(file) => {
-                match file.to_str() {
-                    Some(filename) => {
-                        let content_disposition = format!("attachment; filename={}", filename);
-                        response.headers.set_raw("Content-Disposition", vec![content_disposition.as_bytes().to_vec()]);
-                    },
-                    None => {
-                        return Err(UserError::new("filename unavailable, required for sending as attachment.").into());
-                    }
-                }
-            },
-            None => {
-                return Err(UserError::new("filename unavailable, required for sending as attachment.").into());
-            }
-        }
+        set_attachment(&mut response, filepath)?;
     }
     Ok(response)
 }
 
 
 /// Sends the contents of a file to the client, supporting HTTP Range requests, so it allows only partial files
-/// to be requested and sent. This doesn't support multiranges at the moment.
+/// to be requested and sent.  Ranges are clamped to the file, overlapping ranges are
+/// coalesced and several ranges are sent as `multipart/byteranges`.  If no range can
+/// be satisfied, the response is a `416 Range Not Satisfiable`.
 /// Please never pass filenames to this
 /// function from user sources without checking them first.  Set `as_attachment` to
 /// `true` if you want to send this file with a `Content-Disposition: attachment`
@@ -152,81 +264,350 @@ pub fn send_file_range(filepath: &str, mimetype: Mime, as_attachment: bool, rang
     if !filepath.is_file() {
         return Err(PenHTTPError(NotFound));
     }
-    let mut file = match File::open(&filepath) {
+    let file = match File::open(filepath) {
         Ok(file) => file,
         Err(e) => {
             return Err(UserError::new(format!("couldn't open {}: {}", filepath.display(), e)).into());
         }
     };
 
-    let len = file.metadata().map_err(|_| PenHTTPError(HTTPError::InternalServerError))?.len();
-    let mut response: Response = match range {
-        Some(&Range::Bytes(ref vec_ranges)) => {
-            if vec_ranges.len() != 1 { return Err(PenHTTPError(HTTPError::NotImplemented)) };
-            match vec_ranges[0] {
-                FromTo(s, e) => {
-                    file.seek(Start(s))
-                        .map_err(|_| PenHTTPError(HTTPError::InternalServerError))?;
-                    let mut resp = Response::new(file.take(e-s+1));
-                    resp.status_code = 206;
-                    resp.headers.set(ContentLength(e-s+1));
-                    resp.headers.set(ContentRange(
-                        Bytes{range: Some((s, e)), instance_length: Some(len)}
-                    ));
-                    resp
-                },
-                AllFrom(s) => {
-                    file.seek(Start(s))
-                        .map_err(|_| PenHTTPError(HTTPError::InternalServerError))?;
-                    let mut resp = Response::new(file);
-                    resp.status_code = 206;
-                    resp.headers.set(ContentLength(len-s));
-                    resp.headers.set(ContentRange(
-                        Bytes{range: Some((s, len-1)), instance_length: Some(len)}
-                    ));
-                    resp
-                },
-                Last(l) => {
-                    file.seek(End(-(l as i64)))
-                        .map_err(|_| PenHTTPError(HTTPError::InternalServerError))?;
-                    let mut resp = Response::new(file);
-                    resp.status_code = 206;
-                    resp.headers.set(ContentLength(l));
-                    resp.headers.set(ContentRange(
-                        Bytes{range: Some((len-l, len-1)), instance_length: Some(len)}
-                    ));
-                    resp
-                },
-            }
+    let metadata = file.metadata().map_err(|_| PenHTTPError(HTTPError::InternalServerError))?;
+    let mut response = range_response(file, metadata.len(), mimetype, range)?;
+    set_file_validators(&mut response, &metadata);
+    if as_attachment && response.status_code != 416 {
+        set_attachment(&mut response, filepath)?;
+    }
+    Ok(response)
+}
+
+
+/// The response sending the requested ranges of a body of `len` bytes read
+/// from `reader`, or all of it.
+fn range_response<R>(mut reader: R, len: u64, mimetype: Mime, range: Option<&Range>) -> PencilResult
+    where R: Read + Seek + Send + 'static
+{
+    // Other range units are ignored.
+    let ranges = match range {
+        Some(Range::Bytes(specs)) => Some(resolve_ranges(specs, len)),
+        _ => None,
+    };
+    let mut response: Response = match ranges {
+        Some(ref ranges) if ranges.is_empty() => {
+            let mut resp = Response::from(RequestedRangeNotSatisfiable.get_body());
+            resp.status_code = 416;
+            resp.headers.set(ContentRange(Bytes{range: None, instance_length: Some(len)}));
+            return Ok(resp);
+        },
+        Some(ref ranges) if ranges.len() == 1 => {
+            let (s, e) = ranges[0];
+            reader.seek(Start(s))
+                .map_err(|_| PenHTTPError(HTTPError::InternalServerError))?;
+            let mut resp = Response::new(reader.take(e-s+1));
+            resp.status_code = 206;
+            resp.headers.set(ContentLength(e-s+1));
+            resp.headers.set(ContentRange(
+                Bytes{range: Some((s, e)), instance_length: Some(len)}
+            ));
+            resp.headers.set(ContentType(mimetype));
+            resp
+        },
+        Some(ranges) => {
+            let body = MultipartRanges::new(reader, ranges, &mimetype.to_string(), len);
+            let content_length = body.content_length();
+            let content_type = format!("multipart/byteranges; boundary={}", body.boundary);
+            let mut resp = Response::new(body);
+            resp.status_code = 206;
+            resp.headers.set(ContentLength(content_length));
+            resp.headers.set_raw("Content-Type", vec![content_type.into_bytes()]);
+            resp
         },
-        Some(_) => return Err(PenHTTPError(HTTPError::NotImplemented)),
         None => {
-            let mut resp = Response::new(file);
+            let mut resp = Response::new(reader.take(len));
             resp.headers.set(ContentLength(len));
+            resp.headers.set(ContentType(mimetype));
             resp
         },
     };
+    response.headers.set(AcceptRanges(vec![RangeUnit::Bytes]));
+    Ok(response)
+}
 
-    response.headers.set(ContentType(mimetype));
-    if as_attachment {
-        match filepath.file_name() {
-            Some(file) => {
-                match file.to_str() {
-                    Some(filename) => {
-                        let content_disposition = format!("attachment; filename={}", filename);
-                        response.headers.set_raw("Content-Disposition", vec![content_disposition.as_bytes().to_vec()]);
-                    },
-                    None => {
-                        return Err(UserError::new("filename unavailable, required for sending as attachment.").into());
-                    }
+
+/// Sends the contents of a reader, like an in-memory buffer or a generated
+/// archive, or of a file, with the same range and conditional request
+/// support the static files have:
+///
+/// ```rust,no_run
+/// use std::io::Cursor;
+///
+/// use sharp_pencil::{Request, PencilResult, SendFile};
+///
+///
+/// fn report(request: &mut Request) -> PencilResult {
+///     let csv = String::from("month,total\n2024-03,1200\n").into_bytes();
+///     SendFile::new(Cursor::new(csv))
+///         .with_download_name("Bericht März.csv")
+///         .with_attachment(true)
+///         .send(request)
+/// }
+/// ```
+///
+/// The mimetype is guessed from the download name unless one is given.  The
+/// `Content-Disposition` header is set when sending as an attachment or
+/// with a download name, names that are not printable ASCII are encoded as
+/// described in RFC 6266.
+pub struct SendFile<R> {
+    reader: R,
+    mimetype: Option<Mime>,
+    download_name: Option<String>,
+    as_attachment: bool,
+    etag: Option<EntityTag>,
+    last_modified: Option<i64>,
+}
+
+impl SendFile<File> {
+    /// Send the file at `filepath`.  Its name is the download name, and the
+    /// `ETag` and `Last-Modified` headers are derived from its size and
+    /// modification time.  This will return `NotFound` if filepath is not
+    /// one file.
+    pub fn open(filepath: &str) -> Result<SendFile<File>, PencilError> {
+        let filepath = Path::new(filepath);
+        if !filepath.is_file() {
+            return Err(PenHTTPError(NotFound));
+        }
+        let file = File::open(filepath)
+            .map_err(|e| UserError::new(format!("couldn't open {}: {}", filepath.display(), e)))?;
+        let metadata = file.metadata().map_err(|_| PenHTTPError(HTTPError::InternalServerError))?;
+        let (etag, last_modified) = file_validators(&metadata);
+        let mut send_file = SendFile::new(file);
+        send_file.mimetype = Some(guess_mime_type(filepath));
+        send_file.download_name = filepath.file_name().and_then(|name| name.to_str()).map(String::from);
+        send_file.etag = Some(etag);
+        send_file.last_modified = last_modified;
+        Ok(send_file)
+    }
+}
+
+impl<R: Read + Seek + Send + 'static> SendFile<R> {
+    /// Send the contents of `reader` from its start.
+    pub fn new(reader: R) -> SendFile<R> {
+        SendFile {
+            reader,
+            mimetype: None,
+            download_name: None,
+            as_attachment: false,
+            etag: None,
+            last_modified: None,
+        }
+    }
+
+    /// Set the mimetype instead of guessing it from the download name.
+    pub fn with_mimetype(mut self, mimetype: Mime) -> SendFile<R> {
+        self.mimetype = Some(mimetype);
+        self
+    }
+
+    /// Set the file name the client saves the contents as.
+    pub fn with_download_name(mut self, download_name: &str) -> SendFile<R> {
+        self.download_name = Some(download_name.to_owned());
+        if self.mimetype.is_none() {
+            self.mimetype = Some(guess_mime_type(Path::new(download_name)));
+        }
+        self
+    }
+
+    /// Send the contents as an attachment the client saves instead of
+    /// displaying it inline.
+    pub fn with_attachment(mut self, as_attachment: bool) -> SendFile<R> {
+        self.as_attachment = as_attachment;
+        self
+    }
+
+    /// Set the strong `ETag` of the contents.
+    pub fn with_etag(mut self, etag: &str) -> SendFile<R> {
+        self.etag = Some(EntityTag::new(false, etag.to_owned()));
+        self
+    }
+
+    /// Set the modification time of the contents.
+    pub fn with_last_modified(mut self, last_modified: SystemTime) -> SendFile<R> {
+        self.last_modified = last_modified.duration_since(UNIX_EPOCH).ok()
+            .map(|duration| duration.as_secs() as i64);
+        self
+    }
+
+    /// Send the contents, answering the `Range`, `If-Range` and
+    /// conditional headers of the request.
+    pub fn send(self, request: &Request) -> PencilResult {
+        let range = requested_range(request, self.etag.as_ref(), self.last_modified);
+        let mut response = self.respond(range)?;
+        response.make_conditional(request);
+        Ok(response)
+    }
+
+    /// Send all of the contents, without looking at a request.
+    pub fn into_response(self) -> PencilResult {
+        self.respond(None)
+    }
+
+    fn respond(mut self, range: Option<&Range>) -> PencilResult {
+        let len = self.reader.seek(End(0))
+            .map_err(|_| PenHTTPError(HTTPError::InternalServerError))?;
+        self.reader.seek(Start(0))
+            .map_err(|_| PenHTTPError(HTTPError::InternalServerError))?;
+        let mimetype = self.mimetype.unwrap_or_else(|| "application/octet-stream".parse().unwrap());
+        let mut response = range_response(self.reader, len, mimetype, range)?;
+        if let Some(etag) = self.etag {
+            response.headers.set(ETag(etag));
+        }
+        if let Some(modified) = self.last_modified {
+            response.headers.set(LastModified(HttpDate(time::at_utc(time::Timespec::new(modified, 0)))));
+        }
+        let disposition = self.as_attachment || self.download_name.is_some();
+        if disposition && response.status_code != 416 {
+            set_content_disposition(&mut response, self.as_attachment, self.download_name.as_deref());
+        }
+        Ok(response)
+    }
+}
+
+
+/// Resolve byte range specs to sorted inclusive ranges within a file of
+/// `len` bytes.  Unsatisfiable ranges are dropped, end offsets past the end
+/// are clamped and overlapping or adjacent ranges are coalesced.
+pub fn resolve_ranges(specs: &[ByteRangeSpec], len: u64) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = specs.iter().filter_map(|spec| {
+        match *spec {
+            FromTo(s, e) if s < len && s <= e => Some((s, e.min(len - 1))),
+            AllFrom(s) if s < len => Some((s, len - 1)),
+            Last(l) if l > 0 && len > 0 => Some((len.saturating_sub(l), len - 1)),
+            _ => None,
+        }
+    }).collect();
+    ranges.sort();
+    let mut coalesced: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (s, e) in ranges {
+        match coalesced.last_mut() {
+            Some(last) if s <= last.1.saturating_add(1) => last.1 = last.1.max(e),
+            _ => coalesced.push((s, e)),
+        }
+    }
+    coalesced
+}
+
+
+/// A `multipart/byteranges` body streaming several ranges of a file.
+struct MultipartRanges<R> {
+    reader: R,
+    ranges: Vec<(u64, u64)>,
+    boundary: String,
+    content_type: String,
+    len: u64,
+}
+
+impl<R> MultipartRanges<R> {
+    fn new(reader: R, ranges: Vec<(u64, u64)>, content_type: &str, len: u64) -> MultipartRanges<R> {
+        let mut random = [0u8; 12];
+        let boundary = match SystemRandom::new().fill(&mut random) {
+            Ok(()) => random.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            Err(_) => String::from("3d6b6a416f9b5"),
+        };
+        MultipartRanges {
+            reader,
+            ranges,
+            boundary: format!("pencil-{}", boundary),
+            content_type: content_type.to_string(),
+            len,
+        }
+    }
+
+    fn part_header(&self, s: u64, e: u64) -> String {
+        format!("--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                self.boundary, self.content_type, s, e, self.len)
+    }
+
+    fn closing(&self) -> String {
+        format!("--{}--\r\n", self.boundary)
+    }
+
+    fn content_length(&self) -> u64 {
+        let parts: u64 = self.ranges.iter()
+            .map(|&(s, e)| self.part_header(s, e).len() as u64 + (e - s + 1) + 2)
+            .sum();
+        parts + self.closing().len() as u64
+    }
+}
+
+impl<R: Read + Seek + Send> BodyWrite for MultipartRanges<R> {
+    fn write_body(&mut self, body: &mut ResponseBody) -> io::Result<()> {
+        for &(s, e) in &self.ranges {
+            body.write_all(self.part_header(s, e).as_bytes())?;
+            self.reader.seek(Start(s))?;
+            io::copy(&mut (&mut self.reader).take(e - s + 1), body)?;
+            body.write_all(b"\r\n")?;
+        }
+        body.write_all(self.closing().as_bytes())
+    }
+}
+
+
+/// Set the `Content-Disposition` header sending the response as an
+/// attachment named like the file.
+fn set_attachment(response: &mut Response, filepath: &Path) -> Result<(), PencilError> {
+    match filepath.file_name().and_then(|file| file.to_str()) {
+        Some(filename) => {
+            set_content_disposition(response, true, Some(filename));
+            Ok(())
+        },
+        None => Err(UserError::new("filename unavailable, required for sending as attachment.").into()),
+    }
+}
+
+/// Set the `Content-Disposition` header.  Names that are not printable
+/// ASCII get an ASCII fallback and the `filename*` parameter of RFC 6266.
+fn set_content_disposition(response: &mut Response, as_attachment: bool, filename: Option<&str>) {
+    let mut value = String::from(if as_attachment { "attachment" } else { "inline" });
+    if let Some(filename) = filename {
+        let fallback: String = filename.chars()
+            .map(|c| if (' '..='~').contains(&c) { c } else { '_' })
+            .collect();
+        let quoted = fallback.replace('\\', "\\\\").replace('"', "\\\"");
+        value.push_str(&format!("; filename=\"{}\"", quoted));
+        if fallback != filename {
+            value.push_str("; filename*=UTF-8''");
+            for &byte in filename.as_bytes() {
+                // The attr-chars of RFC 5987.
+                if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+                    value.push(byte as char);
+                } else {
+                    value.push_str(&format!("%{:02X}", byte));
                 }
-            },
-            None => {
-                return Err(UserError::new("filename unavailable, required for sending as attachment.").into());
             }
         }
     }
-    Ok(response)
+    response.headers.set_raw("Content-Disposition", vec![value.into_bytes()]);
+}
+
+
+/// The strong ETag of a file, derived from its size and modification time,
+/// and the modification time in seconds since the epoch.
+fn file_validators(metadata: &Metadata) -> (EntityTag, Option<i64>) {
+    let modified = metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+    let etag = match modified {
+        Some(modified) => format!("{:x}-{:x}-{:x}", metadata.len(), modified.as_secs(), modified.subsec_nanos()),
+        None => format!("{:x}", metadata.len()),
+    };
+    (EntityTag::new(false, etag), modified.map(|modified| modified.as_secs() as i64))
+}
+
+/// Set the `ETag` and `Last-Modified` headers of a file response.
+fn set_file_validators(response: &mut Response, metadata: &Metadata) {
+    let (etag, modified) = file_validators(metadata);
+    response.headers.set(ETag(etag));
+    if let Some(modified) = modified {
+        response.headers.set(LastModified(HttpDate(time::at_utc(time::Timespec::new(modified, 0)))));
+    }
 }
 
 
@@ -235,7 +616,7 @@ pub fn send_file_range(filepath: &str, mimetype: Mime, as_attachment: bool, rang
 /// for you.
 pub fn send_from_directory(directory: &str, filename: &str,
                            as_attachment: bool) -> PencilResult {
-    match safe_join(directory, filename) {
+    match safe_join_with(directory, filename, SymlinkPolicy::default()) {
         Some(filepath) => {
             let mimetype = guess_mime_type(filepath.as_path());
             match filepath.as_path().to_str() {
@@ -254,14 +635,14 @@ pub fn send_from_directory(directory: &str, filename: &str,
 }
 
 /// Send a file from a given directory with `send_file`, supporting HTTP Range requests, so it allows only partial files
-/// to be requested and sent. This doesn't support multiranges at the moment. This is a secure way to
+/// to be requested and sent. This is a secure way to
 /// quickly expose static files from an folder.  This will guess the mimetype
 /// for you.
 pub fn send_from_directory_range(directory: &str, filename: &str,
                            as_attachment: bool, range: Option<&Range>)
     -> PencilResult
 {
-    match safe_join(directory, filename) {
+    match safe_join_with(directory, filename, SymlinkPolicy::default()) {
         Some(filepath) => {
             let mimetype = guess_mime_type(filepath.as_path());
             match filepath.as_path().to_str() {
@@ -278,3 +659,139 @@ pub fn send_from_directory_range(directory: &str, filename: &str,
         }
     }
 }
+
+
+/// The content codings of precompressed files and the suffixes of their
+/// names, in the order the server prefers them.
+const PRECOMPRESSED: &[(&str, &str)] = &[("br", ".br"), ("gzip", ".gz")];
+
+/// Send a file from a given directory like `send_from_directory_range`,
+/// answering the conditional headers of the request.  The file gets an
+/// `ETag` and a `Last-Modified` header from its size and modification time,
+/// a cached copy is answered with `304 Not Modified`, and the `Range` header
+/// is only honoured if the `If-Range` validator still matches, so resumed
+/// downloads of a changed file start over.  This is what the static routes
+/// use.
+///
+/// If precompressed siblings like `app.js.br` or `app.js.gz` exist next to
+/// the file, the best one the `Accept-Encoding` header of the request allows
+/// is sent instead, with a `Content-Encoding` header and the mimetype of the
+/// original file.  Validators and ranges then apply to the compressed file.
+pub fn send_from_directory_conditional(request: &Request, directory: &str, filename: &str,
+                                       as_attachment: bool) -> PencilResult {
+    let policy = request.app.symlink_policy();
+    let filepath = match safe_join_with(directory, filename, policy) {
+        Some(ref filepath) if filepath.is_file() => filepath.clone(),
+        _ => return Err(PenHTTPError(NotFound)),
+    };
+    let mimetype = guess_mime_type(filepath.as_path());
+    let siblings: Vec<(&str, PathBuf)> = PRECOMPRESSED.iter()
+        .filter_map(|&(coding, suffix)| {
+            safe_join_with(directory, &(filename.to_owned() + suffix), policy)
+                .map(|sibling| (coding, sibling))
+        })
+        .filter(|(_, sibling)| sibling.is_file())
+        .collect();
+    let accept = request.accept_encodings();
+    let coding = if accept.provided() {
+        let offered: Vec<&str> = siblings.iter().map(|&(coding, _)| coding).collect();
+        // A precompressed file wins over the original one if the client
+        // accepts it at least as much as the identity encoding.
+        accept.best_match(&offered).filter(|&coding| accept.quality(coding) >= accept.quality("identity"))
+    } else {
+        None
+    };
+    let sendpath = match siblings.iter().find(|&&(sibling_coding, _)| Some(sibling_coding) == coding) {
+        Some((_, sibling)) => sibling.clone(),
+        None => filepath.clone(),
+    };
+
+    let metadata = fs::metadata(&sendpath).map_err(|_| PenHTTPError(NotFound))?;
+    let (etag, modified) = file_validators(&metadata);
+    let range = requested_range(request, Some(&etag), modified);
+    let sendpath = sendpath.to_str().ok_or(PenHTTPError(NotFound))?;
+    let mut response = send_file_range(sendpath, mimetype, false, range)?;
+    if as_attachment {
+        set_attachment(&mut response, &filepath)?;
+    }
+    // The body of a `416` response is not compressed.
+    if let (Some(coding), true) = (coding, response.status_code != 416) {
+        response.headers.set_raw("Content-Encoding", vec![coding.as_bytes().to_vec()]);
+    }
+    if !siblings.is_empty() {
+        response.add_vary("Accept-Encoding");
+    }
+    response.make_conditional(request);
+    Ok(response)
+}
+
+
+/// The `Range` header of the request, unless the `If-Range` validator does
+/// not match the representation anymore.
+fn requested_range<'r>(request: &'r Request, etag: Option<&EntityTag>, modified: Option<i64>) -> Option<&'r Range> {
+    match request.headers().get::<IfRange>() {
+        Some(IfRange::EntityTag(tag)) if etag.is_none_or(|etag| !tag.strong_eq(etag)) => None,
+        Some(IfRange::Date(HttpDate(date))) if Some(date.to_timespec().sec) != modified => None,
+        _ => request.headers().get::<Range>(),
+    }
+}
+
+
+/// Sends a file like `send_from_directory_conditional`, but answers requests
+/// for directories too: a directory requested without a trailing slash is
+/// redirected to the path with one, and a directory is answered with its
+/// `index.html` file.  Directories without an index file are listed if
+/// `autoindex` is set and are not found otherwise.  The listing can be
+/// sorted with the `sort` (`name`, `size` or `mtime`) and `order` (`asc` or
+/// `desc`) query arguments, is sent as JSON to clients preferring JSON and is
+/// rendered with the `autoindex.html` template if one is registered.
+pub fn send_from_directory_index(request: &Request, directory: &str, filename: &str,
+                                 autoindex: bool) -> PencilResult {
+    let policy = request.app.symlink_policy();
+    let dirpath = match safe_join_with(directory, filename, policy) {
+        Some(ref dirpath) if dirpath.is_dir() => dirpath.clone(),
+        _ => return send_from_directory_conditional(request, directory, filename, false),
+    };
+    if !filename.is_empty() && !filename.ends_with('/') {
+        // Relative links from the index resolve against the directory.
+        let mut location = request.url.path().to_owned() + "/";
+        if let Some(query_string) = request.query_string() {
+            location = location + "?" + &query_string;
+        }
+        return redirect(&location, 301);
+    }
+    let index = filename.to_owned() + "index.html";
+    if safe_join_with(directory, &index, policy).is_some_and(|index| index.is_file()) {
+        return send_from_directory_conditional(request, directory, &index, false);
+    }
+    if autoindex {
+        return render_listing(request, Path::new(directory), &dirpath, policy);
+    }
+    Err(PenHTTPError(NotFound))
+}
+
+
+/// Sends a file from an asset source like `send_from_directory_conditional`
+/// sends one from a directory: the file gets its `ETag` and `Last-Modified`
+/// headers, and conditional and range requests are answered.  A directory
+/// path ending with a slash is answered with its `index.html` file.
+pub fn send_asset(request: &Request, source: &dyn AssetSource, filename: &str) -> PencilResult {
+    let asset = if filename.is_empty() || filename.ends_with('/') {
+        source.get(&(filename.to_owned() + "index.html"))
+    } else {
+        source.get(filename)
+    };
+    let asset = asset.ok_or(PenHTTPError(NotFound))?;
+    let etag = EntityTag::new(false, asset.etag.clone());
+    let len = asset.len();
+    let mut response = {
+        let range = requested_range(request, Some(&etag), asset.modified);
+        range_response(io::Cursor::new(asset.data), len, asset.mimetype, range)?
+    };
+    response.headers.set(ETag(etag));
+    if let Some(modified) = asset.modified {
+        response.headers.set(LastModified(HttpDate(time::at_utc(time::Timespec::new(modified, 0)))));
+    }
+    response.make_conditional(request);
+    Ok(response)
+}
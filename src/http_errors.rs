@@ -4,9 +4,12 @@ use std::error::Error;
 use std::fmt;
 
 use hyper;
+use hyper::header::{Header, HeaderFormat, Headers};
 use hyper::method::Method;
+use hyper::status::StatusCode;
+use serde_json::{Map, Value};
 
-use httputils::get_name_by_http_code;
+use httputils::{get_name_by_http_code, get_status_from_code};
 use wrappers::Response;
 
 pub use self::HTTPError::{
@@ -35,6 +38,8 @@ pub use self::HTTPError::{
     NotImplemented,
     BadGateway,
     ServiceUnavailable,
+    Status,
+    Detailed,
 };
 
 
@@ -62,6 +67,20 @@ pub use self::HTTPError::{
 ///     return abort(404)
 /// }
 /// ```
+///
+/// Errors can carry their own description and extra headers:
+///
+/// ```rust,no_run
+/// use sharp_pencil::{Request, PencilResult, PenHTTPError, HTTPError};
+///
+///
+/// fn view(_: &mut Request) -> PencilResult {
+///     let error = HTTPError::new(503)
+///         .with_description("We are upgrading the database, please come back soon.")
+///         .with_retry_after(120);
+///     return Err(PenHTTPError(error))
+/// }
+/// ```
 #[derive(Clone, Debug)]
 pub enum HTTPError {
     BadRequest,
@@ -89,6 +108,25 @@ pub enum HTTPError {
     NotImplemented,
     BadGateway,
     ServiceUnavailable,
+    /// Any other registered error status, e.g. `402` or `423`.
+    Status(u16),
+    /// An error with a custom description or extra headers.
+    Detailed(Box<HTTPErrorDetails>),
+}
+
+/// The custom description and headers of a `Detailed` error.
+#[derive(Clone, Debug)]
+pub struct HTTPErrorDetails {
+    /// The error.
+    pub error: HTTPError,
+    /// Replaces the default description of the error.
+    pub description: Option<String>,
+    /// Headers added to the error response, as names and values.
+    pub headers: Vec<(String, String)>,
+    /// The problem type URI used in `application/problem+json` responses.
+    pub problem_type: Option<String>,
+    /// Extension members added to `application/problem+json` responses.
+    pub extensions: Map<String, Value>,
 }
 
 impl HTTPError {
@@ -120,7 +158,117 @@ impl HTTPError {
             501 => NotImplemented,
             502 => BadGateway,
             503 => ServiceUnavailable,
-            _ => InternalServerError,
+            _ => match get_status_from_code(code) {
+                StatusCode::Unregistered(_) => InternalServerError,
+                _ if (400..600).contains(&code) => Status(code),
+                _ => InternalServerError,
+            },
+        }
+    }
+
+    /// An `Unauthorized` error asking the client to authenticate with the
+    /// given `WWW-Authenticate` challenge, e.g. `Basic realm="admin"`.
+    pub fn unauthorized(challenge: &str) -> HTTPError {
+        Unauthorized.with_raw_header("WWW-Authenticate", challenge)
+    }
+
+    /// Replace the description shown in the error page.
+    pub fn with_description(self, description: &str) -> HTTPError {
+        let mut details = self.into_details();
+        details.description = Some(description.to_string());
+        Detailed(Box::new(details))
+    }
+
+    /// Add a header to the error response.
+    pub fn with_header<H: Header + HeaderFormat>(self, header: H) -> HTTPError {
+        let mut headers = Headers::new();
+        headers.set(header);
+        let value = headers.iter().next().map(|header| header.value_string()).unwrap_or_default();
+        self.with_raw_header(H::header_name(), &value)
+    }
+
+    /// Add a header to the error response without parsing it.
+    pub fn with_raw_header(self, name: &str, value: &str) -> HTTPError {
+        let mut details = self.into_details();
+        details.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        details.headers.push((name.to_string(), value.to_string()));
+        Detailed(Box::new(details))
+    }
+
+    /// Tell the client when to retry, for `TooManyRequests` and
+    /// `ServiceUnavailable`.
+    pub fn with_retry_after(self, seconds: u64) -> HTTPError {
+        self.with_raw_header("Retry-After", &seconds.to_string())
+    }
+
+    /// Set the problem type URI for `application/problem+json` responses,
+    /// e.g. `https://example.com/probs/out-of-credit`.
+    pub fn with_problem_type(self, problem_type: &str) -> HTTPError {
+        let mut details = self.into_details();
+        details.problem_type = Some(problem_type.to_string());
+        Detailed(Box::new(details))
+    }
+
+    /// Add an extension member to `application/problem+json` responses.
+    pub fn with_extension(self, key: &str, value: Value) -> HTTPError {
+        let mut details = self.into_details();
+        details.extensions.insert(key.to_string(), value);
+        Detailed(Box::new(details))
+    }
+
+    /// The problem type URI, `about:blank` if none was set.
+    pub fn problem_type(&self) -> &str {
+        match *self {
+            Detailed(ref details) => match details.problem_type {
+                Some(ref problem_type) => problem_type,
+                None => details.error.problem_type(),
+            },
+            _ => "about:blank",
+        }
+    }
+
+    /// The extension members for `application/problem+json` responses.
+    pub fn extensions(&self) -> Map<String, Value> {
+        match *self {
+            Detailed(ref details) => {
+                let mut extensions = details.error.extensions();
+                extensions.extend(details.extensions.clone());
+                extensions
+            },
+            _ => Map::new(),
+        }
+    }
+
+    fn into_details(self) -> HTTPErrorDetails {
+        match self {
+            Detailed(details) => *details,
+            error => HTTPErrorDetails {
+                error,
+                description: None,
+                headers: Vec::new(),
+                problem_type: None,
+                extensions: Map::new(),
+            },
+        }
+    }
+
+    /// The headers added to the error response, e.g. `Allow` for
+    /// `MethodNotAllowed`.
+    pub fn headers(&self) -> Headers {
+        match *self {
+            MethodNotAllowed(Some(ref valid_methods)) => {
+                let mut headers = Headers::new();
+                headers.set(hyper::header::Allow(valid_methods.clone()));
+                headers
+            },
+            Detailed(ref details) => {
+                let mut headers = details.error.headers();
+                for (name, value) in &details.headers {
+                    headers.set_raw(name.clone(), vec![value.as_bytes().to_vec()]);
+                }
+                headers
+            },
+            _ => Headers::new(),
         }
     }
 
@@ -152,6 +300,8 @@ impl HTTPError {
             NotImplemented => 501,
             BadGateway => 502,
             ServiceUnavailable => 503,
+            Status(code) => code,
+            Detailed(ref details) => details.error.code(),
         }
     }
 
@@ -164,7 +314,7 @@ impl HTTPError {
     }
 
     /// Get description.
-    fn get_description(&self) -> &str {
+    pub fn get_description(&self) -> &str {
         match *self {
             BadRequest => "The browser (or proxy) sent a request that this server \
                            could not understand.",
@@ -215,6 +365,11 @@ impl HTTPError {
             ServiceUnavailable => "The server is temporarily unable to service your request \
                                    due to maintenance downtime or capacity problems.  Please \
                                    try again later.",
+            Status(code) => status_description(code),
+            Detailed(ref details) => match details.description {
+                Some(ref description) => description,
+                None => details.error.get_description(),
+            },
         }
     }
 
@@ -233,13 +388,31 @@ impl HTTPError {
         let mut response = Response::from(self.get_body());
         response.status_code = self.code();
         response.set_content_type("text/html");
-        if let MethodNotAllowed(Some(ref valid_methods)) = *self {
-            response.headers.set(hyper::header::Allow(valid_methods.clone()));
-        }
+        response.headers.extend(self.headers().iter());
         response
     }
 }
 
+/// Descriptions of the statuses without their own variant.
+fn status_description(code: u16) -> &'static str {
+    match code {
+        402 => "Payment is required to access the requested resource.",
+        407 => "You have to authenticate with the proxy server first.",
+        423 => "The requested resource is locked.",
+        424 => "The request failed because it depended on another request that failed.",
+        426 => "The client has to switch to a different protocol.",
+        451 => "The requested resource is unavailable for legal reasons.",
+        504 => "The proxy server did not receive a timely response from an upstream server.",
+        505 => "The server does not support the HTTP protocol version used in the request.",
+        506 => "The server has an internal configuration error.",
+        507 => "The server is unable to store the representation needed to complete the request.",
+        508 => "The server detected an infinite loop while processing the request.",
+        510 => "Further extensions to the request are required for the server to fulfill it.",
+        511 => "You need to authenticate to gain network access.",
+        _ => "The server could not complete your request.",
+    }
+}
+
 impl fmt::Display for HTTPError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.write_str(self.get_description())
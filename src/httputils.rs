@@ -1,8 +1,11 @@
 //! This module implements a bunch of utilities that help Pencil
 //! to deal with HTTP data.
 
-use hyper::header::Host;
+use hyper::header::{Headers, Host, EntityTag, HttpDate};
+use hyper::header::{IfMatch, IfNoneMatch, IfModifiedSince, IfUnmodifiedSince};
+use hyper::method::Method;
 use hyper::status::StatusCode;
+use ring::digest;
 
 
 /// Get HTTP status name by status code.
@@ -82,6 +85,7 @@ pub fn get_status_from_code(code: u16) -> StatusCode {
         428 => StatusCode::PreconditionRequired,
         429 => StatusCode::TooManyRequests,
         431 => StatusCode::RequestHeaderFieldsTooLarge,
+        451 => StatusCode::UnavailableForLegalReasons,
         500 => StatusCode::InternalServerError,
         501 => StatusCode::NotImplemented,
         502 => StatusCode::BadGateway,
@@ -98,8 +102,79 @@ pub fn get_status_from_code(code: u16) -> StatusCode {
 }
 
 
+/// Generate a strong ETag value for a body.
+pub fn generate_etag(data: &[u8]) -> String {
+    let digest = digest::digest(&digest::SHA256, data);
+    digest.as_ref()[..16].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+/// Evaluate the conditional headers of a request for a representation with
+/// the ETag and modification time (in seconds since the epoch) as described
+/// in RFC 7232.  Returns the status to answer with, 304 or 412, or `None` if
+/// the representation has to be sent.
+pub fn evaluate_preconditions(headers: &Headers, method: &Method, etag: Option<&EntityTag>,
+                              last_modified: Option<i64>) -> Option<u16> {
+    let is_get = *method == Method::Get || *method == Method::Head;
+    let seconds = |date: &HttpDate| date.0.to_timespec().sec;
+    if let Some(if_match) = headers.get::<IfMatch>() {
+        let matches = match *if_match {
+            IfMatch::Any => true,
+            IfMatch::Items(ref tags) => etag.is_some_and(|etag| tags.iter().any(|tag| tag.strong_eq(etag))),
+        };
+        if !matches {
+            return Some(412);
+        }
+    } else if let (Some(IfUnmodifiedSince(date)), Some(last_modified)) = (headers.get::<IfUnmodifiedSince>(), last_modified) {
+        if last_modified > seconds(date) {
+            return Some(412);
+        }
+    }
+    if let Some(if_none_match) = headers.get::<IfNoneMatch>() {
+        let matches = match *if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(ref tags) => etag.is_some_and(|etag| tags.iter().any(|tag| tag.weak_eq(etag))),
+        };
+        if matches {
+            return Some(if is_get { 304 } else { 412 });
+        }
+    } else if let (true, Some(IfModifiedSince(date)), Some(last_modified)) = (is_get, headers.get::<IfModifiedSince>(), last_modified) {
+        if last_modified <= seconds(date) {
+            return Some(304);
+        }
+    }
+    None
+}
+
+
 #[test]
 fn test_get_name_by_http_code() {
     let status_name = get_name_by_http_code(200).unwrap();
     assert!(status_name == "OK");
+    assert!(get_status_from_code(451) == StatusCode::UnavailableForLegalReasons);
+    assert!(get_name_by_http_code(451) == Some("Unavailable For Legal Reasons"));
+}
+
+
+#[test]
+fn test_evaluate_preconditions() {
+    let etag = EntityTag::new(false, String::from("abc"));
+    let mut headers = Headers::new();
+    assert!(evaluate_preconditions(&headers, &Method::Get, Some(&etag), None).is_none());
+    headers.set(IfNoneMatch::Items(vec![EntityTag::new(true, String::from("abc"))]));
+    assert!(evaluate_preconditions(&headers, &Method::Get, Some(&etag), None) == Some(304));
+    assert!(evaluate_preconditions(&headers, &Method::Put, Some(&etag), None) == Some(412));
+    let mut headers = Headers::new();
+    headers.set(IfMatch::Items(vec![EntityTag::new(false, String::from("other"))]));
+    assert!(evaluate_preconditions(&headers, &Method::Put, Some(&etag), None) == Some(412));
+    let mut headers = Headers::new();
+    headers.set(IfMatch::Items(vec![EntityTag::new(true, String::from("abc"))]));
+    assert!(evaluate_preconditions(&headers, &Method::Put, Some(&etag), None) == Some(412));
+}
+
+#[test]
+fn test_generate_etag() {
+    assert!(generate_etag(b"Hello") == generate_etag(b"Hello"));
+    assert!(generate_etag(b"Hello") != generate_etag(b"Hello!"));
+    assert!(generate_etag(b"Hello").len() == 32);
 }
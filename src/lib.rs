@@ -61,19 +61,28 @@ extern crate mime_guess;
 extern crate lazycell;
 extern crate time;
 extern crate notify;
+extern crate rustls;
+extern crate rustls_pemfile;
+extern crate ring;
+extern crate flate2;
+extern crate brotli;
 
 /* public api */
 pub use app::Pencil;
+pub use dispatcher::Dispatcher;
 pub use types::{
     PencilError,
         PenHTTPError,
         PenUserError,
+        PenError,
+        PenResponse,
     UserError,
     PencilResult,
     ViewArgs,
     ViewFunc,
     UserErrorHandler,
     HTTPErrorHandler,
+    ErrorHandler,
     BeforeRequestFunc,
     AfterRequestFunc,
     TeardownRequestFunc,
@@ -81,6 +90,8 @@ pub use types::{
 pub use wrappers::{
     Request,
     Response,
+    RemoteAddr,
+    BodySender,
 };
 pub use http_errors::{
     HTTPError
@@ -92,16 +103,39 @@ pub use config::{
 pub use helpers::{
     PathBound,
     safe_join,
+    safe_join_with,
+    SymlinkPolicy,
     abort,
+    abort_with,
     redirect,
     escape,
+    negotiate,
     send_file,
+    SendFile,
     send_from_directory,
+    send_from_directory_conditional,
+    send_from_directory_index,
+    send_asset,
 };
 pub use module::Module;
+pub use assets::{Asset, AssetSource, EmbeddedAssets, EmbeddedFile, embed_directory};
+pub use manifest::{StaticManifest, StaticUrlStyle};
+pub use middleware::Middleware;
+pub use compression::Compress;
+pub use sse::{Event, EventStream, EventSender, EventHub};
+pub use renderers::{
+    ErrorRenderer,
+    HTMLErrorRenderer,
+    ProblemJSONErrorRenderer,
+    NegotiatingErrorRenderer,
+};
+pub use tls::TlsConfig;
+pub use proxy::ProxyFix;
+#[cfg(unix)]
+pub use unix::UnixSocketConfig;
 pub use handlebars::Handlebars;
 
-pub use hyper::header::{Cookie, SetCookie, Headers, ContentLength, ContentType};
+pub use hyper::header::{Cookie, SetCookie, Headers, ContentLength, ContentType, CacheControl, CacheDirective};
 
 
 #[macro_use]
@@ -114,8 +148,10 @@ pub mod json;
 pub mod config;
 pub mod helpers;
 pub mod method;
-mod testing;
+pub mod proxy;
+pub mod testing;
 mod app;
+mod dispatcher;
 mod types;
 mod logging;
 mod serving;
@@ -123,3 +159,15 @@ mod httputils;
 mod templating;
 mod formparser;
 mod module;
+mod middleware;
+mod compression;
+mod sse;
+mod autoindex;
+mod assets;
+mod manifest;
+mod renderers;
+mod panics;
+mod debug;
+mod tls;
+#[cfg(unix)]
+mod unix;
@@ -0,0 +1,215 @@
+//! This module implements cache-busting URLs for static files.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError};
+use serde_json::{self, Value};
+use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET, QUERY_ENCODE_SET};
+
+use assets::AssetSource;
+use httputils::generate_etag;
+
+
+thread_local! {
+    /// The script root of the request handled on this thread.
+    static SCRIPT_ROOT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Restores the script root of the enclosing request, also when a view
+/// panics.
+struct ScriptRootGuard(Option<String>);
+
+impl Drop for ScriptRootGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        SCRIPT_ROOT.with(|cell| *cell.borrow_mut() = previous);
+    }
+}
+
+/// Run `f` with `script_root` as the script root of the static URLs built
+/// on this thread.  Applications mounted in a `Dispatcher` nest.
+pub fn with_script_root<T, F: FnOnce() -> T>(script_root: &str, f: F) -> T {
+    let previous = SCRIPT_ROOT.with(|cell| cell.replace(Some(script_root.to_owned())));
+    let _guard = ScriptRootGuard(previous);
+    f()
+}
+
+/// The script root of the request handled on this thread, if any.
+pub fn current_script_root() -> Option<String> {
+    SCRIPT_ROOT.with(|cell| cell.borrow().clone())
+}
+
+
+/// How the URLs of static files carry the hash of their content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StaticUrlStyle {
+    /// The hash is part of the file name, like `app.3f9a1c2b.css`.
+    HashedName,
+    /// The hash is a query argument, like `app.css?v=3f9a1c2b`.
+    QueryString,
+}
+
+
+/// The versioned names of static files.
+#[derive(Clone, Debug, Default)]
+pub struct StaticManifest {
+    /// Maps file names to the versioned names linked to.
+    urls: HashMap<String, String>,
+    /// Maps hashed names to the file names served for them.
+    files: HashMap<String, String>,
+    /// Maps file names to the hashes expected in the `v` query argument.
+    versions: HashMap<String, String>,
+}
+
+impl StaticManifest {
+    /// Create an empty manifest.
+    pub fn new() -> StaticManifest {
+        StaticManifest::default()
+    }
+
+    /// Version the file `filename` by the hash of its content, cut to its
+    /// first 8 characters.
+    pub fn insert_hash(&mut self, filename: &str, hash: &str, style: StaticUrlStyle) {
+        let hash: String = hash.chars().take(8).collect();
+        match style {
+            StaticUrlStyle::HashedName => {
+                let hashed = hashed_name(filename, &hash);
+                self.urls.insert(filename.to_owned(), hashed.clone());
+                self.files.insert(hashed, filename.to_owned());
+            },
+            StaticUrlStyle::QueryString => {
+                self.urls.insert(filename.to_owned(), format!("{}?v={}", filename, hash));
+                self.versions.insert(filename.to_owned(), hash);
+            },
+        }
+    }
+
+    /// Link `filename` to `versioned`, a file with a hashed name in the
+    /// static folder like the ones bundlers write.
+    pub fn insert(&mut self, filename: &str, versioned: &str) {
+        self.urls.insert(filename.to_owned(), versioned.to_owned());
+        self.files.insert(versioned.to_owned(), versioned.to_owned());
+    }
+
+    /// Load a manifest written by a bundler: a JSON object mapping file
+    /// names to the hashed names of the files in the static folder.
+    pub fn from_json(source: &str) -> Result<StaticManifest, serde_json::Error> {
+        let entries: HashMap<String, Value> = serde_json::from_str(source)?;
+        let mut manifest = StaticManifest::new();
+        for (filename, versioned) in entries {
+            if let Value::String(versioned) = versioned {
+                manifest.insert(filename.trim_start_matches('/'), versioned.trim_start_matches('/'));
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Hash the files of the static folder `directory`.
+    pub fn hash_directory(directory: &str, style: StaticUrlStyle) -> io::Result<StaticManifest> {
+        let mut manifest = StaticManifest::new();
+        hash_files(Path::new(directory), "", style, &mut manifest)?;
+        Ok(manifest)
+    }
+
+    /// Version the files listed by an asset source by their ETags.
+    pub fn hash_assets(source: &dyn AssetSource, style: StaticUrlStyle) -> StaticManifest {
+        let mut manifest = StaticManifest::new();
+        for filename in source.list() {
+            if let Some(asset) = source.get(&filename) {
+                manifest.insert_hash(&filename, &asset.etag, style);
+            }
+        }
+        manifest
+    }
+
+    /// The versioned name of `filename`, if it is in the manifest.
+    pub fn versioned(&self, filename: &str) -> Option<&str> {
+        self.urls.get(filename).map(|versioned| versioned.as_str())
+    }
+
+    /// The file to serve for a request of the static file `filename` with
+    /// the `v` query argument `version`, if this is a versioned URL from the
+    /// manifest.  Versioned URLs never change their content.
+    pub fn resolve<'a>(&'a self, filename: &'a str, version: Option<&str>) -> Option<&'a str> {
+        if let Some(file) = self.files.get(filename) {
+            return Some(file);
+        }
+        match (self.versions.get(filename), version) {
+            (Some(expected), Some(version)) if expected == version => Some(filename),
+            _ => None,
+        }
+    }
+}
+
+/// Insert the hash before the extension of the file name.
+fn hashed_name(filename: &str, hash: &str) -> String {
+    let name_start = filename.rfind('/').map_or(0, |slash| slash + 1);
+    match filename[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => {
+            let (stem, extension) = filename.split_at(name_start + dot);
+            format!("{}.{}{}", stem, hash, extension)
+        },
+        _ => format!("{}.{}", filename, hash),
+    }
+}
+
+fn hash_files(directory: &Path, prefix: &str, style: StaticUrlStyle, manifest: &mut StaticManifest) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => format!("{}{}", prefix, name),
+            None => continue,
+        };
+        if path.is_dir() {
+            hash_files(&path, &(name + "/"), style, manifest)?;
+        } else if path.is_file() {
+            let mut data = Vec::new();
+            File::open(&path)?.read_to_end(&mut data)?;
+            manifest.insert_hash(&name, &generate_etag(&data), style);
+        }
+    }
+    Ok(())
+}
+
+
+/// The URL path of a static file below `script_root`, versioned if the
+/// manifest knows it.
+pub fn static_url(script_root: &str, static_url_path: &str, manifest: &StaticManifest, filename: &str) -> String {
+    let filename = filename.trim_start_matches('/');
+    let (path, query) = match manifest.versioned(filename) {
+        Some(versioned) => match versioned.find('?') {
+            Some(query_start) => versioned.split_at(query_start),
+            None => (versioned, ""),
+        },
+        None => (filename, ""),
+    };
+    format!("{}{}/{}{}", script_root.trim_end_matches('/'), static_url_path,
+            utf8_percent_encode(path, DEFAULT_ENCODE_SET), utf8_percent_encode(query, QUERY_ENCODE_SET))
+}
+
+
+/// The `static_url` template helper: `{{static_url "css/app.css"}}`.  The
+/// URL includes the script root of the request handled on this thread, see
+/// `with_script_root`.
+pub struct StaticUrlHelper {
+    pub static_url_path: String,
+    pub manifest: Arc<RwLock<StaticManifest>>,
+}
+
+impl HelperDef for StaticUrlHelper {
+    fn call<'reg: 'rc, 'rc>(&self, h: &Helper<'reg, 'rc>, _: &'reg Handlebars<'reg>, _: &'rc Context,
+                            _: &mut RenderContext<'reg, 'rc>, out: &mut dyn Output) -> HelperResult {
+        let filename = h.param(0).and_then(|param| param.value().as_str())
+            .ok_or_else(|| RenderError::new("static_url requires a file name"))?;
+        let manifest = self.manifest.read()
+            .map_err(|_| RenderError::new("Can't acquire the static manifest"))?;
+        let script_root = current_script_root().unwrap_or_default();
+        out.write(&static_url(&script_root, &self.static_url_path, &manifest, filename))?;
+        Ok(())
+    }
+}
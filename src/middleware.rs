@@ -0,0 +1,64 @@
+//! This module implements middleware wrapping the request handling.
+
+use wrappers::{Request, Response};
+
+
+/// Middleware wraps the whole handling of a request: the before request
+/// functions, the view function, the error handlers, the after request
+/// functions and the teardown functions all run inside `next`.  Unlike the
+/// hooks, middleware sees every response, including the ones of the error
+/// path, and can time, retry or replace the call:
+///
+/// ```rust,no_run
+/// use std::time::Instant;
+///
+/// use sharp_pencil::{Middleware, Pencil, Request, Response};
+///
+///
+/// struct Timing;
+///
+/// impl Middleware for Timing {
+///     fn call(&self, request: &mut Request, next: &dyn Fn(&mut Request) -> Response) -> Response {
+///         let start = Instant::now();
+///         let mut response = next(request);
+///         let elapsed = format!("app;dur={}", start.elapsed().as_millis());
+///         response.headers.set_raw("Server-Timing", vec![elapsed.into_bytes()]);
+///         response
+///     }
+/// }
+///
+///
+/// fn main() {
+///     let mut app = Pencil::new("/web/demo");
+///     app.middleware(Timing);
+/// }
+/// ```
+///
+/// Middleware runs after routing, so `request.endpoint()` is available.
+/// The application middleware wraps the module middleware, and middleware
+/// registered first is the outermost.
+pub trait Middleware: Send + Sync {
+    /// Handle the request, calling `next` to continue with the inner
+    /// middleware and finally the application.
+    fn call(&self, request: &mut Request, next: &dyn Fn(&mut Request) -> Response) -> Response;
+}
+
+impl<F> Middleware for F
+    where F: Fn(&mut Request, &dyn Fn(&mut Request) -> Response) -> Response + Send + Sync
+{
+    fn call(&self, request: &mut Request, next: &dyn Fn(&mut Request) -> Response) -> Response {
+        self(request, next)
+    }
+}
+
+
+/// Run `handler` wrapped in `middlewares`, the first one is the outermost.
+pub fn call_middlewares(middlewares: &[&dyn Middleware], request: &mut Request,
+                        handler: &dyn Fn(&mut Request) -> Response) -> Response {
+    match middlewares.split_first() {
+        Some((middleware, inner)) => {
+            middleware.call(request, &|request: &mut Request| call_middlewares(inner, request, handler))
+        },
+        None => handler(request),
+    }
+}
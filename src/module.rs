@@ -2,6 +2,7 @@
 //! pluggable applications.
 
 use std::collections::HashMap;
+use std::error::Error;
 use std::mem;
 use std::path::PathBuf;
 
@@ -13,8 +14,10 @@ use routing::Matcher;
 use types::ViewFunc;
 use types::{PencilResult, PencilError, HTTPError, UserError};
 use types::{BeforeRequestFunc, AfterRequestFunc, TeardownRequestFunc};
-use types::{HTTPErrorHandler, UserErrorHandler};
-use helpers::send_from_directory_range;
+use types::{HTTPErrorHandler, UserErrorHandler, ErrorHandlers, register_typed_error_handler};
+use types::{http_error_handler_adapter, user_error_handler_adapter, teardown_request_adapter};
+use middleware::Middleware;
+use renderers::ErrorRenderer;
 use wrappers::{Request, Response};
 
 
@@ -40,6 +43,12 @@ pub struct Module {
     pub http_error_handlers: HashMap<u16, Box<HTTPErrorHandler>>,
     #[doc(hidden)]
     pub user_error_handlers: HashMap<String, Box<UserErrorHandler>>,
+    #[doc(hidden)]
+    pub error_handlers: ErrorHandlers,
+    #[doc(hidden)]
+    pub middlewares: Vec<Box<dyn Middleware>>,
+    #[doc(hidden)]
+    pub error_renderer: Option<Box<dyn ErrorRenderer>>,
     deferred_functions: Vec<Box<dyn Fn(&mut Pencil) + Send + Sync>>,
     deferred_routes: Vec<(Matcher, Vec<Method>, String, ViewFunc)>,
 }
@@ -66,6 +75,9 @@ impl Module {
             teardown_request_funcs: Vec::new(),
             http_error_handlers: HashMap::new(),
             user_error_handlers: HashMap::new(),
+            error_handlers: Vec::new(),
+            middlewares: Vec::new(),
+            error_renderer: None,
             deferred_functions: Vec::new(),
             deferred_routes: Vec::new(),
         }
@@ -113,6 +125,11 @@ impl Module {
     /// Teardown request for a module.  This is only executed when tearing down
     /// each request that is handled by a view function of that module.
     pub fn teardown_request<F: Fn(Option<&PencilError>) + Send + Sync + 'static>(&mut self, f: F) {
+        self.teardown_request_funcs.push(teardown_request_adapter(f));
+    }
+
+    /// Teardown request for a module, the function also receives the request.
+    pub fn teardown<F: Fn(&Request, Option<&PencilError>) + Send + Sync + 'static>(&mut self, f: F) {
         self.teardown_request_funcs.push(Box::new(f));
     }
 
@@ -122,16 +139,67 @@ impl Module {
         self.record(move |app| app.teardown_request(f.clone())); // FIXME Clone can be removed when Box<FnOnce> is supported
     }
 
+    /// Teardown request for the app that this module is registered on, the
+    /// function also receives the request.
+    pub fn app_teardown<F: Fn(&Request, Option<&PencilError>) + Send + Sync + Clone + 'static>(&mut self, f: F) {
+        self.record(move |app| app.teardown(f.clone()));
+    }
+
+    /// Registers a middleware for this module.  It only wraps requests that
+    /// are handled by a view function of that module, inside the
+    /// middleware of the application.
+    pub fn middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    /// Registers a middleware for the app that this module is registered on.
+    /// It wraps each request, even if outside of a module.
+    pub fn app_middleware<M: Middleware + Clone + 'static>(&mut self, middleware: M) {
+        self.record(move |app| app.middleware(middleware.clone()));
+    }
+
+    /// Sets the renderer for HTTP errors without an error handler in this
+    /// module only.
+    pub fn set_error_renderer<R: ErrorRenderer + 'static>(&mut self, renderer: R) {
+        self.error_renderer = Some(Box::new(renderer));
+    }
+
     /// Registers a http error handler that becomes active for this module only.
     pub fn httperrorhandler<F: Fn(HTTPError) -> PencilResult + Send + Sync + 'static>(&mut self, status_code: u16, f: F) {
-        self.http_error_handlers.insert(status_code, Box::new(f));
+        self.http_error_handlers.insert(status_code, http_error_handler_adapter(f));
     }
 
     /// Registers an user error handler that becomes active for this module only.
     pub fn usererrorhandler<F: Fn(UserError) -> PencilResult + Send + Sync + 'static>(&mut self, error_desc: &str, f: F) {
+        self.user_error_handlers.insert(error_desc.to_string(), user_error_handler_adapter(f));
+    }
+
+    /// Registers a http error handler that also receives the request and
+    /// becomes active for this module only.
+    pub fn http_error_handler<F: Fn(HTTPError, &Request) -> PencilResult + Send + Sync + 'static>(&mut self, status_code: u16, f: F) {
+        self.http_error_handlers.insert(status_code, Box::new(f));
+    }
+
+    /// Registers an user error handler that also receives the request and
+    /// becomes active for this module only.
+    pub fn user_error_handler<F: Fn(UserError, &Request) -> PencilResult + Send + Sync + 'static>(&mut self, error_desc: &str, f: F) {
         self.user_error_handlers.insert(error_desc.to_string(), Box::new(f));
     }
 
+    /// Registers a handler for errors of type `E` that becomes active for this module only.
+    pub fn errorhandler<E, F>(&mut self, f: F)
+        where E: Error + 'static, F: Fn(&E, &Request) -> PencilResult + Send + Sync + 'static
+    {
+        register_typed_error_handler(&mut self.error_handlers, f);
+    }
+
+    /// Registers a handler for errors of type `E` for all requests of the application.
+    pub fn app_errorhandler<E, F>(&mut self, f: F)
+        where E: Error + 'static, F: Fn(&E, &Request) -> PencilResult + Send + Sync + Clone + 'static
+    {
+        self.record(move |app| app.register_error_handler(f.clone()));
+    }
+
     /// Registers a http error handler for all requests of the application.
     pub fn app_httperrorhandler<F: Fn(HTTPError) -> PencilResult + Send + Sync + Clone + 'static>(&mut self, status_code: u16, f: F) {
         self.record(move |app| app.httperrorhandler(status_code, f.clone()));
@@ -143,6 +211,19 @@ impl Module {
         self.record(move |app| app.register_user_error_handler(&desc, f.clone()));
     }
 
+    /// Registers a http error handler that also receives the request for all
+    /// requests of the application.
+    pub fn app_http_error_handler<F: Fn(HTTPError, &Request) -> PencilResult + Send + Sync + Clone + 'static>(&mut self, status_code: u16, f: F) {
+        self.record(move |app| app.http_error_handler(status_code, f.clone()));
+    }
+
+    /// Registers an user error handler that also receives the request for all
+    /// requests of the application.
+    pub fn app_user_error_handler<F: Fn(UserError, &Request) -> PencilResult + Send + Sync + Clone + 'static>(&mut self, error_desc: &str, f: F) {
+        let desc = error_desc.to_string();
+        self.record(move |app| app.user_error_handler(&desc, f.clone()));
+    }
+
     /// Register this module.
     pub fn register(mut self, app: &mut Pencil) {
         if app.modules.contains_key(&self.name) {
@@ -162,6 +243,7 @@ impl Module {
             let mut rule = static_url_path.clone();
             rule = rule + "/<filename:path>";
             self.route(rule, &[Method::Get], "static", send_module_static_file);
+            self.route(static_url_path + "/", &[Method::Get], "static", send_module_static_file);
         }
         let deferred_routes = mem::replace(&mut self.deferred_routes, Vec::new());
         for (matcher, methods, endpoint, view_func) in deferred_routes {
@@ -185,8 +267,8 @@ fn send_module_static_file(request: &mut Request) -> PencilResult {
                 let mut static_path = PathBuf::from(&module.root_path);
                 static_path.push(module_static_folder);
                 let static_path_str = static_path.to_str().unwrap();
-                let filename = request.view_args.get("filename").unwrap();
-                return send_from_directory_range(static_path_str, filename, false, request.headers().get());
+                let filename = request.view_args.get("filename").map_or("", |filename| filename.as_str());
+                return request.app.send_static_file(request, static_path_str, filename);
             }
         }
     }
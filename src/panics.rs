@@ -0,0 +1,99 @@
+//! This module implements catching panics of view functions.
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+
+thread_local! {
+    /// The location of the last panic on this thread.
+    static PANIC_LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// The backtrace of the last panic on this thread, if requested.
+    static PANIC_BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+    static CAPTURE_BACKTRACE: Cell<bool> = const { Cell::new(false) };
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+/// Record panic locations and backtraces, the payload caught by
+/// `catch_unwind` doesn't have them.  The previous hook still runs.
+fn install_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let location = info.location().map(|location| location.to_string());
+            PANIC_LOCATION.with(|cell| *cell.borrow_mut() = location);
+            if CAPTURE_BACKTRACE.with(|capture| capture.get()) {
+                let backtrace = Backtrace::force_capture().to_string();
+                PANIC_BACKTRACE.with(|cell| *cell.borrow_mut() = Some(backtrace));
+            }
+            previous_hook(info);
+        }));
+    });
+}
+
+
+/// A panic caught while handling a request.
+pub struct Panic {
+    /// The panic message.
+    pub message: String,
+    /// Where the panic happened, e.g. `src/main.rs:10:5`.
+    pub location: Option<String>,
+    /// The backtrace of the panic, if it was captured.
+    pub backtrace: Option<String>,
+    /// The original payload, used to resume unwinding.
+    pub payload: Box<dyn Any + Send + 'static>,
+}
+
+impl fmt::Display for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some(ref location) => write!(f, "panicked at {}: {}", location, self.message),
+            None => write!(f, "panicked: {}", self.message),
+        }
+    }
+}
+
+impl fmt::Debug for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Panic({})", self)
+    }
+}
+
+/// Run `f`, catching a panic.  Capturing the backtrace is slow, only do it
+/// if it is shown to someone.
+pub fn catch_panic<F: FnOnce() -> R, R>(capture_backtrace: bool, f: F) -> Result<R, Panic> {
+    install_hook();
+    PANIC_LOCATION.with(|cell| *cell.borrow_mut() = None);
+    PANIC_BACKTRACE.with(|cell| *cell.borrow_mut() = None);
+    let previous_capture = CAPTURE_BACKTRACE.with(|capture| capture.replace(capture_backtrace));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CAPTURE_BACKTRACE.with(|capture| capture.set(previous_capture));
+    result.map_err(|payload| {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => String::from("Box<dyn Any>"),
+            },
+        };
+        let location = PANIC_LOCATION.with(|cell| cell.borrow_mut().take());
+        let backtrace = PANIC_BACKTRACE.with(|cell| cell.borrow_mut().take());
+        Panic { message, location, backtrace, payload }
+    })
+}
+
+
+#[test]
+fn test_catch_panic() {
+    assert!(catch_panic(false, || 1).unwrap() == 1);
+    let caught = catch_panic(false, || { panic!("view failed: {}", 42) }).unwrap_err();
+    assert!(caught.message == "view failed: 42");
+    assert!(caught.location.unwrap().starts_with("src/panics.rs:"));
+    assert!(caught.backtrace.is_none());
+    let caught = catch_panic(true, || { panic!("view failed") }).unwrap_err();
+    assert!(caught.backtrace.is_some());
+}
@@ -0,0 +1,356 @@
+//! This module implements support for running behind trusted reverse proxies.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use hyper::header::Headers;
+
+
+/// A network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`.  A single
+/// address is a network with the full prefix length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Create a network from an address and a prefix length.  Returns `None`
+    /// if the prefix length is too long for the address family.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Cidr> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return None;
+        }
+        Some(Cidr { addr, prefix_len })
+    }
+
+    /// Whether the address is inside this network.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, *addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(u128::from(u32::from(net)) << 96, u128::from(u32::from(addr)) << 96, self.prefix_len)
+            },
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(u128::from(net), u128::from(addr), self.prefix_len)
+            },
+            (IpAddr::V4(_), IpAddr::V6(addr)) => {
+                match addr.to_ipv4_mapped() {
+                    Some(addr) => self.contains(&IpAddr::V4(addr)),
+                    None => false,
+                }
+            },
+            (IpAddr::V6(_), IpAddr::V4(addr)) => self.contains(&IpAddr::V6(addr.to_ipv6_mapped())),
+        }
+    }
+}
+
+fn prefix_matches(net: u128, addr: u128, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let mask = !0u128 << (128 - u32::from(prefix_len));
+    net & mask == addr & mask
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(pos) => (&s[..pos], Some(&s[pos + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid network address: {}", s))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| format!("invalid prefix length: {}", s))?,
+            None => if addr.is_ipv4() { 32 } else { 128 },
+        };
+        Cidr::new(addr, prefix_len).ok_or_else(|| format!("prefix length too long: {}", s))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+
+/// Configures which proxy headers are trusted.  The numbers are how many
+/// proxies set the respective header, the value appended by the outermost
+/// trusted proxy is used.  A value of `0` ignores the header.  Typical setup
+/// behind one load balancer:
+///
+/// ```rust,no_run
+/// use sharp_pencil::{Pencil, ProxyFix};
+///
+/// fn main() {
+///     let mut app = Pencil::new("/web/demo");
+///     let mut proxy_fix = ProxyFix::new();
+///     proxy_fix.x_host = 1;
+///     proxy_fix.trust("10.0.0.0/8").unwrap();
+///     app.enable_proxy_fix(proxy_fix);
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ProxyFix {
+    /// Number of proxies setting the RFC 7239 `Forwarded` header.  If this
+    /// is not `0` the `X-Forwarded-*` headers are only used for what
+    /// `Forwarded` doesn't cover, the path prefix and port.
+    pub forwarded: usize,
+    /// Number of values to trust for `X-Forwarded-For`.
+    pub x_for: usize,
+    /// Number of values to trust for `X-Forwarded-Proto`.
+    pub x_proto: usize,
+    /// Number of values to trust for `X-Forwarded-Host`.
+    pub x_host: usize,
+    /// Number of values to trust for `X-Forwarded-Port`.
+    pub x_port: usize,
+    /// Number of values to trust for `X-Forwarded-Prefix`.
+    pub x_prefix: usize,
+    /// The headers are only used if the request comes directly from one of
+    /// these networks.  If empty, only loopback peers are trusted, proxies
+    /// on other hosts have to be added with `trust`.  Requests over Unix
+    /// domain sockets are always trusted.
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+impl Default for ProxyFix {
+    fn default() -> ProxyFix {
+        ProxyFix::new()
+    }
+}
+
+impl ProxyFix {
+    /// Create a configuration trusting one proxy for `X-Forwarded-For`
+    /// and `X-Forwarded-Proto`, if it runs on the same host.
+    pub fn new() -> ProxyFix {
+        ProxyFix {
+            forwarded: 0,
+            x_for: 1,
+            x_proto: 1,
+            x_host: 0,
+            x_port: 0,
+            x_prefix: 0,
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    /// Trust the headers if the peer is inside `network`, e.g. `10.0.0.0/8`.
+    /// Once a network is trusted, loopback peers are only trusted if they
+    /// are inside one of the trusted networks too.
+    pub fn trust(&mut self, network: &str) -> Result<(), String> {
+        self.trusted_proxies.push(network.parse()?);
+        Ok(())
+    }
+
+    /// Whether the headers sent by the peer are trusted.
+    pub fn is_trusted(&self, peer: Option<IpAddr>) -> bool {
+        match peer {
+            Some(ref ip) if self.trusted_proxies.is_empty() => ip.is_loopback(),
+            Some(ref ip) => self.trusted_proxies.iter().any(|net| net.contains(ip)),
+            None => true,
+        }
+    }
+
+    /// Compute what the proxies tell about the original request.
+    pub fn forwarded_values(&self, headers: &Headers) -> ForwardedValues {
+        let mut values = ForwardedValues::default();
+        if self.forwarded > 0 {
+            if let Some(element) = trusted_value(&get_raw_values(headers, "Forwarded"), self.forwarded) {
+                let pairs = parse_forwarded_element(&element);
+                for (key, value) in pairs {
+                    match &key[..] {
+                        "for" => values.remote_addr = parse_node(&value),
+                        "proto" => values.scheme = Some(value.to_ascii_lowercase()),
+                        "host" => values.host = Some(value),
+                        _ => (),
+                    }
+                }
+            }
+        } else {
+            if self.x_for > 0 {
+                values.remote_addr = trusted_value(&get_raw_values(headers, "X-Forwarded-For"), self.x_for)
+                    .and_then(|value| parse_node(&value));
+            }
+            if self.x_proto > 0 {
+                values.scheme = trusted_value(&get_raw_values(headers, "X-Forwarded-Proto"), self.x_proto)
+                    .map(|value| value.to_ascii_lowercase());
+            }
+            if self.x_host > 0 {
+                values.host = trusted_value(&get_raw_values(headers, "X-Forwarded-Host"), self.x_host);
+            }
+        }
+        if self.x_port > 0 {
+            values.port = trusted_value(&get_raw_values(headers, "X-Forwarded-Port"), self.x_port)
+                .and_then(|value| value.parse().ok());
+        }
+        if self.x_prefix > 0 {
+            values.prefix = trusted_value(&get_raw_values(headers, "X-Forwarded-Prefix"), self.x_prefix)
+                .map(|value| value.trim_end_matches('/').to_string());
+        }
+        values
+    }
+}
+
+
+/// What the trusted proxies tell about the original request.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ForwardedValues {
+    /// The address of the client.
+    pub remote_addr: Option<SocketAddr>,
+    /// The scheme the client used.
+    pub scheme: Option<String>,
+    /// The host the client requested, possibly with a port.
+    pub host: Option<String>,
+    /// The port the client connected to.
+    pub port: Option<u16>,
+    /// The path prefix the proxy stripped.
+    pub prefix: Option<String>,
+}
+
+/// All comma separated values of all header lines with the given name.
+fn get_raw_values(headers: &Headers, name: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    if let Some(lines) = headers.get_raw(name) {
+        for line in lines {
+            if let Ok(line) = ::std::str::from_utf8(line) {
+                values.extend(split_header_list(line));
+            }
+        }
+    }
+    values
+}
+
+/// Split a comma separated header value, keeping quoted strings intact.
+fn split_header_list(value: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        if escaped {
+            current.push(c);
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_quotes => { current.push(c); escaped = true; },
+            '"' => { current.push(c); in_quotes = !in_quotes; },
+            ',' if !in_quotes => {
+                values.push(current.trim().to_string());
+                current = String::new();
+            },
+            _ => current.push(c),
+        }
+    }
+    values.push(current.trim().to_string());
+    values
+}
+
+/// The value set by the outermost trusted proxy.  Every proxy appends to
+/// the list, so that's the `trusted`th value from the end.
+fn trusted_value(values: &[String], trusted: usize) -> Option<String> {
+    if trusted == 0 || values.len() < trusted {
+        return None;
+    }
+    let value = &values[values.len() - trusted];
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.clone())
+    }
+}
+
+/// Parse one element of the `Forwarded` header into lowercased keys and unquoted values.
+fn parse_forwarded_element(element: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    for pair in element.split(';') {
+        if let Some(pos) = pair.find('=') {
+            let key = pair[..pos].trim().to_ascii_lowercase();
+            let value = unquote(pair[pos + 1..].trim());
+            pairs.push((key, value));
+        }
+    }
+    pairs
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let mut unquoted = String::new();
+        let mut escaped = false;
+        for c in value[1..value.len() - 1].chars() {
+            if c == '\\' && !escaped {
+                escaped = true;
+            } else {
+                unquoted.push(c);
+                escaped = false;
+            }
+        }
+        unquoted
+    } else {
+        value.to_string()
+    }
+}
+
+/// Parse a node like `192.0.2.60`, `192.0.2.60:47011`, `2001:db8::1` or
+/// `[2001:db8::1]:47011`.  Obfuscated and `unknown` nodes give `None`.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = node.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+
+#[test]
+fn test_cidr_contains() {
+    let net: Cidr = "10.0.0.0/8".parse().unwrap();
+    assert!(net.contains(&"10.1.2.3".parse().unwrap()));
+    assert!(net.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!net.contains(&"11.0.0.1".parse().unwrap()));
+    let net: Cidr = "fd00::/8".parse().unwrap();
+    assert!(net.contains(&"fd12::1".parse().unwrap()));
+    assert!(!net.contains(&"fe80::1".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn test_trusted_value() {
+    let values = vec!["client".to_string(), "proxy1".to_string(), "proxy2".to_string()];
+    assert!(trusted_value(&values, 1) == Some("proxy2".to_string()));
+    assert!(trusted_value(&values, 3) == Some("client".to_string()));
+    assert!(trusted_value(&values, 4).is_none());
+    assert!(trusted_value(&values, 0).is_none());
+}
+
+#[test]
+fn test_parse_forwarded_element() {
+    let elements = split_header_list("for=192.0.2.43, for=\"[2001:db8:cafe::17]:4711\";proto=HTTPS;host=\"a,b\"");
+    assert!(elements.len() == 2);
+    let pairs = parse_forwarded_element(&elements[1]);
+    assert!(pairs[0] == ("for".to_string(), "[2001:db8:cafe::17]:4711".to_string()));
+    assert!(pairs[2] == ("host".to_string(), "a,b".to_string()));
+    assert!(parse_node(&pairs[0].1) == Some("[2001:db8:cafe::17]:4711".parse().unwrap()));
+    assert!(parse_node("unknown").is_none());
+}
+
+#[test]
+fn test_is_trusted() {
+    let mut proxy_fix = ProxyFix::new();
+    assert!(proxy_fix.is_trusted(Some("127.0.0.1".parse().unwrap())));
+    assert!(proxy_fix.is_trusted(Some("::1".parse().unwrap())));
+    assert!(!proxy_fix.is_trusted(Some("203.0.113.7".parse().unwrap())));
+    assert!(!proxy_fix.is_trusted(Some("10.0.0.2".parse().unwrap())));
+    assert!(proxy_fix.is_trusted(None));
+    proxy_fix.trust("10.0.0.0/8").unwrap();
+    assert!(proxy_fix.is_trusted(Some("10.0.0.2".parse().unwrap())));
+    assert!(!proxy_fix.is_trusted(Some("127.0.0.1".parse().unwrap())));
+    assert!(!proxy_fix.is_trusted(Some("203.0.113.7".parse().unwrap())));
+}
@@ -0,0 +1,133 @@
+//! This module implements the renderers turning HTTP errors into responses.
+
+use serde_json::{Map, Value};
+
+use http_errors::HTTPError;
+use wrappers::{Request, Response};
+
+
+/// Renders the response of an HTTP error no error handler took care of.
+/// This is used for routing errors, aborts and unhandled errors alike, so an
+/// API can answer with JSON everywhere:
+///
+/// ```rust,no_run
+/// use sharp_pencil::{Pencil, ProblemJSONErrorRenderer};
+///
+/// fn main() {
+///     let mut app = Pencil::new("/web/api");
+///     app.set_error_renderer(ProblemJSONErrorRenderer);
+/// }
+/// ```
+///
+/// Without a renderer set on the module or the application, the
+/// `ERROR_RENDERER` configuration key selects one of the built-in renderers:
+/// `html` (the default), `problem+json` or `negotiate`.
+pub trait ErrorRenderer: Send + Sync {
+    /// Render the error for the request.
+    fn render(&self, error: &HTTPError, request: &Request) -> Response;
+}
+
+impl<F> ErrorRenderer for F
+    where F: Fn(&HTTPError, &Request) -> Response + Send + Sync
+{
+    fn render(&self, error: &HTTPError, request: &Request) -> Response {
+        self(error, request)
+    }
+}
+
+
+/// Renders errors as HTML pages.
+#[derive(Clone, Copy, Debug)]
+pub struct HTMLErrorRenderer;
+
+impl ErrorRenderer for HTMLErrorRenderer {
+    fn render(&self, error: &HTTPError, _: &Request) -> Response {
+        error.to_response()
+    }
+}
+
+
+/// Renders errors as `application/problem+json` documents as described in
+/// RFC 7807.  The members are `type`, `title`, `status`, `detail`,
+/// `instance` and the extensions of the error:
+///
+/// ```rust,no_run
+/// extern crate serde_json;
+/// extern crate sharp_pencil;
+///
+/// use serde_json::Value;
+/// use sharp_pencil::{HTTPError, PencilError, PencilResult, Request};
+///
+///
+/// fn buy(_: &mut Request) -> PencilResult {
+///     let error = HTTPError::new(403)
+///         .with_problem_type("https://example.com/probs/out-of-credit")
+///         .with_description("Your current balance is 30, but that costs 50.")
+///         .with_extension("balance", Value::from(30));
+///     Err(PencilError::PenHTTPError(error))
+/// }
+///
+/// fn main() {}
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct ProblemJSONErrorRenderer;
+
+impl ErrorRenderer for ProblemJSONErrorRenderer {
+    fn render(&self, error: &HTTPError, request: &Request) -> Response {
+        let mut response = Response::from(problem_json(error, request).to_string());
+        response.status_code = error.code();
+        response.set_content_type("application/problem+json");
+        response.headers.extend(error.headers().iter());
+        response
+    }
+}
+
+/// The problem details object of the error.
+pub fn problem_json(error: &HTTPError, request: &Request) -> Value {
+    let mut problem = Map::new();
+    problem.insert(String::from("type"), Value::from(error.problem_type()));
+    problem.insert(String::from("title"), Value::from(error.name()));
+    problem.insert(String::from("status"), Value::from(error.code()));
+    problem.insert(String::from("detail"), Value::from(error.get_description()));
+    problem.insert(String::from("instance"), Value::from(request.full_path()));
+    for (key, value) in error.extensions() {
+        problem.entry(key).or_insert(value);
+    }
+    Value::Object(problem)
+}
+
+
+/// Renders errors as `application/problem+json` for clients preferring JSON
+/// in their `Accept` header and as HTML for everyone else.
+#[derive(Clone, Copy, Debug)]
+pub struct NegotiatingErrorRenderer;
+
+impl ErrorRenderer for NegotiatingErrorRenderer {
+    fn render(&self, error: &HTTPError, request: &Request) -> Response {
+        let mut response = if prefers_json(request) {
+            ProblemJSONErrorRenderer.render(error, request)
+        } else {
+            HTMLErrorRenderer.render(error, request)
+        };
+        response.add_vary("Accept");
+        response
+    }
+}
+
+/// Whether the client accepts JSON with a higher quality than HTML.
+fn prefers_json(request: &Request) -> bool {
+    let accept = request.accept_mimetypes();
+    let json = accept.quality("application/problem+json").max(accept.quality("application/json"));
+    json > accept.quality("text/html")
+}
+
+/// The built-in renderer named by the `ERROR_RENDERER` configuration key.
+pub fn renderer_by_name(name: &str) -> Option<&'static dyn ErrorRenderer> {
+    match name {
+        "html" => Some(&HTMLErrorRenderer),
+        "problem+json" => Some(&ProblemJSONErrorRenderer),
+        "negotiate" => Some(&NegotiatingErrorRenderer),
+        _ => None,
+    }
+}
+
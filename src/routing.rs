@@ -4,12 +4,15 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use regex::Regex;
 use regex::escape as regex_quote;
+use url::form_urlencoded;
+use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET, PATH_SEGMENT_ENCODE_SET};
 
 use hyper::method::Method;
 
 use http_errors::{HTTPError, MethodNotAllowed, NotFound};
 use types::ViewArgs;
 use utils::join_string;
+use wrappers::Request;
 
 /// Parse a rule and return a list of tuples in the form
 /// `(Option<converter>, variable)`.  If the converter
@@ -68,6 +71,9 @@ fn parse_rule(rule: &str) -> Vec<(Option<&str>, &str)> {
 pub struct Matcher {
     pub regex: Regex,
     pub matches_query: bool,
+    /// The rule string the regex was compiled from.  URLs can only be
+    /// built for matchers that have one.
+    pub rule: Option<String>,
 }
 
 impl Matcher {
@@ -75,6 +81,7 @@ impl Matcher {
         Matcher {
             regex: regex,
             matches_query: matches_query,
+            rule: None,
         }
     }
 }
@@ -127,7 +134,9 @@ impl<'a> From<&'a str> for Matcher {
             regex_parts.push(String::from("(?P<__suffix__>/?)"));
         }
         let regex = format!(r"^{}$", join_string(regex_parts, ""));
-        Matcher::new(Regex::new(&regex).unwrap(), matches_query)
+        let mut matcher = Matcher::new(Regex::new(&regex).unwrap(), matches_query);
+        matcher.rule = Some(rule.to_string());
+        matcher
     }
 }
 
@@ -224,6 +233,51 @@ impl Rule {
             None => None,
         }
     }
+
+    /// Build the URL path of this rule with the given values.  Values that
+    /// are not variables of the rule are appended as query arguments.
+    /// Returns `None` if the rule can't be built with these values.
+    pub fn build(&self, values: &ViewArgs) -> Option<String> {
+        let rule = match self.matcher.rule {
+            Some(ref rule) => rule,
+            None => return None,
+        };
+        let mut path = String::new();
+        let mut used_names = HashSet::new();
+        for (converter, variable) in parse_rule(rule) {
+            match converter {
+                Some(converter) => {
+                    let value = values.get(variable)?;
+                    let encoded = if converter == "path" {
+                        utf8_percent_encode(value, DEFAULT_ENCODE_SET).to_string()
+                    } else {
+                        utf8_percent_encode(value, PATH_SEGMENT_ENCODE_SET).to_string()
+                    };
+                    path.push_str(&encoded);
+                    used_names.insert(variable);
+                },
+                None => path.push_str(variable),
+            }
+        }
+        // The values have to fit the converters.
+        match self.matched(&path) {
+            Some(Ok(_)) => (),
+            _ => return None,
+        }
+        let mut query_args: Vec<(&String, &String)> = values.iter()
+            .filter(|&(name, _)| !used_names.contains(&name[..]))
+            .collect();
+        if !query_args.is_empty() {
+            query_args.sort();
+            let mut serializer = form_urlencoded::Serializer::new(String::new());
+            for (name, value) in query_args {
+                serializer.append_pair(name, value);
+            }
+            path.push(if path.contains('?') { '&' } else { '?' });
+            path.push_str(&serializer.finish());
+        }
+        Some(path)
+    }
 }
 
 
@@ -251,6 +305,14 @@ impl Map {
     pub fn bind(&self, host: String, path: String, query_string: Option<String>, method: Method) -> MapAdapter {
         MapAdapter::new(self, host, path, query_string, method)
     }
+
+    /// Bind to the URL scheme, host, script root, path, query string and method of a request.
+    pub fn bind_to_request(&self, request: &Request) -> MapAdapter<'_> {
+        let mut adapter = self.bind(request.host(), request.path(), request.query_string(), request.method());
+        adapter.url_scheme = request.scheme();
+        adapter.script_root = request.script_root.clone();
+        adapter
+    }
 }
 
 
@@ -260,6 +322,7 @@ pub struct MapAdapter<'m> {
     map: &'m Map,
     url_scheme: String,
     host: String,
+    script_root: String,
     path: String,
     query_string: Option<String>,
     method: Method,
@@ -271,6 +334,7 @@ impl<'m> MapAdapter<'m> {
             map: map,
             url_scheme: "http".to_owned(),
             host: host,
+            script_root: String::new(),
             path: path,
             query_string: query_string,
             method: method,
@@ -284,7 +348,7 @@ impl<'m> MapAdapter<'m> {
         if let Some(ref query_string) = self.query_string {
             suffix = suffix + "?" + query_string;
         }
-        format!("{}://{}/{}{}", self.url_scheme, self.host, redirect_path, suffix)
+        format!("{}://{}{}/{}{}", self.url_scheme, self.host, self.script_root, redirect_path, suffix)
     }
 
     pub fn matched(&self) -> MapAdapterMatched {
@@ -331,6 +395,25 @@ impl<'m> MapAdapter<'m> {
         MapAdapterMatched::MatchedError(NotFound)
     }
 
+    /// Build the URL of an endpoint, relative to the host.  The first rule of
+    /// the endpoint that can be built with the values is used, the script
+    /// root is prepended.
+    pub fn build(&self, endpoint: &str, values: &ViewArgs) -> Option<String> {
+        for rule in &self.map.rules {
+            if rule.endpoint == endpoint {
+                if let Some(path) = rule.build(values) {
+                    return Some(self.script_root.clone() + &path);
+                }
+            }
+        }
+        None
+    }
+
+    /// Build the absolute URL of an endpoint, including scheme and host.
+    pub fn build_external(&self, endpoint: &str, values: &ViewArgs) -> Option<String> {
+        self.build(endpoint, values).map(|url| format!("{}://{}{}", self.url_scheme, self.host, url))
+    }
+
     /// Get the valid methods that match for the given path.
     pub fn allowed_methods(&self) -> Vec<Method> {
         let mut have_match_for = HashSet::new();
@@ -369,3 +452,23 @@ fn test_basic_routing() {
         _ => { panic!("Basic routing failed!"); }
     }
 }
+
+#[test]
+fn test_url_building() {
+    let mut map = Map::new();
+    map.add(Rule::new("/".into(), &[Method::Get], "index"));
+    map.add(Rule::new("/user/<user_id:int>".into(), &[Method::Get], "user"));
+    map.add(Rule::new("/static/<filename:path>".into(), &[Method::Get], "static"));
+    let mut adapter = map.bind(String::from("localhost"), String::from("/"), None, Method::Get);
+    adapter.script_root = String::from("/app");
+    let mut values = ViewArgs::new();
+    assert!(adapter.build("index", &values) == Some(String::from("/app/")));
+    values.insert(String::from("user_id"), String::from("42"));
+    values.insert(String::from("tab"), String::from("a b"));
+    assert!(adapter.build("user", &values) == Some(String::from("/app/user/42?tab=a+b")));
+    values.insert(String::from("user_id"), String::from("admin"));
+    assert!(adapter.build("user", &values).is_none());
+    let mut values = ViewArgs::new();
+    values.insert(String::from("filename"), String::from("css/main.css"));
+    assert!(adapter.build_external("static", &values) == Some(String::from("http://localhost/app/static/css/main.css")));
+}
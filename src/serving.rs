@@ -4,9 +4,18 @@ use std::{net::ToSocketAddrs, path::PathBuf, sync::Arc, sync::RwLock};
 use std::fmt::Write;
 
 use handlebars::{Handlebars, TemplateFileError};
-use hyper::server::Server;
+use hyper::net::NetworkListener;
+#[cfg(unix)]
+use hyper::net::HttpListener;
+use hyper::server::{Handler, Server};
+#[cfg(unix)]
+use hyper::server::{Request as HTTPRequest, Response as HTTPResponse};
 use notify::{Error as NotifyError, Event, EventFn, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use Pencil;
+use dispatcher::Dispatcher;
+use tls::{TlsConfig, TlsServer};
+#[cfg(unix)]
+use unix::{UnixSocketConfig, UnixSocketListener, InheritedListener, listen_fds};
 
 fn handle_modify(paths: &[PathBuf], registry: &RwLock<Handlebars<'_>>) {
     for path in paths {
@@ -42,18 +51,112 @@ fn watch_files(registry: Arc<RwLock<Handlebars<'static>>>) -> impl EventFn {
 }
 
 
-/// Run the `Pencil` application.
-pub fn run_server<A: ToSocketAddrs>(application: Pencil, addr: A, threads: usize) {
+/// Something the servers in this module can run: a `Pencil` application
+/// or a `Dispatcher` of several applications.
+pub trait Application: Handler + 'static {
+    /// Start live-reloading, the watchers stop when they are dropped.
+    fn watch(&self) -> Vec<RecommendedWatcher>;
+}
+
+impl Application for Pencil {
+    fn watch(&self) -> Vec<RecommendedWatcher> {
+        watch_templates(self).into_iter().collect()
+    }
+}
+
+impl Application for Dispatcher {
+    fn watch(&self) -> Vec<RecommendedWatcher> {
+        self.applications().flat_map(watch_templates).collect()
+    }
+}
+
+
+/// Run the application.
+pub fn run_server<H: Application, A: ToSocketAddrs>(application: H, addr: A, threads: usize) {
+    let server = Server::http(addr).unwrap();
+    serve(application, server, threads);
+}
+
+/// Run the application over TLS.
+pub fn run_tls_server<H: Application, A: ToSocketAddrs>(application: H, addr: A, tls_config: TlsConfig, threads: usize) {
+    let config = match tls_config.server_config() {
+        Ok(config) => config,
+        Err(e) => panic!("Can't load certificate {}: {}", tls_config.cert_path, e),
+    };
+    let tls_server = TlsServer::new(config);
+    let _cert_watcher = if tls_config.reload_on_change {
+        tls_server.watch(&tls_config)
+    } else {
+        None
+    };
+    let server = Server::https(addr, tls_server).unwrap();
+    serve(application, server, threads);
+}
 
-    let mut watcher: RecommendedWatcher;
+/// Run the application on a Unix domain socket.
+#[cfg(unix)]
+pub fn run_unix_server<H: Application>(application: H, unix_config: UnixSocketConfig, threads: usize) {
+    let listener = match unix_config.bind() {
+        Ok(listener) => listener,
+        Err(e) => panic!("Can't bind {}: {}", unix_config.path, e),
+    };
+    serve(application, Server::new(listener), threads);
+}
+
+/// Run the application on the sockets passed by the service manager.
+#[cfg(unix)]
+pub fn run_listen_fds_server<H: Application>(application: H, threads: usize) {
+    let listeners = match listen_fds() {
+        Ok(listeners) => listeners,
+        Err(e) => panic!("Can't take the sockets passed by the service manager: {}", e),
+    };
+    if listeners.is_empty() {
+        panic!("The service manager didn't pass any sockets.");
+    }
+    let _watchers = application.watch();
+    let application = Arc::new(application);
+    let mut guards = Vec::new();
+    for listener in listeners {
+        let handler = SharedApplication(application.clone());
+        let guard = match listener {
+            InheritedListener::Tcp(listener) => {
+                Server::new(HttpListener::from(listener)).handle_threads(handler, threads)
+            },
+            InheritedListener::Unix(listener) => {
+                Server::new(UnixSocketListener::from(listener)).handle_threads(handler, threads)
+            },
+        };
+        guards.push(guard.unwrap());
+    }
+}
+
+/// An application shared between several servers.
+#[cfg(unix)]
+struct SharedApplication<H>(Arc<H>);
+
+#[cfg(unix)]
+impl<H: Handler> Handler for SharedApplication<H> {
+    fn handle<'a, 'k>(&'a self, req: HTTPRequest<'a, 'k>, res: HTTPResponse<'a>) {
+        self.0.handle(req, res);
+    }
+}
+
+/// Watch the template folder if template live-reloading is enabled.
+fn watch_templates(application: &Pencil) -> Option<RecommendedWatcher> {
     if application.template_debug {
         let registry = application.handlebars_registry.clone();
         let template_dir = application.template_folder.clone();
-        watcher = Watcher::new_immediate(watch_files(registry)).unwrap();
+        let mut watcher: RecommendedWatcher = Watcher::new_immediate(watch_files(registry)).unwrap();
         info!("Begin watching {}", &template_dir);
         watcher.watch(&template_dir, RecursiveMode::Recursive).unwrap();
+        Some(watcher)
+    } else {
+        None
     }
+}
 
-    let server = Server::http(addr).unwrap();
+/// Handle the connections of `server` with the application until the server stops.
+fn serve<H: Application, L: NetworkListener + Send + 'static>(application: H, server: Server<L>, threads: usize) {
+    let _watchers = application.watch();
     let _guard = server.handle_threads(application, threads).unwrap();
 }
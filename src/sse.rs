@@ -0,0 +1,300 @@
+//! This module implements Server-Sent Events.
+
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::header::{CacheControl, CacheDirective, ContentType};
+use typemap::Key;
+
+use wrappers::{BodySender, Response};
+
+
+/// The comment sent to idle clients so proxies don't close the connection.
+const KEEP_ALIVE: &[u8] = b": keep-alive\n\n";
+
+
+/// One event of an event stream:
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use sharp_pencil::Event;
+///
+/// let event = Event::new()
+///     .with_id("42")
+///     .with_event("price")
+///     .with_data("{\"symbol\": \"PNCL\", \"price\": 12.5}")
+///     .with_retry(Duration::from_secs(5));
+/// assert_eq!(event.to_string(),
+///            "id: 42\nevent: price\nretry: 5000\ndata: {\"symbol\": \"PNCL\", \"price\": 12.5}\n\n");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comments: Vec<String>,
+}
+
+impl Event {
+    /// Create an empty event.
+    pub fn new() -> Event {
+        Event::default()
+    }
+
+    /// Set the id the client sends back as `Last-Event-ID` when it
+    /// reconnects.  Line breaks are removed.
+    pub fn with_id(mut self, id: &str) -> Event {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    /// Set the event type, clients dispatch events without one as `message`.
+    /// Line breaks are removed.
+    pub fn with_event(mut self, event: &str) -> Event {
+        self.event = Some(single_line(event));
+        self
+    }
+
+    /// Set the data of the event, it may span several lines.
+    pub fn with_data<S: Into<String>>(mut self, data: S) -> Event {
+        self.data = Some(data.into());
+        self
+    }
+
+    /// Set how long the client waits before reconnecting.
+    pub fn with_retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Add a comment, which clients ignore.
+    pub fn with_comment(mut self, comment: &str) -> Event {
+        self.comments.push(comment.to_owned());
+        self
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for comment in &self.comments {
+            for line in lines(comment) {
+                writeln!(f, ": {}", line)?;
+            }
+        }
+        if let Some(ref id) = self.id {
+            writeln!(f, "id: {}", id)?;
+        }
+        if let Some(ref event) = self.event {
+            writeln!(f, "event: {}", event)?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_secs() * 1000 + u64::from(retry.subsec_millis()))?;
+        }
+        if let Some(ref data) = self.data {
+            for line in lines(data) {
+                writeln!(f, "data: {}", line)?;
+            }
+        }
+        writeln!(f)
+    }
+}
+
+/// Remove the line breaks of a field that must stay on one line.
+fn single_line(value: &str) -> String {
+    value.chars().filter(|&c| c != '\r' && c != '\n').collect()
+}
+
+/// Split a value at any of the line breaks the event stream format knows.
+fn lines(value: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut rest = value;
+    while let Some(end) = rest.find(['\r', '\n']) {
+        lines.push(&rest[..end]);
+        let break_len = if rest[end..].starts_with("\r\n") { 2 } else { 1 };
+        rest = &rest[end + break_len..];
+    }
+    lines.push(rest);
+    lines
+}
+
+
+/// A `text/event-stream` response, see `EventStream::open`.
+pub struct EventStream {
+    keep_alive: Option<Duration>,
+}
+
+impl Default for EventStream {
+    fn default() -> EventStream {
+        EventStream::new()
+    }
+}
+
+impl EventStream {
+    /// Create an event stream sending a keep-alive comment after 15 seconds
+    /// without events.
+    pub fn new() -> EventStream {
+        EventStream {
+            keep_alive: Some(Duration::from_secs(15)),
+        }
+    }
+
+    /// Set how long the stream may be idle before a keep-alive comment is
+    /// sent, `None` sends none.  Keep-alive comments also notice clients
+    /// that went away.
+    pub fn with_keep_alive(mut self, keep_alive: Option<Duration>) -> EventStream {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Create the response and the sender writing events into it.  The
+    /// stream ends when all senders are dropped, and sending fails once the
+    /// client disconnected:
+    ///
+    /// ```rust,no_run
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// use sharp_pencil::{Event, EventStream, Request, PencilResult};
+    ///
+    ///
+    /// fn clock(_: &mut Request) -> PencilResult {
+    ///     let (response, sender) = EventStream::new().open();
+    ///     thread::spawn(move || {
+    ///         for tick in 0.. {
+    ///             let event = Event::new().with_id(&tick.to_string()).with_data(tick.to_string());
+    ///             if sender.send(&event).is_err() {
+    ///                 break;
+    ///             }
+    ///             thread::sleep(Duration::from_secs(1));
+    ///         }
+    ///     });
+    ///     Ok(response)
+    /// }
+    /// ```
+    ///
+    /// Every open stream keeps one of the server threads busy, so run the
+    /// application with enough threads for all clients.  The responses are
+    /// marked `no-transform` so they aren't compressed or buffered.
+    pub fn open(self) -> (Response, EventSender) {
+        let (mut response, sender) = Response::channel_with_keep_alive(self.keep_alive, KEEP_ALIVE);
+        // Event streams are always UTF-8, without a charset parameter.
+        response.headers.set(ContentType("text/event-stream".parse().unwrap()));
+        response.headers.set(CacheControl(vec![CacheDirective::NoCache, CacheDirective::NoTransform]));
+        response.headers.set_raw("X-Accel-Buffering", vec![b"no".to_vec()]);
+        (response, EventSender { sender })
+    }
+}
+
+
+/// The sending half of an event stream, see `EventStream::open`.
+#[derive(Clone)]
+pub struct EventSender {
+    sender: BodySender,
+}
+
+impl EventSender {
+    /// Send an event to the client.  This blocks while the client is behind,
+    /// and fails once the client disconnected.
+    pub fn send(&self, event: &Event) -> io::Result<()> {
+        self.sender.send(event.to_string())
+    }
+}
+
+impl fmt::Debug for EventSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<EventSender>")
+    }
+}
+
+
+/// Broadcasts events to all subscribed event streams.  Clones share their
+/// subscribers, and the hub can be kept in the extensions of the
+/// application for views to subscribe clients to:
+///
+/// ```rust,no_run
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// use sharp_pencil::{Event, EventHub, Pencil, Request, PencilResult};
+///
+///
+/// fn updates(request: &mut Request) -> PencilResult {
+///     let hub = request.app.extensions.get::<EventHub>().unwrap();
+///     Ok(hub.subscribe())
+/// }
+///
+/// fn main() {
+///     let mut app = Pencil::new("/web/dashboard");
+///     let hub = EventHub::new();
+///     app.extensions.insert::<EventHub>(hub.clone());
+///     thread::spawn(move || {
+///         loop {
+///             hub.broadcast(&Event::new().with_event("load").with_data("0.42"));
+///             thread::sleep(Duration::from_secs(5));
+///         }
+///     });
+///     app.get("/updates", "updates", updates);
+///     app.run_threads("127.0.0.1:5000", 64);
+/// }
+/// ```
+///
+/// Broadcasting never waits for clients: subscribers that fell too far
+/// behind are dropped from the hub, their streams end and the clients
+/// reconnect.
+#[derive(Clone, Default)]
+pub struct EventHub {
+    subscribers: Arc<Mutex<Vec<EventSender>>>,
+}
+
+impl Key for EventHub {
+    type Value = EventHub;
+}
+
+impl EventHub {
+    /// Create a hub without subscribers.
+    pub fn new() -> EventHub {
+        EventHub::default()
+    }
+
+    /// Open an event stream subscribed to the hub.
+    pub fn subscribe(&self) -> Response {
+        let (response, sender) = EventStream::new().open();
+        self.add_subscriber(sender);
+        response
+    }
+
+    /// Subscribe an open event stream, e.g. one that was sent the events
+    /// the client missed or that has its own keep-alive interval.
+    pub fn add_subscriber(&self, sender: EventSender) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(sender);
+        }
+    }
+
+    /// Send an event to all subscribers, returns how many got it.
+    pub fn broadcast(&self, event: &Event) -> usize {
+        let chunk = event.to_string().into_bytes();
+        let mut subscribers = match self.subscribers.lock() {
+            Ok(subscribers) => subscribers,
+            Err(_) => return 0,
+        };
+        subscribers.retain(|subscriber| subscriber.sender.try_send(chunk.clone()).is_ok());
+        subscribers.len()
+    }
+
+    /// The number of subscribers, including ones that disconnected since
+    /// the last broadcast.
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().map(|subscribers| subscribers.len()).unwrap_or(0)
+    }
+}
+
+impl fmt::Debug for EventHub {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<EventHub {} subscribers>", self.subscriber_count())
+    }
+}
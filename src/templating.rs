@@ -1,28 +1,17 @@
 //! This module implements the bridge to handlebars.
-use std::convert;
 use std::io::Read;
 use std::io::Result as IOResult;
+use std::io::{Error as IOError, ErrorKind};
 use std::fs::File;
 use std::path::PathBuf;
 
 use serde::Serialize;
-use handlebars::{RenderError, TemplateRenderError};
 
 use app::Pencil;
-use types::{PencilResult, PenUserError, UserError, PencilError};
+use manifest::{current_script_root, with_script_root};
+use types::{PencilError, PencilResult, PenUserError, UserError};
 use wrappers::Response;
 
-impl convert::From<RenderError> for PencilError {
-    fn from(err: RenderError) -> PencilError {
-        PenUserError(UserError::new(err.to_string()))
-    }
-}
-
-impl convert::From<TemplateRenderError> for PencilError {
-    fn from(err: TemplateRenderError) -> PencilError {
-        PenUserError(UserError::new(err.to_string()))
-    }
-}
 
 pub fn render_template<T: Serialize>(app: &Pencil, template_name: &str, context: &T)
     -> PencilResult
@@ -32,7 +21,7 @@ pub fn render_template<T: Serialize>(app: &Pencil, template_name: &str, context:
         return Err(PenUserError(UserError::new("Can't acquire handlebars registry")));
     }
     let registry = registry_read_rv.unwrap();
-    let rv = registry.render(template_name, context)?;
+    let rv = in_script_root(app, || registry.render(template_name, context).map_err(PencilError::from))?;
     Ok(Response::from(rv))
 }
 
@@ -44,10 +33,19 @@ pub fn render_template_string<T: Serialize>(app: &Pencil, source: &str, context:
         return Err(PenUserError(UserError::new("Can't acquire handlebars registry")));
     }
     let registry = registry_read_rv.unwrap();
-    let rv = registry.render_template(source, context)?;
+    let rv = in_script_root(app, || registry.render_template(source, context).map_err(PencilError::from))?;
     Ok(Response::from(rv))
 }
 
+/// Templates rendered outside of requests link static files below the
+/// application root.
+fn in_script_root<T, F: FnOnce() -> T>(app: &Pencil, f: F) -> T {
+    match current_script_root() {
+        Some(_) => f(),
+        None => with_script_root(&app.application_root(), f),
+    }
+}
+
 /// The template loader trait allows for loading template source.
 trait TemplateLoader {
     /// Get the template source for a template name.
@@ -95,6 +93,12 @@ impl TemplateLoader for FileSystemLoader {
 }
 
 pub fn load_template(app: &Pencil, template_name: &str) -> Option<IOResult<String>> {
+    if let Some(ref source) = app.template_assets {
+        if let Some(asset) = source.get(template_name) {
+            return Some(String::from_utf8(asset.data.into_owned())
+                .map_err(|err| IOError::new(ErrorKind::InvalidData, err)));
+        }
+    }
     let mut template_path = PathBuf::from(&app.root_path);
     template_path.push(&app.template_folder);
     let template_loader = FileSystemLoader::new(template_path.to_str().unwrap());
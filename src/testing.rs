@@ -1,11 +1,54 @@
 //! This module implements test support helpers.
 
+use std::io::{self, Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::buffer::BufReader;
+use hyper::net::NetworkStream;
+use hyper::server::Request as HttpRequest;
+
 use app::Pencil;
-use wrappers::{Request, Response};
+use wrappers::{Request, Response, ResponseBody};
+
+
+/// A connection that replays a raw HTTP request.
+struct MockStream {
+    input: Cursor<Vec<u8>>,
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl NetworkStream for MockStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok("127.0.0.1:40000".parse().unwrap())
+    }
+
+    fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
 
 
 /// This type allows to send requests to a wrapped application.
-#[allow(dead_code)]
 pub struct PencilClient<'c> {
     application: &'c Pencil,
 }
@@ -17,7 +60,6 @@ impl<'c> PencilClient<'c> {
     }
 
     /// Get wrapped application.
-    #[allow(dead_code)]
     pub fn get_application(&self) -> &Pencil {
         self.application
     }
@@ -27,12 +69,36 @@ impl<'c> PencilClient<'c> {
         self.application.handle_request(request)
     }
 
-    fn open(&self, mut request: Request) -> Response {
+    /// Send a request without body to the wrapped application.  The host
+    /// is `localhost` unless a `Host` header is given.
+    pub fn open(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> Response {
+        let mut raw = format!("{} {} HTTP/1.1\r\n", method, path);
+        if !headers.iter().any(|&(name, _)| name.eq_ignore_ascii_case("Host")) {
+            raw.push_str("Host: localhost\r\n");
+        }
+        for &(name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        let mut stream = MockStream { input: Cursor::new(raw.into_bytes()) };
+        let mut reader = BufReader::new(&mut stream as &mut dyn NetworkStream);
+        let http_request = HttpRequest::new(&mut reader, "127.0.0.1:40000".parse().unwrap()).unwrap();
+        let mut request = Request::new(self.application, http_request).unwrap();
         self.run_pencil_app(&mut request)
     }
 
-    #[allow(dead_code)]
-    pub fn get(&self, request: Request) -> Response {
-        self.open(request)
+    /// Send a `GET` request to the wrapped application.
+    pub fn get(&self, path: &str) -> Response {
+        self.open("GET", path, &[])
+    }
+}
+
+
+/// Write the whole body of a response into memory.
+pub fn read_body(response: Response) -> Vec<u8> {
+    let mut body = Vec::new();
+    if let Some(mut response_body) = response.body {
+        response_body.write_body(&mut ResponseBody::new(&mut body)).unwrap();
     }
+    body
 }
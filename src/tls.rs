@@ -0,0 +1,356 @@
+//! This module implements TLS termination with rustls.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
+
+use hyper;
+use hyper::net::{HttpStream, NetworkStream, SslServer};
+use notify::{Error as NotifyError, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls_pemfile::{self, Item};
+
+
+/// The TLS configuration used by `Pencil::run_tls`.  Certificates and keys
+/// are loaded from PEM files:
+///
+/// ```rust,no_run
+/// use sharp_pencil::{Pencil, TlsConfig};
+///
+/// fn main() {
+///     let app = Pencil::new("/web/demo");
+///     let mut tls = TlsConfig::new("/etc/demo/cert.pem", "/etc/demo/key.pem");
+///     tls.client_ca_path = Some(String::from("/etc/demo/clients.pem"));
+///     app.run_tls_threads("0.0.0.0:443", tls, 15);
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// The PEM file with the certificate chain, leaf certificate first.
+    pub cert_path: String,
+    /// The PEM file with the private key (PKCS#8, RSA or EC).
+    pub key_path: String,
+    /// A PEM file with the CA certificates client certificates are verified
+    /// against.  Client certificates are not requested if this is `None`.
+    pub client_ca_path: Option<String>,
+    /// Reject clients that don't present a valid certificate.  Only used if
+    /// `client_ca_path` is set, defaults to `false`.
+    pub client_auth_required: bool,
+    /// Reload the certificates when the files change on disk.  Defaults to `true`.
+    pub reload_on_change: bool,
+}
+
+impl TlsConfig {
+    /// Create a TLS configuration from the certificate chain and private key files.
+    pub fn new(cert_path: &str, key_path: &str) -> TlsConfig {
+        TlsConfig {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            client_ca_path: None,
+            client_auth_required: false,
+            reload_on_change: true,
+        }
+    }
+
+    /// Load the files and build the rustls server configuration.
+    pub fn server_config(&self) -> io::Result<ServerConfig> {
+        let certs = load_certs(&self.cert_path)?;
+        let key = load_private_key(&self.key_path)?;
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match self.client_ca_path {
+            Some(ref client_ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(client_ca_path)? {
+                    roots.add(&cert).map_err(|e| invalid_data(format!("{}: {:?}", client_ca_path, e)))?;
+                }
+                if self.client_auth_required {
+                    builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+                } else {
+                    builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+                }
+            },
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs, key)
+            .map_err(|e| invalid_data(format!("{}: {}", self.key_path, e)))?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Load all certificates from a PEM file.
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificates found in {}", path)));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Load the first private key from a PEM file.
+fn load_private_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(Item::PKCS8Key(key)) | Some(Item::RSAKey(key)) | Some(Item::ECKey(key)) => {
+                return Ok(PrivateKey(key));
+            },
+            Some(_) => continue,
+            None => return Err(invalid_data(format!("no private key found in {}", path))),
+        }
+    }
+}
+
+
+/// A TLS connection to one client.
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<StreamOwned<ServerConnection, HttpStream>>>);
+
+impl TlsStream {
+    fn lock(&self) -> io::Result<MutexGuard<'_, StreamOwned<ServerConnection, HttpStream>>> {
+        self.0.lock().map_err(|_| io::Error::other("TLS stream lock poisoned"))
+    }
+
+    /// The DER encoded certificate chain the client presented, if any.
+    pub fn peer_certificates(&self) -> Option<Vec<Vec<u8>>> {
+        let stream = self.lock().ok()?;
+        stream.conn.peer_certificates().map(|certs| {
+            certs.iter().map(|cert| cert.0.clone()).collect()
+        })
+    }
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<TlsStream>")
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock()?.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock()?.flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.lock()?.sock.peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock()?.sock.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock()?.sock.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        let mut stream = self.lock()?;
+        let StreamOwned { ref mut conn, ref mut sock } = *stream;
+        conn.send_close_notify();
+        while conn.wants_write() {
+            if conn.write_tls(sock).is_err() {
+                break;
+            }
+        }
+        sock.close(how)
+    }
+}
+
+
+/// The hyper SSL server backed by rustls.  The configuration can be swapped
+/// while the server is running, new connections pick up the new certificates.
+#[derive(Clone)]
+pub struct TlsServer {
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsServer {
+    pub fn new(config: ServerConfig) -> TlsServer {
+        TlsServer {
+            config: Arc::new(RwLock::new(Arc::new(config))),
+        }
+    }
+
+    /// Watch the certificate files of `tls_config` and reload them on change.
+    pub fn watch(&self, tls_config: &TlsConfig) -> Option<RecommendedWatcher> {
+        let mut paths = vec![PathBuf::from(&tls_config.cert_path), PathBuf::from(&tls_config.key_path)];
+        if let Some(ref client_ca_path) = tls_config.client_ca_path {
+            paths.push(PathBuf::from(client_ca_path));
+        }
+        let watched = paths.clone();
+        let current = self.config.clone();
+        let tls_config = tls_config.clone();
+        let event_fn = move |event: Result<Event, NotifyError>| match event {
+            Ok(Event { kind: EventKind::Create(_), paths, .. }) |
+            Ok(Event { kind: EventKind::Modify(_), paths, .. })
+                if paths.iter().any(|path| is_watched(&watched, path)) => reload(&tls_config, &current),
+            Err(e) => warn!("certificate watch error: {:?}", e),
+            _ => (),
+        };
+        let mut watcher: RecommendedWatcher = match Watcher::new_immediate(event_fn) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                warn!("Can't watch certificates: {:?}", e);
+                return None;
+            }
+        };
+        // Watch the directories, certificates are usually replaced rather than modified.
+        for path in &paths {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                warn!("Can't watch {}: {:?}", dir.display(), e);
+            }
+        }
+        Some(watcher)
+    }
+}
+
+fn is_watched(watched: &[PathBuf], path: &Path) -> bool {
+    watched.iter().any(|w| w.file_name().is_some() && w.file_name() == path.file_name())
+}
+
+fn reload(tls_config: &TlsConfig, current: &RwLock<Arc<ServerConfig>>) {
+    match tls_config.server_config() {
+        Ok(config) => {
+            if let Ok(mut current) = current.write() {
+                *current = Arc::new(config);
+                info!("Reloaded certificate {}", tls_config.cert_path);
+            }
+        },
+        Err(e) => warn!("Can't reload certificate {}: {}", tls_config.cert_path, e),
+    }
+}
+
+impl SslServer for TlsServer {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<TlsStream> {
+        let config = match self.config.read() {
+            Ok(config) => config.clone(),
+            Err(_) => return Err(hyper::Error::Ssl("TLS config lock poisoned".into())),
+        };
+        let conn = ServerConnection::new(config).map_err(|e| hyper::Error::Ssl(Box::new(e)))?;
+        Ok(TlsStream(Arc::new(Mutex::new(StreamOwned::new(conn, stream)))))
+    }
+}
+
+
+#[cfg(test)]
+fn fixture(name: &str) -> String {
+    format!("{}/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// Send a request over TLS to the server at `addr`, trusting only `ca_path`.
+#[cfg(test)]
+fn tls_get(addr: SocketAddr, ca_path: &str, path: &str) -> io::Result<String> {
+    use std::convert::TryFrom;
+    use std::net::TcpStream;
+    use rustls::{ClientConfig, ClientConnection, ServerName};
+
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots.add(&cert).unwrap();
+    }
+    let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+    let conn = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+    let mut stream = StreamOwned::new(conn, TcpStream::connect(addr)?);
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[test]
+fn test_load_pem_files() {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    assert!(load_certs(&fixture("localhost-cert.pem")).unwrap().len() == 1);
+    assert!(load_private_key(&fixture("localhost-key.pem")).is_ok());
+    assert!(TlsConfig::new(&fixture("localhost-cert.pem"), &fixture("localhost-key.pem")).server_config().is_ok());
+
+    let dir = env::temp_dir().join(format!("pencil-tls-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let empty = dir.join("empty.pem");
+    let invalid = dir.join("invalid.pem");
+    fs::write(&empty, "").unwrap();
+    fs::write(&invalid, "-----BEGIN CERTIFICATE-----\nnot base64!\n-----END CERTIFICATE-----\n").unwrap();
+    let (empty, invalid) = (empty.to_str().unwrap(), invalid.to_str().unwrap());
+    assert!(load_certs(empty).unwrap_err().kind() == io::ErrorKind::InvalidData);
+    assert!(load_certs(invalid).is_err());
+    assert!(load_certs(&dir.join("missing.pem").to_string_lossy()).unwrap_err().kind() == io::ErrorKind::NotFound);
+    // A certificate file has no private key.
+    assert!(load_private_key(&fixture("localhost-cert.pem")).unwrap_err().kind() == io::ErrorKind::InvalidData);
+    assert!(load_private_key(empty).is_err());
+    assert!(TlsConfig::new(&fixture("localhost-cert.pem"), &fixture("localhost-cert.pem")).server_config().is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_tls_server_and_reload() {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use hyper::server::Server;
+
+    use app::Pencil;
+    use wrappers::{Request, Response};
+
+    let dir = env::temp_dir().join(format!("pencil-tls-reload-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::copy(fixture("localhost-cert.pem"), &cert_path).unwrap();
+    fs::copy(fixture("localhost-key.pem"), &key_path).unwrap();
+    let tls_config = TlsConfig::new(cert_path.to_str().unwrap(), key_path.to_str().unwrap());
+
+    let mut app = Pencil::new("/test");
+    app.get("/scheme", "scheme", |request: &mut Request| {
+        Ok(Response::from(format!("{} {}", request.scheme(), request.is_secure())))
+    });
+    let tls_server = TlsServer::new(tls_config.server_config().unwrap());
+    let mut listening = Server::https("127.0.0.1:0", tls_server.clone()).unwrap().handle_threads(app, 1).unwrap();
+    // Detach the server, dropping `Listening` waits for it forever.
+    listening.close().unwrap();
+    let response = tls_get(listening.socket, &fixture("localhost-cert.pem"), "/scheme").unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("\r\n\r\nhttps true"));
+    assert!(tls_get(listening.socket, &fixture("renewed-cert.pem"), "/scheme").is_err());
+
+    // New connections get the renewed certificate, a broken one keeps the old.
+    fs::copy(fixture("renewed-cert.pem"), &cert_path).unwrap();
+    fs::copy(fixture("renewed-key.pem"), &key_path).unwrap();
+    reload(&tls_config, &tls_server.config);
+    assert!(tls_get(listening.socket, &fixture("renewed-cert.pem"), "/scheme").unwrap().ends_with("https true"));
+    assert!(tls_get(listening.socket, &fixture("localhost-cert.pem"), "/scheme").is_err());
+    fs::write(&cert_path, "").unwrap();
+    reload(&tls_config, &tls_server.config);
+    assert!(tls_get(listening.socket, &fixture("renewed-cert.pem"), "/scheme").unwrap().ends_with("https true"));
+    fs::remove_dir_all(&dir).unwrap();
+}
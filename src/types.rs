@@ -1,5 +1,6 @@
 //! This module implements a number of types.
 
+use std::any::TypeId;
 use std::collections::HashMap;
 use std::error;
 use std::convert;
@@ -10,7 +11,9 @@ pub use http_errors::HTTPError;
 
 pub use self::PencilError::{
     PenHTTPError,
-    PenUserError
+    PenUserError,
+    PenError,
+    PenResponse,
 };
 
 
@@ -41,36 +44,85 @@ impl error::Error for UserError {
 }
 
 
-/// The Pencil Error type.
-#[derive(Clone, Debug)]
+/// The Pencil Error type.  Any error type can be turned into it with `?`,
+/// `HTTPError` and `UserError` keep their own variants, everything else is
+/// boxed into `PenError`:
+///
+/// ```rust,no_run
+/// use sharp_pencil::{Request, PencilResult, Response};
+///
+///
+/// fn view(_: &mut Request) -> PencilResult {
+///     let number: i32 = "forty-two".parse()?;
+///     Ok(Response::from(number.to_string()))
+/// }
+/// ```
+///
+/// Handlers for boxed errors are registered per error type with
+/// `Pencil::register_error_handler`.  `PenResponse` stops the request
+/// handling early with the given response, see `abort_with`.
+///
+/// Since boxed errors can't be cloned, `PencilError` is not `Clone`.  It
+/// doesn't implement `std::error::Error` either, that would conflict with
+/// the conversion from all error types; use `as_error` instead.
+#[derive(Debug)]
 pub enum PencilError {
     PenHTTPError(HTTPError),
     PenUserError(UserError),
+    PenError(Box<dyn error::Error + Send + Sync>),
+    PenResponse(Box<Response>),
+}
+
+impl<E: error::Error + Send + Sync + 'static> convert::From<E> for PencilError {
+    fn from(err: E) -> PencilError {
+        let err: Box<dyn error::Error + Send + Sync> = Box::new(err);
+        let err = match err.downcast::<HTTPError>() {
+            Ok(err) => return PenHTTPError(*err),
+            Err(err) => err,
+        };
+        match err.downcast::<UserError>() {
+            Ok(err) => PenUserError(*err),
+            Err(err) => PenError(err),
+        }
+    }
 }
 
-impl convert::From<HTTPError> for PencilError {
-    fn from(err: HTTPError) -> PencilError {
-        PenHTTPError(err)
+impl PencilError {
+    /// The error as `std::error::Error`, e.g. to walk the `source()` chain
+    /// or to downcast it.
+    pub fn as_error(&self) -> &(dyn error::Error + Send + Sync + 'static) {
+        match *self {
+            PenHTTPError(ref err) => err,
+            PenUserError(ref err) => err,
+            PenError(ref err) => &**err,
+            PenResponse(_) => &Aborted,
+        }
     }
 }
 
-impl convert::From<UserError> for PencilError {
-    fn from(err: UserError) -> PencilError {
-        PenUserError(err)
+/// The error of a request aborted with a custom response.
+#[derive(Debug)]
+struct Aborted;
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Aborted with a custom response")
     }
 }
 
+impl error::Error for Aborted {}
+
 impl fmt::Display for PencilError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             PenHTTPError(ref err) => write!(f, "{}", err),
             PenUserError(ref err) => write!(f, "{}", err),
+            PenError(ref err) => write!(f, "{}", err),
+            PenResponse(ref response) => write!(f, "Aborted with a {} response", response.status_code),
         }
     }
 }
 
-impl error::Error for PencilError {}
-
 /// The Pencil Result type.
 pub type PencilResult = Result<Response, PencilError>;
 
@@ -82,9 +134,32 @@ pub type ViewFunc = fn(&mut Request) -> PencilResult;
 
 
 /// HTTP Error handler type.
-pub type HTTPErrorHandler = dyn Fn(HTTPError) -> PencilResult + Send + Sync;
+pub type HTTPErrorHandler = dyn Fn(HTTPError, &Request) -> PencilResult + Send + Sync;
 /// User Error handler type.
-pub type UserErrorHandler = dyn Fn(UserError) -> PencilResult + Send + Sync;
+pub type UserErrorHandler = dyn Fn(UserError, &Request) -> PencilResult + Send + Sync;
+/// Typed error handler type.  It returns `None` if the error is not of the
+/// type the handler was registered for.
+pub type ErrorHandler = dyn Fn(&(dyn error::Error + 'static), &Request) -> Option<PencilResult> + Send + Sync;
+
+/// Typed error handlers in registration order with the error type they
+/// handle.  A boxed error doesn't expose its `TypeId` on stable Rust, so
+/// they are tried in order, each downcasting to its own type.
+pub type ErrorHandlers = Vec<(TypeId, Box<ErrorHandler>)>;
+
+/// Register a handler for errors of type `E`, replacing the handler
+/// registered for that type before.
+pub fn register_typed_error_handler<E, F>(handlers: &mut ErrorHandlers, f: F)
+    where E: error::Error + 'static, F: Fn(&E, &Request) -> PencilResult + Send + Sync + 'static
+{
+    let handler: Box<ErrorHandler> = Box::new(move |e: &(dyn error::Error + 'static), request: &Request| {
+        e.downcast_ref::<E>().map(|e| f(e, request))
+    });
+    let type_id = TypeId::of::<E>();
+    match handlers.iter().position(|&(id, _)| id == type_id) {
+        Some(index) => handlers[index].1 = handler,
+        None => handlers.push((type_id, handler)),
+    }
+}
 
 /// Before request func type.
 pub type BeforeRequestFunc = dyn Fn(&mut Request) -> Option<PencilResult> + Send + Sync;
@@ -95,4 +170,26 @@ pub type AfterRequestFunc = dyn Fn(&Request, &mut Response) + Send + Sync;
 
 
 /// Teardown request func type.
-pub type TeardownRequestFunc = dyn Fn(Option<&PencilError>) + Send + Sync;
+pub type TeardownRequestFunc = dyn Fn(&Request, Option<&PencilError>) + Send + Sync;
+
+
+/// Adapt a http error handler that doesn't need the request.
+pub fn http_error_handler_adapter<F>(f: F) -> Box<HTTPErrorHandler>
+    where F: Fn(HTTPError) -> PencilResult + Send + Sync + 'static
+{
+    Box::new(move |e: HTTPError, _: &Request| f(e))
+}
+
+/// Adapt a user error handler that doesn't need the request.
+pub fn user_error_handler_adapter<F>(f: F) -> Box<UserErrorHandler>
+    where F: Fn(UserError) -> PencilResult + Send + Sync + 'static
+{
+    Box::new(move |e: UserError, _: &Request| f(e))
+}
+
+/// Adapt a teardown function that doesn't need the request.
+pub fn teardown_request_adapter<F>(f: F) -> Box<TeardownRequestFunc>
+    where F: Fn(Option<&PencilError>) + Send + Sync + 'static
+{
+    Box::new(move |_: &Request, e: Option<&PencilError>| f(e))
+}
@@ -0,0 +1,345 @@
+//! This module implements listening on Unix domain sockets and on sockets
+//! inherited from the service manager.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper;
+use hyper::net::{NetworkListener, NetworkStream};
+
+
+/// The first file descriptor passed by the service manager.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// hyper wants an IP address for every listener and peer, Unix sockets
+/// don't have one.
+fn unspecified_addr() -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+}
+
+
+/// The Unix domain socket configuration used by `Pencil::run_unix`:
+///
+/// ```rust,no_run
+/// use sharp_pencil::{Pencil, UnixSocketConfig};
+///
+/// fn main() {
+///     let app = Pencil::new("/web/demo");
+///     let mut unix = UnixSocketConfig::new("/run/demo/demo.sock");
+///     unix.mode = Some(0o660);
+///     app.run_unix_threads(unix, 15);
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct UnixSocketConfig {
+    /// The path of the socket file.
+    pub path: String,
+    /// The permissions the socket file is set to after binding, e.g. `0o660`.
+    pub mode: Option<u32>,
+    /// Remove a stale socket file left over by a previous run before binding.
+    /// Only socket files are removed.  Defaults to `true`.
+    pub remove_existing: bool,
+    /// Remove the socket file when the server stops.  Defaults to `true`.
+    ///
+    /// The file is removed when the listener is dropped, which doesn't
+    /// happen when the process is killed by a signal like `SIGTERM` or
+    /// `SIGINT`.  The file is left behind then, and `remove_existing`
+    /// takes care of it on the next start.
+    pub remove_on_exit: bool,
+}
+
+impl UnixSocketConfig {
+    /// Create a Unix domain socket configuration for the socket file at `path`.
+    pub fn new(path: &str) -> UnixSocketConfig {
+        UnixSocketConfig {
+            path: path.to_string(),
+            mode: None,
+            remove_existing: true,
+            remove_on_exit: true,
+        }
+    }
+
+    /// Bind the socket.
+    pub fn bind(&self) -> io::Result<UnixSocketListener> {
+        let path = Path::new(&self.path);
+        if self.remove_existing {
+            if let Ok(metadata) = fs::symlink_metadata(path) {
+                if metadata.file_type().is_socket() {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        let listener = UnixListener::bind(path)?;
+        let cleanup = if self.remove_on_exit {
+            Some(SocketFileCleanup(path.to_path_buf()))
+        } else {
+            None
+        };
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        let mut listener = UnixSocketListener::from(listener);
+        listener.cleanup = cleanup.map(Arc::new);
+        Ok(listener)
+    }
+}
+
+/// Removes the socket file once the last listener handle is gone.
+struct SocketFileCleanup(PathBuf);
+
+impl Drop for SocketFileCleanup {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            warn!("Can't remove socket {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+
+/// A `NetworkListener` for Unix domain sockets.
+#[derive(Clone)]
+pub struct UnixSocketListener {
+    listener: Arc<UnixListener>,
+    cleanup: Option<Arc<SocketFileCleanup>>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl From<UnixListener> for UnixSocketListener {
+    fn from(listener: UnixListener) -> UnixSocketListener {
+        UnixSocketListener {
+            listener: Arc::new(listener),
+            cleanup: None,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+}
+
+impl NetworkListener for UnixSocketListener {
+    type Stream = UnixSocketStream;
+
+    fn accept(&mut self) -> hyper::Result<UnixSocketStream> {
+        let (stream, _) = self.listener.accept()?;
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        Ok(UnixSocketStream(Arc::new(stream)))
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(unspecified_addr())
+    }
+
+    fn set_read_timeout(&mut self, duration: Option<Duration>) {
+        self.read_timeout = duration;
+    }
+
+    fn set_write_timeout(&mut self, duration: Option<Duration>) {
+        self.write_timeout = duration;
+    }
+}
+
+
+/// A connection accepted on a Unix domain socket.
+#[derive(Clone)]
+pub struct UnixSocketStream(Arc<UnixStream>);
+
+impl UnixSocketStream {
+    /// The path the peer socket is bound to.  Clients usually connect from
+    /// unnamed sockets, so this is mostly `None`.
+    pub fn peer_path(&self) -> Option<PathBuf> {
+        match self.0.peer_addr() {
+            Ok(addr) => addr.as_pathname().map(|path| path.to_path_buf()),
+            Err(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for UnixSocketStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("UnixSocketStream(_)")
+    }
+}
+
+impl Read for UnixSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl Write for UnixSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+impl NetworkStream for UnixSocketStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(unspecified_addr())
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        match self.0.shutdown(how) {
+            Ok(_) => Ok(()),
+            // Already closed by the peer.
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+
+/// A listening socket passed by the service manager.
+#[derive(Debug)]
+pub enum InheritedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Take the listening sockets passed with systemd-style socket activation,
+/// that is the `LISTEN_PID` and `LISTEN_FDS` environment variables.  The
+/// variables are removed so child processes don't inherit them.
+pub fn listen_fds() -> io::Result<Vec<InheritedListener>> {
+    let not_found = |msg: &str| io::Error::new(io::ErrorKind::NotFound, msg.to_string());
+    let listen_pid = env::var("LISTEN_PID").map_err(|_| not_found("LISTEN_PID is not set"))?;
+    let listen_fds = env::var("LISTEN_FDS").map_err(|_| not_found("LISTEN_FDS is not set"))?;
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if listen_pid.trim().parse::<u32>().ok() != Some(process::id()) {
+        return Err(not_found("LISTEN_PID doesn't match this process"));
+    }
+    let count = listen_fds.trim().parse::<RawFd>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "LISTEN_FDS is not a number"))?;
+    let mut listeners = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        // The service manager hands these over to us, nobody else owns them.
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        // Only IP sockets have an IP address.
+        if listener.local_addr().is_ok() {
+            listeners.push(InheritedListener::Tcp(listener));
+        } else {
+            let fd = listener.into_raw_fd();
+            listeners.push(InheritedListener::Unix(unsafe { UnixListener::from_raw_fd(fd) }));
+        }
+    }
+    Ok(listeners)
+}
+
+
+#[cfg(test)]
+fn temp_socket_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("pencil-{}-{}.sock", name, process::id()))
+}
+
+#[test]
+fn test_bind_unix_socket() {
+    let path = temp_socket_path("bind");
+    let mut config = UnixSocketConfig::new(path.to_str().unwrap());
+    config.mode = Some(0o600);
+    let listener = config.bind().unwrap();
+    let metadata = fs::symlink_metadata(&path).unwrap();
+    assert!(metadata.file_type().is_socket());
+    assert!(metadata.permissions().mode() & 0o777 == 0o600);
+    // Clones share the listener, the file goes with the last one.
+    let clone = listener.clone();
+    drop(listener);
+    assert!(path.exists());
+    drop(clone);
+    assert!(!path.exists());
+
+    config.remove_on_exit = false;
+    drop(config.bind().unwrap());
+    assert!(path.exists());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_bind_stale_unix_socket() {
+    let path = temp_socket_path("stale");
+    // Listeners of the standard library leave their file behind.
+    drop(UnixListener::bind(&path).unwrap());
+    let mut config = UnixSocketConfig::new(path.to_str().unwrap());
+    config.remove_existing = false;
+    assert!(config.bind().err().unwrap().kind() == io::ErrorKind::AddrInUse);
+    config.remove_existing = true;
+    drop(config.bind().unwrap());
+    assert!(!path.exists());
+
+    // Other files are never removed.
+    fs::write(&path, "data").unwrap();
+    assert!(config.bind().is_err());
+    assert!(fs::read(&path).unwrap() == b"data");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_listen_fds() {
+    let not_found = || listen_fds().err().unwrap().kind() == io::ErrorKind::NotFound;
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    assert!(not_found());
+    env::set_var("LISTEN_PID", process::id().to_string());
+    assert!(not_found());
+    env::set_var("LISTEN_PID", (process::id() + 1).to_string());
+    env::set_var("LISTEN_FDS", "1");
+    assert!(not_found());
+    // The variables are taken even if they are for another process.
+    assert!(env::var("LISTEN_PID").is_err() && env::var("LISTEN_FDS").is_err());
+    env::set_var("LISTEN_PID", process::id().to_string());
+    env::set_var("LISTEN_FDS", "many");
+    assert!(listen_fds().err().unwrap().kind() == io::ErrorKind::InvalidData);
+    env::set_var("LISTEN_PID", format!("{}\n", process::id()));
+    env::set_var("LISTEN_FDS", "0");
+    env::set_var("LISTEN_FDNAMES", "");
+    assert!(listen_fds().unwrap().is_empty());
+    assert!(env::var("LISTEN_FDNAMES").is_err());
+}
+
+#[test]
+fn test_request_over_unix_socket() {
+    use hyper::server::Server;
+
+    use app::Pencil;
+    use wrappers::{Request, Response};
+
+    let path = temp_socket_path("request");
+    let mut app = Pencil::new("/test");
+    app.get("/peer", "peer", |request: &mut Request| {
+        Ok(Response::from(format!("{:?} {}", request.remote_addr(), request.remote_addr().ip().is_none())))
+    });
+    let listener = UnixSocketConfig::new(path.to_str().unwrap()).bind().unwrap();
+    let mut listening = Server::new(listener).handle_threads(app, 1).unwrap();
+    // Detach the server, dropping `Listening` waits for it forever.
+    listening.close().unwrap();
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream.write_all(b"GET /peer HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("\r\n\r\nUnix(None) true"));
+    // The server thread keeps the listener, and with it the file.
+    fs::remove_file(&path).unwrap();
+}
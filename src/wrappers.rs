@@ -2,43 +2,112 @@
 
 use std::fmt;
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::fs::File;
 use std::io::{self, Read, Write, Take};
 use std::convert;
 use std::cell::RefCell;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use hyper;
 use hyper::server::request::Request as HttpRequest;
 use hyper::uri::RequestUri::{AbsolutePath, AbsoluteUri, Authority, Star};
-use hyper::header::{Headers, ContentLength, ContentType, Cookie, Host};
+use hyper::header::{Headers, ContentLength, ContentType, Cookie, Host, ETag, EntityTag, LastModified};
 use hyper::mime::Mime;
 use hyper::method::Method;
 use hyper::http::h1::HttpReader;
 use hyper::net::NetworkStream;
 use hyper::buffer::BufReader;
-use url::Url;
+use url::{Url, Position};
 use url::form_urlencoded;
 use formdata::FilePart;
 use serde_json;
 use typemap::TypeMap;
 
 use app::Pencil;
-use datastructures::MultiDict;
+use datastructures::{MultiDict, Accept};
 use httputils::{get_name_by_http_code, get_content_type, get_host_value};
-use httputils::get_status_from_code;
+use httputils::{get_status_from_code, generate_etag, evaluate_preconditions};
 use routing::{Rule, MapAdapterMatched, MapAdapter};
-use types::ViewArgs;
-use http_errors::HTTPError;
+use types::{ViewArgs, UserError, PencilError, PenHTTPError, PenResponse};
+use http_errors::{HTTPError, PreconditionFailed};
 use formparser::FormDataParser;
 use lazycell::LazyCell;
+use tls::TlsStream;
+use proxy::ProxyFix;
+#[cfg(unix)]
+use unix::UnixSocketStream;
+
+
+/// The address of the remote end of a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RemoteAddr {
+    /// A TCP peer.
+    Tcp(SocketAddr),
+    /// A Unix domain socket peer, with the path of its socket if it's bound to one.
+    Unix(Option<PathBuf>),
+}
+
+impl RemoteAddr {
+    /// The socket address of a TCP peer.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            RemoteAddr::Tcp(addr) => Some(addr),
+            RemoteAddr::Unix(_) => None,
+        }
+    }
+
+    /// The IP address of a TCP peer.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.socket_addr().map(|addr| addr.ip())
+    }
+}
+
+impl convert::From<SocketAddr> for RemoteAddr {
+    fn from(addr: SocketAddr) -> RemoteAddr {
+        RemoteAddr::Tcp(addr)
+    }
+}
+
+impl fmt::Display for RemoteAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RemoteAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            RemoteAddr::Unix(Some(ref path)) => write!(f, "unix:{}", path.display()),
+            RemoteAddr::Unix(None) => f.write_str("unix:"),
+        }
+    }
+}
+
+/// Script roots start with a slash and have no trailing slash, the root is empty.
+fn normalize_script_root(prefix: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() || prefix.starts_with('/') {
+        prefix.to_string()
+    } else {
+        format!("/{}", prefix)
+    }
+}
+
+/// The remote address of requests that came in over a Unix domain socket.
+#[cfg(unix)]
+fn unix_remote_addr(http_request: &HttpRequest) -> Option<RemoteAddr> {
+    http_request.downcast_ref::<UnixSocketStream>().map(|stream| RemoteAddr::Unix(stream.peer_path()))
+}
+
+#[cfg(not(unix))]
+fn unix_remote_addr(_: &HttpRequest) -> Option<RemoteAddr> {
+    None
+}
 
 
 /// Request type.
 pub struct Request<'r, 'a, 'b: 'a> {
     pub app: &'r Pencil,
-    /// The IP address of the remote connection.
-    pub remote_addr: SocketAddr,
+    /// The address of the remote connection.
+    pub remote_addr: RemoteAddr,
     /// The request method.
     pub method: Method,
     /// The headers of the incoming request.
@@ -58,17 +127,33 @@ pub struct Request<'r, 'a, 'b: 'a> {
     pub extensions_data: TypeMap,
     /// The server host
     pub host: Host,
+    /// The path the application is mounted at, without a trailing slash.
+    /// Empty if the application is served from the root.
+    pub script_root: String,
+    /// The DER encoded certificate chain presented by the client, if the
+    /// request came in over TLS with client certificates enabled.
+    pub peer_certificates: Option<Vec<Vec<u8>>>,
     body: RefCell<HttpReader<&'a mut BufReader<&'b mut dyn NetworkStream>>>,
     args: LazyCell<MultiDict<String>>,
     form: LazyCell<MultiDict<String>>,
     files: LazyCell<MultiDict<FilePart>>,
-    cached_json: LazyCell<Option<serde_json::Value>>
+    cached_json: LazyCell<Option<serde_json::Value>>,
+    proxy_fixed: bool,
 }
 
 impl<'r, 'a, 'b: 'a> Request<'r, 'a, 'b> {
     /// Create a `Request`.
     pub fn new(app: &'r Pencil, http_request: HttpRequest<'a, 'b>) -> Result<Request<'r, 'a, 'b>, String> {
+        let (scheme, peer_certificates) = match http_request.ssl::<TlsStream>() {
+            Some(stream) => ("https", stream.peer_certificates()),
+            None => ("http", None),
+        };
+        let unix_remote_addr = unix_remote_addr(&http_request);
         let (remote_addr, method, headers, uri, _, body) = http_request.deconstruct();
+        let remote_addr = match unix_remote_addr {
+            Some(unix_remote_addr) => unix_remote_addr,
+            None => RemoteAddr::Tcp(remote_addr),
+        };
         let host = match headers.get::<hyper::header::Host>() {
             Some(host) => host.clone(),
             None => {
@@ -77,7 +162,7 @@ impl<'r, 'a, 'b: 'a> Request<'r, 'a, 'b> {
         };
         let url = match uri {
             AbsolutePath(ref path) => {
-                let url_string = format!("http://{}{}", get_host_value(&host), path);
+                let url_string = format!("{}://{}{}", scheme, get_host_value(&host), path);
                 match Url::parse(&url_string) {
                     Ok(url) => url,
                     Err(e) => return Err(format!("Couldn't parse requested URL: {}", e))
@@ -103,21 +188,121 @@ impl<'r, 'a, 'b: 'a> Request<'r, 'a, 'b> {
             extensions_data: TypeMap::new(),
             body: RefCell::new(body),
             host: host,
+            script_root: String::new(),
+            peer_certificates,
             args: LazyCell::new(),
             form: LazyCell::new(),
             files: LazyCell::new(),
             cached_json: LazyCell::new(),
+            proxy_fixed: false,
         })
     }
 
     /// Get the url adapter for this request.
     pub fn url_adapter(&self) -> MapAdapter {
-        self.app.url_map.bind(self.host(), self.path(), self.query_string(), self.method())
+        self.app.url_map.bind_to_request(self)
+    }
+
+    /// Rewrite the remote address, scheme, host and script root from the
+    /// headers set by trusted proxies.  This is done by the application
+    /// before routing if `Pencil::enable_proxy_fix` was called, or by the
+    /// `Dispatcher` if `Dispatcher::enable_proxy_fix` was.  Only the first
+    /// call has an effect.
+    pub fn apply_proxy_fix(&mut self, proxy_fix: &ProxyFix) {
+        if self.proxy_fixed || !proxy_fix.is_trusted(self.remote_addr.ip()) {
+            return;
+        }
+        self.proxy_fixed = true;
+        let values = proxy_fix.forwarded_values(&self.headers);
+        if let Some(remote_addr) = values.remote_addr {
+            self.remote_addr = RemoteAddr::Tcp(remote_addr);
+        }
+        if values.scheme.is_some() || values.host.is_some() || values.port.is_some() {
+            let scheme = values.scheme.unwrap_or_else(|| self.scheme());
+            let host = values.host.unwrap_or_else(|| self.host());
+            let url_string = format!("{}://{}{}", scheme, host, &self.url[Position::BeforePath..]);
+            match Url::parse(&url_string) {
+                Ok(mut url) => {
+                    if let Some(port) = values.port {
+                        let _ = url.set_port(Some(port));
+                    }
+                    if let Some(hostname) = url.host_str() {
+                        self.host = Host { hostname: hostname.to_owned(), port: url.port() };
+                    }
+                    self.url = url;
+                },
+                Err(e) => warn!("Ignoring forwarded URL {}: {}", url_string, e),
+            }
+        }
+        if let Some(prefix) = values.prefix {
+            // The proxy stripped the prefix, the URL the client requested has it.
+            let prefix = normalize_script_root(&prefix);
+            let path = prefix.clone() + self.url.path();
+            self.url.set_path(&path);
+            self.script_root = prefix + &self.script_root;
+        }
+    }
+
+    /// Mount the request at `prefix`: the prefix moves from the start of the
+    /// path to the end of the script root.  Mounting a request for
+    /// `/blog/2016/` at `/blog` gives a script root of `/blog` and a path
+    /// of `/2016/`.  Returns `false` and leaves the request unchanged if
+    /// the path is not below the prefix.
+    pub fn mount(&mut self, prefix: &str) -> bool {
+        let prefix = normalize_script_root(prefix);
+        if prefix.is_empty() {
+            return true;
+        }
+        let path = self.path();
+        if path == prefix || path.starts_with(&(prefix.clone() + "/")) {
+            self.script_root.push_str(&prefix);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Build the URL of an endpoint, relative to the host and including the
+    /// script root.  Values that are not variables of the URL rule are added
+    /// as query arguments.  An endpoint starting with a dot is relative to
+    /// the current module:
+    ///
+    /// ```rust,ignore
+    /// let mut values = ViewArgs::new();
+    /// values.insert("filename".to_string(), "style.css".to_string());
+    /// let url = request.url_for("static", &values)?;
+    /// ```
+    pub fn url_for(&self, endpoint: &str, values: &ViewArgs) -> Result<String, UserError> {
+        let endpoint = self.resolve_endpoint(endpoint);
+        match self.url_adapter().build(&endpoint, values) {
+            Some(url) => Ok(url),
+            None => Err(UserError::new(format!("Could not build url for endpoint '{}'", endpoint))),
+        }
+    }
+
+    /// Build the absolute URL of an endpoint, including scheme and host.
+    pub fn external_url_for(&self, endpoint: &str, values: &ViewArgs) -> Result<String, UserError> {
+        let endpoint = self.resolve_endpoint(endpoint);
+        match self.url_adapter().build_external(&endpoint, values) {
+            Some(url) => Ok(url),
+            None => Err(UserError::new(format!("Could not build url for endpoint '{}'", endpoint))),
+        }
+    }
+
+    /// Resolve endpoints relative to the current module.
+    fn resolve_endpoint(&self, endpoint: &str) -> String {
+        if let Some(relative) = endpoint.strip_prefix('.') {
+            if let Some(module_name) = self.module_name() {
+                return module_name + endpoint;
+            }
+            return relative.to_string();
+        }
+        endpoint.to_string()
     }
 
     /// Match the request, set the `url_rule` and `view_args` field.
     pub fn match_request(&mut self) {
-        let url_adapter = self.app.url_map.bind(self.host(), self.path(), self.query_string(), self.method());
+        let url_adapter = self.app.url_map.bind_to_request(self);
         match url_adapter.matched() {
             MapAdapterMatched::MatchedRule((rule, view_args)) => {
                 self.url_rule = Some(rule);
@@ -227,9 +412,86 @@ impl<'r, 'a, 'b: 'a> Request<'r, 'a, 'b> {
         &self.headers
     }
 
-    /// Requested path.
+    /// The value of a header as sent by the client, several lines joined
+    /// by commas.
+    fn header_value(&self, name: &str) -> Option<String> {
+        self.headers.get_raw(name).map(|lines| {
+            let lines: Vec<String> = lines.iter().map(|line| String::from_utf8_lossy(line).into_owned()).collect();
+            lines.join(", ")
+        })
+    }
+
+    /// The mimetypes the client accepts from the `Accept` header.
+    pub fn accept_mimetypes(&self) -> Accept {
+        Accept::mimetypes(self.header_value("Accept").as_ref().map(|h| &h[..]))
+    }
+
+    /// The languages the client accepts from the `Accept-Language` header.
+    pub fn accept_languages(&self) -> Accept {
+        Accept::languages(self.header_value("Accept-Language").as_ref().map(|h| &h[..]))
+    }
+
+    /// The encodings the client accepts from the `Accept-Encoding` header.
+    pub fn accept_encodings(&self) -> Accept {
+        Accept::encodings(self.header_value("Accept-Encoding").as_ref().map(|h| &h[..]))
+    }
+
+    /// The charsets the client accepts from the `Accept-Charset` header.
+    pub fn accept_charsets(&self) -> Accept {
+        Accept::charsets(self.header_value("Accept-Charset").as_ref().map(|h| &h[..]))
+    }
+
+    /// The id of the last event an event stream client received, sent in the
+    /// `Last-Event-ID` header when it reconnects.
+    pub fn last_event_id(&self) -> Option<String> {
+        self.header_value("Last-Event-ID")
+    }
+
+    /// Evaluate the conditional headers of the request for the current
+    /// representation of the resource before handling it, e.g. before
+    /// updating the resource for a `PUT` request.  Fails with
+    /// `PreconditionFailed` if `If-Match` or `If-Unmodified-Since` don't hold
+    /// and with a `304 Not Modified` response for a cached `GET` request.
+    ///
+    /// ```rust,no_run
+    /// use sharp_pencil::{Request, PencilResult, Response};
+    ///
+    ///
+    /// fn update(request: &mut Request) -> PencilResult {
+    ///     let current_etag = "5d41402abc4b2a76";
+    ///     request.check_preconditions(Some(current_etag), None)?;
+    ///     Ok(Response::from("Updated"))
+    /// }
+    /// ```
+    pub fn check_preconditions(&self, etag: Option<&str>, last_modified: Option<SystemTime>) -> Result<(), PencilError> {
+        let etag = etag.map(|etag| EntityTag::new(false, etag.to_string()));
+        let last_modified = last_modified.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                                         .map(|duration| duration.as_secs() as i64);
+        match evaluate_preconditions(&self.headers, &self.method, etag.as_ref(), last_modified) {
+            Some(304) => {
+                let mut response = Response::new_empty();
+                response.status_code = 304;
+                if let Some(etag) = etag {
+                    response.headers.set(ETag(etag));
+                }
+                Err(PenResponse(Box::new(response)))
+            },
+            Some(_) => Err(PenHTTPError(PreconditionFailed)),
+            None => Ok(()),
+        }
+    }
+
+    /// Requested path, relative to the script root.
     pub fn path(&self) -> String {
-        self.url.path().to_owned()
+        let path = self.url.path();
+        if !self.script_root.is_empty() && path.starts_with(&self.script_root) {
+            let path = &path[self.script_root.len()..];
+            if path.is_empty() {
+                return String::from("/");
+            }
+            return path.to_owned();
+        }
+        path.to_owned()
     }
 
     /// Requested path including the query string.
@@ -264,13 +526,19 @@ impl<'r, 'a, 'b: 'a> Request<'r, 'a, 'b> {
     }
 
     /// The remote address of the client.
-    pub fn remote_addr(&self) -> SocketAddr {
-        self.remote_addr
+    pub fn remote_addr(&self) -> RemoteAddr {
+        self.remote_addr.clone()
     }
 
     /// URL scheme (http or https)
     pub fn scheme(&self) -> String {
-        String::from("http")
+        self.url.scheme().to_owned()
+    }
+
+    /// The certificate chain the client authenticated with, leaf certificate
+    /// first.  Only available over TLS with client certificates enabled.
+    pub fn peer_certificates(&self) -> Option<&[Vec<u8>]> {
+        self.peer_certificates.as_ref().map(|certs| &certs[..])
     }
 
     /// Just the host with scheme.
@@ -278,14 +546,19 @@ impl<'r, 'a, 'b: 'a> Request<'r, 'a, 'b> {
         self.scheme() + "://" + &self.host() + "/"
     }
 
+    /// The full URL root (with hostname and script root).
+    pub fn url_root(&self) -> String {
+        self.scheme() + "://" + &self.host() + &self.script_root + "/"
+    }
+
     /// The current url.
     pub fn url(&self) -> String {
-        self.host_url() + &self.full_path().trim_start_matches('/')
+        self.url_root() + self.full_path().trim_start_matches('/')
     }
 
     /// The current url without the query string.
     pub fn base_url(&self) -> String {
-        self.host_url() + &self.path().trim_start_matches('/')
+        self.url_root() + self.path().trim_start_matches('/')
     }
 
     /// Whether the request is secure (https).
@@ -331,30 +604,51 @@ impl<'a> Write for ResponseBody<'a> {
 /// A trait which writes the body of one response.
 pub trait BodyWrite: Send {
     fn write_body(&mut self, body: &mut ResponseBody) -> io::Result<()>;
+
+    /// The whole body if it is in memory, used to generate ETags.
+    fn bytes(&self) -> Option<&[u8]> {
+        None
+    }
 }
 
 impl BodyWrite for Vec<u8> {
     fn write_body(&mut self, body: &mut ResponseBody) -> io::Result<()> {
         body.write_all(self)
     }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(self)
+    }
 }
 
 impl<'a> BodyWrite for &'a [u8] {
     fn write_body(&mut self, body: &mut ResponseBody) -> io::Result<()> {
         body.write_all(self)
     }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(self)
+    }
 }
 
 impl BodyWrite for String {
     fn write_body(&mut self, body: &mut ResponseBody) -> io::Result<()> {
         self.as_bytes().write_body(body)
     }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(self.as_bytes())
+    }
 }
 
 impl<'a> BodyWrite for &'a str {
     fn write_body(&mut self, body: &mut ResponseBody) -> io::Result<()> {
         self.as_bytes().write_body(body)
     }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(self.as_bytes())
+    }
 }
 
 impl BodyWrite for File {
@@ -363,12 +657,111 @@ impl BodyWrite for File {
     }
 }
 
-impl BodyWrite for Take<File> {
+impl<R: Read + Send> BodyWrite for Take<R> {
     fn write_body(&mut self, body: &mut ResponseBody) -> io::Result<()> {
         io::copy(self, body).map(|_| ())
     }
 }
 
+/// A body streamed from an iterator of chunks, see `Response::stream`.
+struct StreamBody<I> {
+    chunks: I,
+}
+
+impl<I, B> BodyWrite for StreamBody<I>
+    where I: Iterator<Item = io::Result<B>> + Send, B: AsRef<[u8]>
+{
+    fn write_body(&mut self, body: &mut ResponseBody) -> io::Result<()> {
+        for chunk in &mut self.chunks {
+            write_chunk(body, chunk?.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+/// A body streamed from another thread, see `Response::channel`.
+struct ChannelBody {
+    receiver: Receiver<Vec<u8>>,
+    /// How long the body may be idle and the chunk sent then.
+    keep_alive: Option<(Duration, Vec<u8>)>,
+}
+
+impl BodyWrite for ChannelBody {
+    fn write_body(&mut self, body: &mut ResponseBody) -> io::Result<()> {
+        // Send the headers right away, so the client knows the body started.
+        body.flush()?;
+        loop {
+            let chunk = match self.keep_alive {
+                Some((interval, ref keep_alive)) => match self.receiver.recv_timeout(interval) {
+                    Ok(chunk) => chunk,
+                    Err(RecvTimeoutError::Timeout) => keep_alive.clone(),
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                },
+                None => match self.receiver.recv() {
+                    Ok(chunk) => chunk,
+                    Err(_) => return Ok(()),
+                },
+            };
+            write_chunk(body, &chunk)?;
+        }
+    }
+}
+
+/// Write one chunk and send it to the client right away.  Empty chunks are
+/// skipped, they would end a chunked body.
+fn write_chunk(body: &mut ResponseBody, chunk: &[u8]) -> io::Result<()> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    body.write_all(chunk)?;
+    body.flush()
+}
+
+/// How many chunks a `BodySender` can send ahead of the client.
+const CHANNEL_CAPACITY: usize = 16;
+
+/// The sending half of a response body streamed from another thread, see
+/// `Response::channel`.  The body ends when all senders are dropped.
+#[derive(Clone)]
+pub struct BodySender {
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl BodySender {
+    /// Send a chunk of the body to the client.  This blocks while the client
+    /// is behind, and fails once the client disconnected or the response
+    /// was dropped, so the producer can stop.
+    pub fn send<B: Into<Vec<u8>>>(&self, chunk: B) -> io::Result<()> {
+        self.sender.send(chunk.into())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the response body is closed"))
+    }
+
+    /// Send a chunk of the body without waiting for the client, fails with
+    /// `WouldBlock` while the client is behind.
+    pub fn try_send<B: Into<Vec<u8>>>(&self, chunk: B) -> io::Result<()> {
+        self.sender.try_send(chunk.into()).map_err(|e| match e {
+            TrySendError::Full(_) => io::Error::new(io::ErrorKind::WouldBlock, "the client is behind"),
+            TrySendError::Disconnected(_) => io::Error::new(io::ErrorKind::BrokenPipe, "the response body is closed"),
+        })
+    }
+}
+
+impl Write for BodySender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf).map(|_| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BodySender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<BodySender>")
+    }
+}
+
 
 
 /// Response type.  It is just one container with a couple of parameters
@@ -404,6 +797,73 @@ impl Response {
         response
     }
 
+    /// Create a response streaming the chunks of an iterator, like the rows
+    /// of a large export.  Each chunk is sent to the client as soon as it is
+    /// produced, with chunked transfer encoding unless a content length is
+    /// set.  The iterator isn't advanced anymore once the client
+    /// disconnected, and an error from it aborts the response:
+    ///
+    /// ```rust,no_run
+    /// use sharp_pencil::{Request, PencilResult, Response};
+    ///
+    ///
+    /// fn export(_: &mut Request) -> PencilResult {
+    ///     let rows = (0..1_000_000).map(|i| Ok(format!("{},{}\n", i, i * i)));
+    ///     let mut response = Response::stream(rows);
+    ///     response.set_content_type("text/csv");
+    ///     Ok(response)
+    /// }
+    /// ```
+    pub fn stream<I, B>(chunks: I) -> Response
+        where I: IntoIterator<Item = io::Result<B>>, I::IntoIter: Send + 'static, B: AsRef<[u8]> + 'static
+    {
+        Response::new(StreamBody { chunks: chunks.into_iter() })
+    }
+
+    /// Create a response with a body another thread writes into through the
+    /// returned sender.  Chunks are sent to the client as they arrive, with
+    /// chunked transfer encoding unless a content length is set, and the
+    /// body ends when all senders are dropped.  Sending fails once the
+    /// client disconnected:
+    ///
+    /// ```rust,no_run
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// use sharp_pencil::{Request, PencilResult, Response};
+    ///
+    ///
+    /// fn progress(_: &mut Request) -> PencilResult {
+    ///     let (mut response, sender) = Response::channel();
+    ///     thread::spawn(move || {
+    ///         for percent in 0..101 {
+    ///             if sender.send(format!("{}%\n", percent)).is_err() {
+    ///                 break;
+    ///             }
+    ///             thread::sleep(Duration::from_millis(100));
+    ///         }
+    ///     });
+    ///     response.set_content_type("text/plain");
+    ///     Ok(response)
+    /// }
+    /// ```
+    pub fn channel() -> (Response, BodySender) {
+        Response::channel_with_keep_alive(None, b"")
+    }
+
+    /// Create a response like `channel` that writes the `keep_alive` chunk
+    /// whenever no chunk arrived for `interval`, so proxies don't close an
+    /// idle connection.  Writing the chunk also notices clients that went
+    /// away.  `None` sends none.
+    pub fn channel_with_keep_alive(interval: Option<Duration>, keep_alive: &[u8]) -> (Response, BodySender) {
+        let (sender, receiver) = sync_channel(CHANNEL_CAPACITY);
+        let body = ChannelBody {
+            receiver,
+            keep_alive: interval.map(|interval| (interval, keep_alive.to_vec())),
+        };
+        (Response::new(body), BodySender { sender })
+    }
+
     /// Create an empty response without body.
     pub fn new_empty() -> Response {
         Response {
@@ -451,6 +911,96 @@ impl Response {
         self.headers.set(content_length);
     }
 
+    /// Adds a header name to the `Vary` header, telling caches that the
+    /// response depends on that request header.
+    pub fn add_vary(&mut self, header: &str) {
+        let mut names: Vec<String> = match self.headers.get_raw("Vary") {
+            Some(lines) => lines.iter()
+                .flat_map(|line| String::from_utf8_lossy(line).split(',')
+                          .map(|name| name.trim().to_string())
+                          .collect::<Vec<String>>())
+                .filter(|name| !name.is_empty())
+                .collect(),
+            None => Vec::new(),
+        };
+        if names.iter().any(|name| name == "*" || name.eq_ignore_ascii_case(header)) {
+            return;
+        }
+        names.push(header.to_string());
+        self.headers.set_raw("Vary", vec![names.join(", ").into_bytes()]);
+    }
+
+    /// Returns the ETag of the response if available.
+    pub fn get_etag(&self) -> Option<&EntityTag> {
+        self.headers.get::<ETag>().map(|etag| &etag.0)
+    }
+
+    /// Set the ETag of the response.  A weak ETag only promises that the
+    /// representations are equivalent, not byte for byte identical.
+    pub fn set_etag(&mut self, etag: &str, weak: bool) {
+        self.headers.set(ETag(EntityTag::new(weak, etag.to_string())));
+    }
+
+    /// Set a strong ETag hashed from the body unless the response already
+    /// has one.  This only works for bodies in memory, like the ones
+    /// converted from strings and byte vectors.  Returns whether the
+    /// response has an ETag now.
+    pub fn add_etag(&mut self) -> bool {
+        if self.get_etag().is_some() {
+            return true;
+        }
+        let etag = match self.body.as_ref().and_then(|body| body.bytes()) {
+            Some(bytes) => generate_etag(bytes),
+            None => return false,
+        };
+        self.set_etag(&etag, false);
+        true
+    }
+
+    /// Make the response conditional to the request.  A successful
+    /// response gets an ETag from `add_etag` and is evaluated against the
+    /// `If-Match`, `If-None-Match`, `If-Modified-Since` and
+    /// `If-Unmodified-Since` headers of the request, which turns it into a
+    /// `304 Not Modified` without a body or a `412 Precondition Failed`.
+    ///
+    /// ```rust,no_run
+    /// use sharp_pencil::{Request, PencilResult, Response};
+    ///
+    ///
+    /// fn report(request: &mut Request) -> PencilResult {
+    ///     let mut response = Response::from("An expensive report");
+    ///     response.make_conditional(request);
+    ///     Ok(response)
+    /// }
+    /// ```
+    ///
+    /// Unsafe methods like `PUT` should check the preconditions with
+    /// `Request::check_preconditions` before they change anything.
+    pub fn make_conditional(&mut self, request: &Request) {
+        if self.status_code < 200 || self.status_code >= 300 {
+            return;
+        }
+        self.add_etag();
+        let last_modified = self.headers.get::<LastModified>().map(|date| (date.0).0.to_timespec().sec);
+        match evaluate_preconditions(request.headers(), &request.method, self.get_etag(), last_modified) {
+            Some(304) => {
+                self.status_code = 304;
+                self.body = None;
+                self.headers.remove::<ContentType>();
+                self.headers.remove::<ContentLength>();
+            },
+            Some(status_code) => {
+                let error = PreconditionFailed;
+                let body = error.get_body();
+                self.status_code = status_code;
+                self.set_content_type("text/html");
+                self.set_content_length(body.len());
+                self.body = Some(Box::new(body));
+            },
+            None => (),
+        }
+    }
+
     /// Sets cookie.
     pub fn set_cookie(&mut self, cookie: hyper::header::SetCookie) {
         self.headers.set(cookie);
@@ -0,0 +1,386 @@
+// Test the request handling of applications.
+
+extern crate sharp_pencil;
+extern crate flate2;
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+use std::num::ParseIntError;
+use std::sync::{Arc, Mutex};
+
+use sharp_pencil::{Compress, Module, Pencil, PencilError, PencilResult, PenHTTPError, PenUserError, Request, Response, UserError, ViewArgs};
+use sharp_pencil::http_errors::NotFound;
+use sharp_pencil::method::Get;
+use sharp_pencil::testing::read_body;
+
+
+fn index(_: &mut Request) -> PencilResult {
+    Ok(Response::from("index"))
+}
+
+fn missing(_: &mut Request) -> PencilResult {
+    Err(PenHTTPError(NotFound))
+}
+
+fn failing(_: &mut Request) -> PencilResult {
+    let number: u32 = "forty-two".parse()?;
+    Ok(Response::from(number.to_string()))
+}
+
+fn user_error(_: &mut Request) -> PencilResult {
+    Err(PenUserError(UserError::new("quota exceeded")))
+}
+
+fn unreadable(_: &mut Request) -> PencilResult {
+    Err(io::Error::new(io::ErrorKind::PermissionDenied, "unreadable").into())
+}
+
+fn status(status_code: u16, body: String) -> PencilResult {
+    let mut response = Response::from(body);
+    response.status_code = status_code;
+    Ok(response)
+}
+
+fn report(_: &mut Request) -> PencilResult {
+    let mut response = Response::from("<p>report</p>\n".repeat(100));
+    response.set_etag("report", false);
+    Ok(response)
+}
+
+fn update(request: &mut Request) -> PencilResult {
+    request.check_preconditions(Some("v2"), None)?;
+    Ok(Response::from("updated"))
+}
+
+fn broken(_: &mut Request) -> PencilResult {
+    panic!("the view is broken")
+}
+
+type Trace = Arc<Mutex<Vec<String>>>;
+
+/// A middleware recording when the request passes through it.
+fn tracing(trace: &Trace, name: &'static str)
+           -> impl Fn(&mut Request, &dyn Fn(&mut Request) -> Response) -> Response + Clone {
+    let trace = trace.clone();
+    move |request: &mut Request, next: &dyn Fn(&mut Request) -> Response| {
+        trace.lock().unwrap().push(format!("{} in", name));
+        let response = next(request);
+        trace.lock().unwrap().push(format!("{} out {}", name, response.status_code));
+        response
+    }
+}
+
+fn header(response: &Response, name: &str) -> Option<String> {
+    response.headers.get_raw(name).map(|value| String::from_utf8(value[0].clone()).unwrap())
+}
+
+fn gunzip(data: &[u8]) -> Vec<u8> {
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(data).read_to_end(&mut decompressed).unwrap();
+    decompressed
+}
+
+fn take(trace: &Trace) -> Vec<String> {
+    trace.lock().unwrap().drain(..).collect()
+}
+
+
+#[test]
+fn test_middleware_order() {
+    let trace = Trace::default();
+    let mut app = Pencil::new("/test");
+    app.middleware(tracing(&trace, "app1"));
+    app.middleware(tracing(&trace, "app2"));
+    let mut module = Module::new("admin", "/test");
+    module.middleware(tracing(&trace, "module"));
+    module.route("/admin", &[Get], "index", index);
+    app.register_module(module);
+    app.get("/", "index", index);
+
+    let client = app.test_client();
+    assert!(client.get("/admin").status_code == 200);
+    assert!(take(&trace) == ["app1 in", "app2 in", "module in", "module out 200", "app2 out 200", "app1 out 200"]);
+    assert!(client.get("/").status_code == 200);
+    assert!(take(&trace) == ["app1 in", "app2 in", "app2 out 200", "app1 out 200"]);
+}
+
+#[test]
+fn test_middleware_short_circuit_and_errors() {
+    let trace = Trace::default();
+    let mut app = Pencil::new("/test");
+    app.middleware(tracing(&trace, "outer"));
+    app.middleware(|request: &mut Request, next: &dyn Fn(&mut Request) -> Response| {
+        if request.path() == "/blocked" {
+            let mut response = Response::from("blocked");
+            response.status_code = 403;
+            return response;
+        }
+        next(request)
+    });
+    app.middleware(tracing(&trace, "inner"));
+    app.get("/blocked", "blocked", index);
+    app.get("/missing", "missing", missing);
+
+    let client = app.test_client();
+    let response = client.get("/blocked");
+    assert!(response.status_code == 403);
+    assert!(read_body(response) == b"blocked");
+    assert!(take(&trace) == ["outer in", "outer out 403"]);
+    assert!(client.get("/missing").status_code == 404);
+    assert!(take(&trace) == ["outer in", "inner in", "inner out 404", "outer out 404"]);
+    assert!(client.get("/nowhere").status_code == 404);
+    assert!(take(&trace) == ["outer in", "inner in", "inner out 404", "outer out 404"]);
+}
+
+#[test]
+fn test_panic_becomes_internal_server_error() {
+    let trace = Trace::default();
+    let mut app = Pencil::new("/test");
+    app.get("/broken", "broken", broken);
+    let teardown_trace = trace.clone();
+    app.teardown(move |_: &Request, e: Option<&PencilError>| {
+        if let Some(PenHTTPError(error)) = e {
+            teardown_trace.lock().unwrap().push(error.get_description().to_string());
+        }
+    });
+    let response = app.test_client().get("/broken");
+    assert!(response.status_code == 500);
+    assert!(!String::from_utf8(read_body(response)).unwrap().contains("the view is broken"));
+
+    app.http_error_handler(500, |error, _| Ok(Response::from(error.get_description())));
+    let response = app.test_client().get("/broken");
+    assert!(response.status_code == 200);
+    let description = String::from_utf8(read_body(response)).unwrap();
+    assert!(description.starts_with("panicked at tests/test_app.rs:"));
+    assert!(description.ends_with(": the view is broken"));
+    let teardowns = take(&trace);
+    assert!(teardowns.len() == 2);
+    assert!(teardowns.iter().all(|teardown| *teardown == description));
+}
+
+#[test]
+fn test_debug_error_page() {
+    let mut app = Pencil::new("/test");
+    app.set_debug(true);
+    app.get("/error", "error", failing);
+    let response = app.test_client().open("GET", "/error", &[("Authorization", "Bearer hunter2"), ("X-Trace", "abc")]);
+    assert!(response.status_code == 500);
+    let page = String::from_utf8(read_body(response)).unwrap();
+    assert!(page.contains("invalid digit found in string"));
+    assert!(page.contains("<h2>Backtrace</h2>"));
+    assert!(page.contains("abc"));
+    assert!(!page.contains("hunter2"));
+}
+
+#[test]
+fn test_typed_error_handlers() {
+    let mut app = Pencil::new("/test");
+    app.register_error_handler(|e: &ParseIntError, _: &Request| status(400, format!("app: {}", e)));
+    let mut module = Module::new("admin", "/test");
+    module.errorhandler(|e: &ParseIntError, _: &Request| status(422, format!("module: {}", e)));
+    module.route("/admin/failing", &[Get], "failing", failing);
+    module.route("/admin/unreadable", &[Get], "unreadable", unreadable);
+    app.register_module(module);
+    app.get("/failing", "failing", failing);
+
+    let client = app.test_client();
+    let response = client.get("/failing");
+    assert!(response.status_code == 400);
+    assert!(read_body(response) == b"app: invalid digit found in string");
+    let response = client.get("/admin/failing");
+    assert!(response.status_code == 422);
+    assert!(read_body(response) == b"module: invalid digit found in string");
+    assert!(client.get("/admin/unreadable").status_code == 500);
+
+    app.register_error_handler(|e: &io::Error, _: &Request| status(403, e.to_string()));
+    let response = app.test_client().get("/admin/unreadable");
+    assert!(response.status_code == 403);
+    assert!(read_body(response) == b"unreadable");
+
+    app.register_error_handler(|e: &ParseIntError, _: &Request| status(418, format!("again: {}", e)));
+    let response = app.test_client().get("/failing");
+    assert!(response.status_code == 418);
+    assert!(read_body(response) == b"again: invalid digit found in string");
+}
+
+#[test]
+fn test_request_aware_and_old_style_handlers() {
+    let trace = Trace::default();
+    let mut app = Pencil::new("/test");
+    app.get("/missing", "missing", missing);
+    app.get("/quota", "quota", user_error);
+    app.http_error_handler(404, |e, request: &Request| status(e.code(), format!("{} not found", request.path())));
+    app.user_error_handler("quota exceeded", |e, request: &Request| status(429, format!("{}: {}", request.path(), e.desc)));
+    let old_trace = trace.clone();
+    app.teardown_request(move |e: Option<&PencilError>| {
+        old_trace.lock().unwrap().push(format!("old {}", e.is_some()));
+    });
+    let new_trace = trace.clone();
+    app.teardown(move |request: &Request, e: Option<&PencilError>| {
+        new_trace.lock().unwrap().push(format!("new {} {}", request.path(), e.is_some()));
+    });
+
+    let client = app.test_client();
+    let response = client.get("/missing");
+    assert!(response.status_code == 404);
+    assert!(read_body(response) == b"/missing not found");
+    let response = client.get("/quota");
+    assert!(response.status_code == 429);
+    assert!(read_body(response) == b"/quota: quota exceeded");
+    // Teardown functions run in reverse order of registration.
+    assert!(take(&trace) == ["new /missing false", "old false", "new /quota false", "old false"]);
+
+    app.httperrorhandler(404, |e| status(e.code(), String::from("old not found")));
+    app.register_user_error_handler("quota exceeded", |e| status(429, format!("old: {}", e.desc)));
+    let client = app.test_client();
+    assert!(read_body(client.get("/missing")) == b"old not found");
+    assert!(read_body(client.get("/quota")) == b"old: quota exceeded");
+}
+
+#[test]
+fn test_compress_middleware() {
+    let mut app = Pencil::new("/test");
+    app.middleware(Compress::new());
+    app.get("/report", "report", report);
+    app.get("/small", "small", index);
+    app.get("/encoded", "encoded", |request: &mut Request| {
+        let mut response = report(request)?;
+        response.headers.set_raw("Content-Encoding", vec![b"x-custom".to_vec()]);
+        Ok(response)
+    });
+    app.get("/no-transform", "no_transform", |request: &mut Request| {
+        let mut response = report(request)?;
+        response.headers.set_raw("Cache-Control", vec![b"no-transform".to_vec()]);
+        Ok(response)
+    });
+    let client = app.test_client();
+    let gzip = [("Accept-Encoding", "gzip")];
+
+    let response = client.open("GET", "/report", &gzip);
+    assert!(header(&response, "Content-Encoding") == Some(String::from("gzip")));
+    assert!(header(&response, "ETag") == Some(String::from("\"report-gzip\"")));
+    assert!(header(&response, "Vary") == Some(String::from("Accept-Encoding")));
+    let content_length = response.content_length().unwrap();
+    let body = read_body(response);
+    assert!(body.len() == content_length && content_length < 1400);
+    assert!(gunzip(&body) == "<p>report</p>\n".repeat(100).into_bytes());
+
+    let response = client.get("/report");
+    assert!(header(&response, "Content-Encoding").is_none());
+    assert!(header(&response, "ETag") == Some(String::from("\"report\"")));
+    assert!(header(&response, "Vary") == Some(String::from("Accept-Encoding")));
+    assert!(response.content_length() == Some(1400));
+
+    let response = client.open("GET", "/small", &gzip);
+    assert!(header(&response, "Content-Encoding").is_none());
+    assert!(read_body(response) == b"index");
+    let response = client.open("GET", "/encoded", &gzip);
+    assert!(header(&response, "Content-Encoding") == Some(String::from("x-custom")));
+    assert!(header(&response, "ETag") == Some(String::from("\"report\"")));
+    assert!(read_body(response).len() == 1400);
+    let response = client.open("GET", "/no-transform", &gzip);
+    assert!(header(&response, "Content-Encoding").is_none());
+    assert!(header(&response, "Vary").is_none());
+    assert!(read_body(response).len() == 1400);
+}
+
+#[test]
+fn test_compress_conditional_static_file() {
+    let root = env::temp_dir().join(format!("pencil-compress-{}", process::id()));
+    fs::create_dir_all(root.join("static")).unwrap();
+    fs::write(root.join("static/app.css"), "body { color: black; }\n".repeat(50)).unwrap();
+    let mut app = Pencil::new(root.to_str().unwrap());
+    app.enable_static_file_handling();
+    app.middleware(Compress::new());
+    let client = app.test_client();
+
+    let response = client.open("GET", "/static/app.css", &[("Accept-Encoding", "gzip")]);
+    assert!(response.status_code == 200);
+    let etag = header(&response, "ETag").unwrap();
+    assert!(etag.ends_with("-gzip\""));
+    let response = client.open("GET", "/static/app.css", &[("Accept-Encoding", "gzip"), ("If-None-Match", &etag)]);
+    assert!(response.status_code == 304);
+    assert!(header(&response, "ETag") == Some(etag.clone()));
+    assert!(header(&response, "Vary") == Some(String::from("Accept-Encoding")));
+    // The compressed ETag doesn't match the uncompressed copy.
+    let response = client.open("GET", "/static/app.css", &[("If-None-Match", &etag)]);
+    assert!(response.status_code == 200);
+    assert!(header(&response, "ETag") == Some(etag.replace("-gzip", "")));
+    let response = client.open("GET", "/static/app.css", &[("If-None-Match", &etag.replace("-gzip", ""))]);
+    assert!(response.status_code == 304);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_application_root() {
+    let mut app = Pencil::new("/test");
+    app.set_application_root("/shop/");
+    app.get("/items/<id:int>", "item", |request: &mut Request| {
+        let mut values = ViewArgs::new();
+        values.insert(String::from("id"), String::from("2"));
+        values.insert(String::from("sort"), String::from("new"));
+        let next = request.url_for("item", &values)?;
+        Ok(Response::from(format!("{} {} {} {}", request.path(), request.script_root, next, request.url())))
+    });
+    app.get("/docs/", "docs", index);
+    let client = app.test_client();
+
+    let response = client.get("/shop/items/1?page=3");
+    assert!(response.status_code == 200);
+    assert!(read_body(response) ==
+            b"/items/1 /shop /shop/items/2?sort=new http://localhost/shop/items/1?page=3".to_vec());
+    let response = client.get("/shop/docs");
+    assert!(response.status_code == 301);
+    assert!(response.headers.get_raw("Location").unwrap()[0] == b"http://localhost/shop/docs/".to_vec());
+    assert!(client.get("/shop/docs/").status_code == 200);
+    assert!(client.get("/items/1").status_code == 404);
+    assert!(client.get("/shopping/items/1").status_code == 404);
+}
+
+
+#[test]
+fn test_use_etags() {
+    let mut app = Pencil::new("/test");
+    app.config.set("USE_ETAGS", serde_json::Value::Bool(true));
+    app.get("/", "index", index);
+    app.get("/missing", "missing", missing);
+    app.put("/document", "update", update);
+    let client = app.test_client();
+    let etag = |response: &Response| response.headers.get_raw("ETag").map(|values| String::from_utf8(values[0].clone()).unwrap());
+
+    let response = client.get("/");
+    assert!(response.status_code == 200);
+    let index_etag = etag(&response).unwrap();
+    assert!(index_etag.starts_with('"'));
+    assert!(read_body(response) == b"index");
+
+    let response = client.open("GET", "/", &[("If-None-Match", &index_etag)]);
+    assert!(response.status_code == 304);
+    assert!(etag(&response) == Some(index_etag.clone()));
+    assert!(response.body.is_none());
+    let response = client.open("HEAD", "/", &[("If-None-Match", &index_etag)]);
+    assert!(response.status_code == 304);
+    let response = client.open("GET", "/", &[("If-None-Match", "\"stale\"")]);
+    assert!(response.status_code == 200);
+    assert!(read_body(response) == b"index");
+
+    // Unsafe methods check the preconditions themselves.
+    let response = client.open("PUT", "/document", &[("If-Match", "\"v1\"")]);
+    assert!(response.status_code == 412);
+    let response = client.open("PUT", "/document", &[("If-Match", "\"v2\"")]);
+    assert!(response.status_code == 200);
+    assert!(read_body(response) == b"updated");
+
+    // Error responses are left alone.
+    let response = client.open("GET", "/missing", &[("If-None-Match", "*")]);
+    assert!(response.status_code == 404);
+    assert!(etag(&response).is_none());
+    assert!(!read_body(response).is_empty());
+
+    app.config.set("USE_ETAGS", serde_json::Value::Bool(false));
+    let response = app.test_client().get("/");
+    assert!(etag(&response).is_none());
+}
@@ -2,7 +2,7 @@
 
 extern crate sharp_pencil;
 
-use sharp_pencil::datastructures::MultiDict;
+use sharp_pencil::datastructures::{MultiDict, Accept};
 
 
 #[test]
@@ -29,3 +29,41 @@ fn test_multi_dict_basic_interface() {
     }
     assert!(all_values.len() == 2);
 }
+
+
+#[test]
+fn test_accept_mimetypes() {
+    let accept = Accept::mimetypes(Some("text/*;q=0.5, application/json, */*;q=0.1"));
+    assert!(accept.provided());
+    assert!(accept.best() == Some("application/json"));
+    assert!(accept.quality("application/json") == 1.0);
+    assert!(accept.quality("text/html") == 0.5);
+    assert!(accept.quality("image/png") == 0.1);
+    assert!(accept.best_match(&["text/html", "application/json"]) == Some("application/json"));
+    assert!(accept.best_match(&["text/html", "text/plain"]) == Some("text/html"));
+
+    let accept = Accept::mimetypes(Some("text/html, application/json;q=0"));
+    assert!(accept.best_match(&["application/json"]).is_none());
+
+    let accept = Accept::mimetypes(None);
+    assert!(!accept.provided());
+    assert!(accept.best_match(&["text/html", "application/json"]) == Some("text/html"));
+}
+
+
+#[test]
+fn test_accept_languages_and_encodings() {
+    let accept = Accept::languages(Some("de-CH, en;q=0.8"));
+    assert!(accept.best_match(&["en-US", "fr"]) == Some("en-US"));
+    assert!(accept.best_match(&["de", "en"]) == Some("de"));
+    assert!(accept.quality("fr") == 0.0);
+
+    let accept = Accept::encodings(Some("gzip, br;q=0.9"));
+    assert!(accept.best_match(&["br", "gzip"]) == Some("gzip"));
+    assert!(accept.quality("identity") == 1.0);
+    let accept = Accept::encodings(Some("gzip, identity;q=0"));
+    assert!(accept.quality("identity") == 0.0);
+
+    let accept = Accept::charsets(Some("ISO-8859-1, utf-8;q=0.7"));
+    assert!(accept.best_match(&["UTF-8", "iso-8859-1"]) == Some("iso-8859-1"));
+}
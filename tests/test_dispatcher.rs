@@ -0,0 +1,104 @@
+// Test the dispatcher.
+
+extern crate sharp_pencil;
+extern crate serde_json;
+extern crate hyper;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use hyper::server::Server;
+use serde_json::Value;
+
+use sharp_pencil::{Dispatcher, Pencil, PencilResult, ProxyFix, Request, Response, ViewArgs};
+
+
+fn named_app(name: &str) -> Pencil {
+    let mut app = Pencil::new("/test");
+    app.config.set("NAME", Value::String(name.to_string()));
+    app
+}
+
+fn find(dispatcher: &Dispatcher, host: Option<&str>, path: &str) -> (String, String) {
+    let (app, prefix) = dispatcher.find_application(host, path);
+    let name = app.config.get("NAME").unwrap().as_str().unwrap().to_string();
+    (name, prefix.to_string())
+}
+
+fn describe(request: &mut Request) -> PencilResult {
+    let mut values = ViewArgs::new();
+    values.insert(String::from("id"), String::from("2"));
+    let url = request.url_for("user", &values)?;
+    let name = request.app.config.get("NAME").unwrap().as_str().unwrap();
+    Ok(Response::from(format!("{} {} {} {}", name, request.path(), request.script_root, url)))
+}
+
+/// An application answering with what it sees of the request.
+fn describing_app(name: &str) -> Pencil {
+    let mut app = named_app(name);
+    app.get("/users/<id:int>", "user", describe);
+    app
+}
+
+/// Run the dispatcher on a free port.
+fn serve(dispatcher: Dispatcher) -> SocketAddr {
+    let mut listening = Server::http("127.0.0.1:0").unwrap().handle_threads(dispatcher, 1).unwrap();
+    // Detach the server, dropping `Listening` waits for it forever.
+    listening.close().unwrap();
+    listening.socket
+}
+
+/// Send a request to the server at `addr` and return the body.
+fn get(addr: SocketAddr, path: &str, headers: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n", path, headers).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let body_start = response.find("\r\n\r\n").unwrap() + 4;
+    response[body_start..].to_string()
+}
+
+#[test]
+fn test_dispatcher_find_application() {
+    let mut dispatcher = Dispatcher::new(named_app("public"));
+    dispatcher.mount("/admin/", named_app("admin"));
+    dispatcher.mount("/admin/stats", named_app("stats"));
+    dispatcher.mount_host("API.example.com", named_app("api"));
+    let found = |host, path| find(&dispatcher, host, path);
+    assert!(found(Some("example.com"), "/") == ("public".to_string(), "".to_string()));
+    assert!(found(Some("example.com"), "/administrator") == ("public".to_string(), "".to_string()));
+    assert!(found(Some("example.com"), "/admin") == ("admin".to_string(), "/admin".to_string()));
+    assert!(found(None, "/admin/users") == ("admin".to_string(), "/admin".to_string()));
+    assert!(found(None, "/admin/stats/1") == ("stats".to_string(), "/admin/stats".to_string()));
+    assert!(found(Some("api.example.com"), "/admin") == ("api".to_string(), "".to_string()));
+}
+
+#[test]
+fn test_dispatcher_requests() {
+    let mut dispatcher = Dispatcher::new(describing_app("public"));
+    dispatcher.mount("/admin", describing_app("admin"));
+    dispatcher.mount_host("api.example.com", describing_app("api"));
+    let addr = serve(dispatcher);
+    assert!(get(addr, "/users/1", "") == "public /users/1  /users/2");
+    assert!(get(addr, "/admin/users/1?page=2", "") == "admin /users/1 /admin /admin/users/2");
+    // Without a proxy fix forwarded hosts are ignored.
+    assert!(get(addr, "/admin/users/1", "X-Forwarded-Host: api.example.com\r\n") == "admin /users/1 /admin /admin/users/2");
+}
+
+#[test]
+fn test_dispatcher_proxy_fix() {
+    let mut proxy_fix = ProxyFix::new();
+    proxy_fix.x_host = 1;
+    proxy_fix.x_prefix = 1;
+    let mut dispatcher = Dispatcher::new(describing_app("public"));
+    // The proxy fix of the dispatcher is not applied twice.
+    let mut admin = describing_app("admin");
+    admin.enable_proxy_fix(proxy_fix.clone());
+    dispatcher.mount("/admin", admin);
+    dispatcher.mount_host("api.example.com", describing_app("api"));
+    dispatcher.enable_proxy_fix(proxy_fix);
+    let addr = serve(dispatcher);
+    assert!(get(addr, "/users/1", "X-Forwarded-Host: api.example.com\r\n") == "api /users/1  /users/2");
+    assert!(get(addr, "/users/1", "") == "public /users/1  /users/2");
+    assert!(get(addr, "/admin/users/1", "X-Forwarded-Prefix: /eu\r\n") == "admin /users/1 /eu/admin /eu/admin/users/2");
+}
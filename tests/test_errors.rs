@@ -2,9 +2,18 @@
 
 extern crate sharp_pencil;
 extern crate hyper;
+extern crate serde_json;
 
-use sharp_pencil::http_errors::NotFound;
+use std::num::ParseIntError;
+
+use sharp_pencil::http_errors::{NotFound, ServiceUnavailable};
+use sharp_pencil::{HTTPError, PencilError, PenHTTPError, PenUserError, PenError, UserError};
+use sharp_pencil::{Pencil, Module, Request, Response, PencilResult};
+use sharp_pencil::{ProblemJSONErrorRenderer, NegotiatingErrorRenderer};
+use sharp_pencil::method::Method::Get;
+use sharp_pencil::testing::read_body;
 use hyper::header::ContentType;
+use serde_json::Value;
 
 
 #[test]
@@ -42,3 +51,176 @@ fn test_http_error_to_response() {
     assert!(*response.content_type().unwrap() ==
             ContentType::html());
 }
+
+
+#[test]
+fn test_http_error_registered_status() {
+    assert!(HTTPError::new(402).code() == 402);
+    assert!(HTTPError::new(423).name() == "Locked");
+    assert!(HTTPError::new(451).code() == 451);
+    assert!(HTTPError::new(451).name() == "Unavailable For Legal Reasons");
+    assert!(HTTPError::new(451).get_description() == "The requested resource is unavailable for legal reasons.");
+    assert!(HTTPError::new(299).code() == 500);
+    assert!(HTTPError::new(599).code() == 500);
+}
+
+
+#[test]
+fn test_http_error_details() {
+    let error = ServiceUnavailable.with_description("Back soon.").with_retry_after(120);
+    assert!(error.code() == 503);
+    assert!(error.to_string() == "Back soon.");
+    let response = error.to_response();
+    assert!(response.status_code == 503);
+    assert!(response.headers.get_raw("Retry-After").unwrap()[0] == b"120".to_vec());
+
+    let response = HTTPError::unauthorized("Basic realm=\"admin\"").to_response();
+    assert!(response.status_code == 401);
+    assert!(response.headers.get_raw("WWW-Authenticate").unwrap()[0] == b"Basic realm=\"admin\"".to_vec());
+}
+
+
+#[test]
+fn test_http_error_problem_details() {
+    assert!(NotFound.problem_type() == "about:blank");
+    assert!(NotFound.extensions().is_empty());
+    let error = HTTPError::new(403)
+        .with_problem_type("https://example.com/probs/out-of-credit")
+        .with_extension("balance", Value::from(30));
+    assert!(error.code() == 403);
+    assert!(error.problem_type() == "https://example.com/probs/out-of-credit");
+    assert!(error.extensions().get("balance") == Some(&Value::from(30)));
+}
+
+
+#[test]
+fn test_pencil_error_from() {
+    match PencilError::from(NotFound) {
+        PenHTTPError(e) => assert!(e.code() == 404),
+        _ => panic!("HTTPError should keep its variant"),
+    }
+    match PencilError::from(UserError::new("oops")) {
+        PenUserError(e) => assert!(e.desc == "oops"),
+        _ => panic!("UserError should keep its variant"),
+    }
+    let parse_error = "forty-two".parse::<i32>().unwrap_err();
+    let error = PencilError::from(parse_error.clone());
+    match error {
+        PenError(ref e) => assert!(e.downcast_ref::<ParseIntError>() == Some(&parse_error)),
+        _ => panic!("Other errors should be boxed"),
+    }
+    assert!(error.as_error().is::<ParseIntError>());
+    assert!(error.to_string() == parse_error.to_string());
+}
+
+
+fn buy(_: &mut Request) -> PencilResult {
+    let error = HTTPError::new(403)
+        .with_problem_type("https://example.com/probs/out-of-credit")
+        .with_description("Your current balance is 30, but that costs 50.")
+        .with_extension("balance", Value::from(30));
+    Err(PenHTTPError(error))
+}
+
+
+fn raw_header(response: &Response, name: &str) -> Option<String> {
+    response.headers.get_raw(name).map(|values| String::from_utf8(values[0].clone()).unwrap())
+}
+
+
+fn body_json(response: Response) -> Value {
+    serde_json::from_slice(&read_body(response)).unwrap()
+}
+
+
+#[test]
+fn test_problem_json_error_renderer() {
+    let mut app = Pencil::new("/test");
+    app.set_error_renderer(ProblemJSONErrorRenderer);
+    app.get("/buy", "buy", buy);
+    let client = app.test_client();
+
+    let response = client.get("/buy?item=42");
+    assert!(response.status_code == 403);
+    assert!(raw_header(&response, "Content-Type") == Some(String::from("application/problem+json")));
+    let problem = body_json(response);
+    assert!(problem["type"] == "https://example.com/probs/out-of-credit");
+    assert!(problem["title"] == "Forbidden");
+    assert!(problem["status"] == 403);
+    assert!(problem["detail"] == "Your current balance is 30, but that costs 50.");
+    assert!(problem["instance"] == "/buy?item=42");
+    assert!(problem["balance"] == 30);
+
+    // Routing errors are rendered too, with their headers.
+    let response = client.get("/missing");
+    assert!(response.status_code == 404);
+    let problem = body_json(response);
+    assert!(problem["type"] == "about:blank");
+    assert!(problem["title"] == "Not Found");
+    let response = client.open("POST", "/buy", &[]);
+    assert!(response.status_code == 405);
+    assert!(raw_header(&response, "Allow").is_some());
+    assert!(raw_header(&response, "Content-Type") == Some(String::from("application/problem+json")));
+}
+
+
+#[test]
+fn test_negotiating_error_renderer() {
+    let mut app = Pencil::new("/test");
+    app.set_error_renderer(NegotiatingErrorRenderer);
+    app.get("/buy", "buy", buy);
+    let client = app.test_client();
+
+    let response = client.open("GET", "/buy", &[("Accept", "application/json")]);
+    assert!(response.status_code == 403);
+    assert!(raw_header(&response, "Content-Type") == Some(String::from("application/problem+json")));
+    assert!(raw_header(&response, "Vary") == Some(String::from("Accept")));
+    assert!(body_json(response)["balance"] == 30);
+
+    let response = client.open("GET", "/buy", &[("Accept", "text/html,application/json;q=0.9")]);
+    assert!(response.status_code == 403);
+    assert!(*response.content_type().unwrap() == ContentType::html());
+    assert!(raw_header(&response, "Vary") == Some(String::from("Accept")));
+
+    let response = client.get("/buy");
+    assert!(*response.content_type().unwrap() == ContentType::html());
+}
+
+
+#[test]
+fn test_error_renderer_selection() {
+    let mut app = Pencil::new("/test");
+    app.config.set("ERROR_RENDERER", Value::from("problem+json"));
+    app.get("/buy", "buy", buy);
+    let mut module = Module::new("pages", "/test");
+    module.set_error_renderer(|error: &HTTPError, _: &Request| {
+        let mut response = Response::from(format!("page error {}", error.code()));
+        response.status_code = error.code();
+        response
+    });
+    module.route("/pages/buy", &[Get], "buy", buy);
+    app.register_module(module);
+    let client = app.test_client();
+
+    // The config picks the application wide renderer.
+    let response = client.get("/buy");
+    assert!(raw_header(&response, "Content-Type") == Some(String::from("application/problem+json")));
+    assert!(body_json(response)["status"] == 403);
+
+    // Requests of the module use its own renderer.
+    let response = client.get("/pages/buy");
+    assert!(response.status_code == 403);
+    assert!(read_body(response) == b"page error 403");
+
+    // An unknown name falls back to HTML.
+    app.config.set("ERROR_RENDERER", Value::from("yaml"));
+    let response = app.test_client().get("/buy");
+    assert!(response.status_code == 403);
+    assert!(*response.content_type().unwrap() == ContentType::html());
+
+    // A renderer set on the application wins over the config.
+    app.config.set("ERROR_RENDERER", Value::from("html"));
+    app.set_error_renderer(ProblemJSONErrorRenderer);
+    let response = app.test_client().get("/buy");
+    assert!(raw_header(&response, "Content-Type") == Some(String::from("application/problem+json")));
+}
@@ -3,13 +3,26 @@
 extern crate sharp_pencil;
 extern crate url;
 extern crate hyper;
+extern crate serde_json;
 
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::env;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
 
-use hyper::header::Location;
+use hyper::header::{Location, ETag, EntityTag, LastModified, CacheControl, CacheDirective, ContentType, ContentLength};
+use hyper::header::ByteRangeSpec::{FromTo, AllFrom, Last};
+use serde_json::Value;
 
-use sharp_pencil::{PenHTTPError, PenUserError};
-use sharp_pencil::{abort, redirect, safe_join, escape};
+use sharp_pencil::{PenHTTPError, PenUserError, PenError, PenResponse, PencilResult};
+use sharp_pencil::{abort, negotiate, redirect, safe_join, safe_join_with, escape, send_file, Pencil, ProxyFix, Request, SymlinkPolicy};
+use sharp_pencil::{Event, EventHub, EventStream, Response, SendFile, AssetSource, EmbeddedAssets, EmbeddedFile, StaticManifest, StaticUrlStyle};
+use sharp_pencil::helpers::resolve_ranges;
+use sharp_pencil::testing::read_body;
+use sharp_pencil::wrappers::ResponseBody;
 
 
 #[test]
@@ -18,12 +31,53 @@ fn test_abort() {
     let pencil_error = result.err().unwrap();
     let http_error = match pencil_error {
         PenHTTPError(e) => Some(e),
-        PenUserError(_) => None,
+        PenUserError(_) | PenError(_) | PenResponse(_) => None,
     };
     assert!(http_error.unwrap().code() == 404);
 }
 
 
+fn user(request: &mut Request) -> PencilResult {
+    let mut context = BTreeMap::new();
+    context.insert("name", "admin");
+    negotiate(request, "user.html", &context)
+}
+
+
+#[test]
+fn test_negotiate() {
+    let mut app = Pencil::new("/test");
+    app.handlebars_registry.write().unwrap()
+        .register_template_string("user.html", "<h1>{{name}}</h1>").unwrap();
+    app.get("/user", "user", user);
+    let client = app.test_client();
+    let vary = |response: &Response| response.headers.get_raw("Vary").map(|values| values[0].clone());
+
+    let response = client.open("GET", "/user", &[("Accept", "text/html")]);
+    assert!(response.status_code == 200);
+    assert!(vary(&response) == Some(b"Accept".to_vec()));
+    assert!(read_body(response) == b"<h1>admin</h1>");
+
+    let response = client.open("GET", "/user", &[("Accept", "application/json")]);
+    assert!(response.status_code == 200);
+    assert!(*response.content_type().unwrap() == ContentType::json());
+    assert!(vary(&response) == Some(b"Accept".to_vec()));
+    let body: Value = serde_json::from_slice(&read_body(response)).unwrap();
+    assert!(body["name"] == "admin");
+
+    // HTML wins a tie and without an `Accept` header.
+    let response = client.open("GET", "/user", &[("Accept", "application/json, text/html")]);
+    assert!(read_body(response) == b"<h1>admin</h1>");
+    let response = client.open("GET", "/user", &[("Accept", "text/*;q=0.5, application/json;q=0.8")]);
+    assert!(*response.content_type().unwrap() == ContentType::json());
+    assert!(read_body(client.get("/user")) == b"<h1>admin</h1>");
+
+    let response = client.open("GET", "/user", &[("Accept", "image/png")]);
+    assert!(response.status_code == 406);
+    assert!(vary(&response) == Some(b"Accept".to_vec()));
+}
+
+
 #[test]
 fn test_redirect() {
     let result = redirect("http://localhost/füübär", 302);
@@ -46,6 +100,39 @@ fn test_safe_join() {
     let path = safe_join("foo", "bar/baz").unwrap();
     assert!(path == PathBuf::from("foo/bar/baz"));
     assert!(safe_join("foo", "../bar/baz").is_none());
+    assert!(safe_join("foo", "bar/../../etc/passwd").is_none());
+    assert!(safe_join("foo", "/etc/passwd").is_none());
+    assert!(safe_join("foo", "bar\\..\\..\\baz").is_none());
+    assert!(safe_join("foo", "bar\0.txt").is_none());
+    assert!(safe_join("foo", "bar/../baz").unwrap() == Path::new("foo/baz"));
+    assert!(safe_join("foo", "./bar/./baz/").unwrap() == Path::new("foo/bar/baz"));
+    assert!(safe_join("foo", "").unwrap() == Path::new("foo"));
+}
+
+
+#[cfg(unix)]
+#[test]
+fn test_safe_join_with_symlinks() {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    let base = env::temp_dir().join(format!("pencil-safe-join-{}", process::id()));
+    let root = base.join("root");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(base.join("secret.txt"), "secret").unwrap();
+    fs::write(root.join("file.txt"), "file").unwrap();
+    symlink(root.join("file.txt"), root.join("inside.txt")).unwrap();
+    symlink(base.join("secret.txt"), root.join("outside.txt")).unwrap();
+    let root = root.to_str().unwrap();
+
+    assert!(safe_join_with(root, "file.txt", SymlinkPolicy::WithinRoot).is_some());
+    assert!(safe_join_with(root, "inside.txt", SymlinkPolicy::WithinRoot).is_some());
+    assert!(safe_join_with(root, "outside.txt", SymlinkPolicy::WithinRoot).is_none());
+    assert!(safe_join_with(root, "missing.txt", SymlinkPolicy::WithinRoot).is_none());
+    assert!(safe_join_with(root, "outside.txt", SymlinkPolicy::Follow).is_some());
+    assert!(safe_join_with(root, "file.txt", SymlinkPolicy::Deny).is_some());
+    assert!(safe_join_with(root, "inside.txt", SymlinkPolicy::Deny).is_none());
+    fs::remove_dir_all(&base).unwrap();
 }
 
 
@@ -55,3 +142,501 @@ fn test_escape() {
     assert!(escape(String::from("<>")) == "&lt;&gt;");
     assert!(escape(String::from("\"foo\"")) == "&quot;foo&quot;");
 }
+
+
+#[test]
+fn test_send_file_validators() {
+    let response = send_file("Cargo.toml", "text/plain".parse().unwrap(), false).ok().unwrap();
+    let etag: Option<&ETag> = response.headers.get();
+    let last_modified: Option<&LastModified> = response.headers.get();
+    assert!(!etag.unwrap().weak);
+    assert!(last_modified.is_some());
+    let again = send_file("Cargo.toml", "text/plain".parse().unwrap(), false).ok().unwrap();
+    assert!(again.headers.get::<ETag>() == etag);
+}
+
+
+#[test]
+fn test_static_cache_control() {
+    let mut app = Pencil::new("/test");
+    assert!(app.get_static_cache_control("app.css").is_none());
+    app.set_static_cache_control(".CSS", vec![CacheDirective::MaxAge(3600)]);
+    app.set_static_cache_control("*", vec![CacheDirective::NoCache]);
+    assert!(app.get_static_cache_control("css/app.css") == Some(CacheControl(vec![CacheDirective::MaxAge(3600)])));
+    assert!(app.get_static_cache_control("index.html") == Some(CacheControl(vec![CacheDirective::NoCache])));
+}
+
+
+fn alphabet(request: &mut Request) -> PencilResult {
+    SendFile::new(Cursor::new(b"abcdefghijklmnopqrst".to_vec()))
+        .with_mimetype("text/plain".parse().unwrap())
+        .with_etag("v1")
+        .send(request)
+}
+
+
+#[test]
+fn test_range_requests() {
+    let mut app = Pencil::new("/test");
+    app.get("/alphabet", "alphabet", alphabet);
+    let client = app.test_client();
+    let header = |response: &Response, name: &str| {
+        response.headers.get_raw(name).map(|values| String::from_utf8(values[0].clone()).unwrap())
+    };
+
+    let response = client.open("GET", "/alphabet", &[("Range", "bytes=30-40")]);
+    assert!(response.status_code == 416);
+    assert!(header(&response, "Content-Range") == Some(String::from("bytes */20")));
+
+    let response = client.open("GET", "/alphabet", &[("Range", "bytes=2-5")]);
+    assert!(response.status_code == 206);
+    assert!(header(&response, "Content-Range") == Some(String::from("bytes 2-5/20")));
+    assert!(response.headers.get::<ContentLength>() == Some(&ContentLength(4)));
+    assert!(read_body(response) == b"cdef");
+
+    let response = client.open("GET", "/alphabet", &[("Range", "bytes=0-1,-3")]);
+    assert!(response.status_code == 206);
+    let content_type = header(&response, "Content-Type").unwrap();
+    assert!(content_type.starts_with("multipart/byteranges; boundary="));
+    let boundary = content_type["multipart/byteranges; boundary=".len()..].to_string();
+    let content_length = response.headers.get::<ContentLength>().unwrap().0;
+    let body = read_body(response);
+    assert!(body.len() as u64 == content_length);
+    assert!(String::from_utf8(body).unwrap() == format!(
+        "--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/20\r\n\r\nab\r\n\
+         --{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 17-19/20\r\n\r\nrst\r\n\
+         --{0}--\r\n", boundary));
+
+    // A stale `If-Range` validator gets the whole contents.
+    let response = client.open("GET", "/alphabet", &[("Range", "bytes=2-5"), ("If-Range", "\"v0\"")]);
+    assert!(response.status_code == 200);
+    assert!(header(&response, "Content-Range").is_none());
+    assert!(read_body(response) == b"abcdefghijklmnopqrst");
+    let response = client.open("GET", "/alphabet", &[("Range", "bytes=2-5"), ("If-Range", "\"v1\"")]);
+    assert!(response.status_code == 206);
+    assert!(read_body(response) == b"cdef");
+}
+
+
+#[test]
+fn test_resolve_ranges() {
+    assert!(resolve_ranges(&[FromTo(0, 99)], 10) == vec![(0, 9)]);
+    assert!(resolve_ranges(&[FromTo(10, 20)], 10).is_empty());
+    assert!(resolve_ranges(&[AllFrom(4)], 10) == vec![(4, 9)]);
+    assert!(resolve_ranges(&[Last(20)], 10) == vec![(0, 9)]);
+    assert!(resolve_ranges(&[Last(0)], 10).is_empty());
+    assert!(resolve_ranges(&[Last(1)], 0).is_empty());
+    assert!(resolve_ranges(&[FromTo(5, 7), FromTo(0, 2), FromTo(3, 4)], 10) == vec![(0, 7)]);
+    assert!(resolve_ranges(&[FromTo(0, 1), FromTo(5, 6), Last(2)], 10) == vec![(0, 1), (5, 6), (8, 9)]);
+}
+
+
+#[test]
+fn test_embedded_assets() {
+    let files = [
+        EmbeddedFile { path: "css/app.css", data: b"body {}", mimetype: "text/css", etag: "abc", modified: Some(0) },
+    ];
+    let assets = EmbeddedAssets::new(&files);
+    let asset = assets.get("css/app.css").unwrap();
+    assert!(&*asset.data == b"body {}");
+    assert!(asset.len() == 7);
+    assert!(asset.mimetype.to_string() == "text/css");
+    assert!(asset.etag == "abc");
+    assert!(assets.get("app.css").is_none());
+    assert!(assets.paths() == ["css/app.css"]);
+    assert!(assets.list() == ["css/app.css"]);
+
+    // Live files win, missing ones fall back to the embedded files.
+    let assets = EmbeddedAssets::new(&files).live(".");
+    assert!(assets.get("Cargo.toml").is_some());
+    assert!(assets.get("css/app.css").is_some());
+    assert!(assets.get("../Cargo.toml").is_none());
+}
+
+
+fn page(request: &mut Request) -> PencilResult {
+    let mut context = BTreeMap::new();
+    context.insert("title", "Embedded");
+    request.app.render_template("page.html", &context)
+}
+
+
+#[test]
+fn test_embedded_assets_app() {
+    let static_files = [
+        EmbeddedFile { path: "css/app.css", data: b"body {}", mimetype: "text/css", etag: "abc", modified: Some(0) },
+        EmbeddedFile { path: "index.html", data: b"<p>home</p>", mimetype: "text/html", etag: "def", modified: None },
+    ];
+    let template_files = [
+        EmbeddedFile { path: "page.html", data: b"<h1>{{title}}</h1>", mimetype: "text/html", etag: "ghi", modified: None },
+    ];
+    let mut app = Pencil::new("/nonexistent");
+    app.enable_static_file_handling();
+    app.set_static_assets(EmbeddedAssets::new(&static_files));
+    app.set_template_assets(EmbeddedAssets::new(&template_files));
+    app.register_template("page.html");
+    app.get("/page", "page", page);
+    let client = app.test_client();
+
+    let response = client.get("/static/css/app.css");
+    assert!(response.status_code == 200);
+    assert!(*response.content_type().unwrap() == ContentType("text/css".parse().unwrap()));
+    assert!(response.headers.get::<ETag>() == Some(&ETag(EntityTag::new(false, String::from("abc")))));
+    assert!(response.headers.get::<LastModified>().is_some());
+    assert!(read_body(response) == b"body {}");
+
+    let response = client.open("GET", "/static/css/app.css", &[("If-None-Match", "\"abc\"")]);
+    assert!(response.status_code == 304);
+    assert!(read_body(response).is_empty());
+    let response = client.open("GET", "/static/css/app.css", &[("If-None-Match", "\"old\"")]);
+    assert!(response.status_code == 200);
+
+    assert!(read_body(client.get("/static/")) == b"<p>home</p>");
+    assert!(client.get("/static/css/missing.css").status_code == 404);
+
+    let response = client.get("/page");
+    assert!(response.status_code == 200);
+    assert!(read_body(response) == b"<h1>Embedded</h1>");
+}
+
+
+#[test]
+fn test_static_manifest() {
+    let mut manifest = StaticManifest::new();
+    manifest.insert_hash("css/app.css", "3f9a1c2b5d", StaticUrlStyle::HashedName);
+    manifest.insert_hash("LICENSE", "73cb3858", StaticUrlStyle::HashedName);
+    manifest.insert_hash("app.js", "0badc0de", StaticUrlStyle::QueryString);
+    assert!(manifest.versioned("css/app.css") == Some("css/app.3f9a1c2b.css"));
+    assert!(manifest.versioned("LICENSE") == Some("LICENSE.73cb3858"));
+    assert!(manifest.versioned("app.js") == Some("app.js?v=0badc0de"));
+    assert!(manifest.resolve("css/app.3f9a1c2b.css", None) == Some("css/app.css"));
+    assert!(manifest.resolve("css/app.css", None).is_none());
+    assert!(manifest.resolve("app.js", Some("0badc0de")) == Some("app.js"));
+    assert!(manifest.resolve("app.js", Some("old")).is_none());
+
+    let manifest = StaticManifest::from_json(r#"{"main.js": "/main.1a2b.js"}"#).unwrap();
+    assert!(manifest.versioned("main.js") == Some("main.1a2b.js"));
+    assert!(manifest.resolve("main.1a2b.js", None) == Some("main.1a2b.js"));
+
+    let mut app = Pencil::new("/test");
+    app.set_static_manifest(manifest);
+    assert!(app.static_url("main.js") == "/static/main.1a2b.js");
+    assert!(app.static_url("app.css") == "/static/app.css");
+
+    // Hashes are cut by characters, not bytes.
+    let mut manifest = StaticManifest::new();
+    manifest.insert_hash("app.css", "ünïcödé-hash", StaticUrlStyle::HashedName);
+    manifest.insert_hash("app.js", "ünïcödé-hash", StaticUrlStyle::QueryString);
+    assert!(manifest.versioned("app.css") == Some("app.ünïcödé-.css"));
+    assert!(manifest.versioned("app.js") == Some("app.js?v=ünïcödé-"));
+    app.set_static_manifest(manifest);
+    assert!(app.static_url("app.css") == "/static/app.%C3%BCn%C3%AFc%C3%B6d%C3%A9-.css");
+    assert!(app.static_url("app.js") == "/static/app.js?v=%C3%BCn%C3%AFc%C3%B6d%C3%A9-");
+}
+
+#[test]
+fn test_versioned_static_files() {
+    use std::fs;
+
+    let root = env::temp_dir().join(format!("pencil-versioned-{}", process::id()));
+    fs::create_dir_all(root.join("static")).unwrap();
+    fs::write(root.join("static/app.css"), "body {}").unwrap();
+    let mut app = Pencil::new(root.to_str().unwrap());
+    app.enable_static_file_handling();
+    app.set_static_cache_control("*", vec![CacheDirective::NoCache]);
+    let immutable = CacheControl(vec![
+        CacheDirective::Public,
+        CacheDirective::MaxAge(31_536_000),
+        CacheDirective::Extension(String::from("immutable"), None),
+    ]);
+    let no_cache = CacheControl(vec![CacheDirective::NoCache]);
+
+    app.hash_static_files(StaticUrlStyle::HashedName).unwrap();
+    let url = app.static_url("app.css");
+    assert!(url.starts_with("/static/app.") && url.ends_with(".css") && url != "/static/app.css");
+    let client = app.test_client();
+    let response = client.get(&url);
+    assert!(response.status_code == 200);
+    assert!(response.headers.get::<CacheControl>() == Some(&immutable));
+    assert!(read_body(response) == b"body {}");
+    let response = client.get("/static/app.css");
+    assert!(response.headers.get::<CacheControl>() == Some(&no_cache));
+    assert!(client.get("/static/app.00000000.css").status_code == 404);
+
+    app.hash_static_files(StaticUrlStyle::QueryString).unwrap();
+    let url = app.static_url("app.css");
+    assert!(url.starts_with("/static/app.css?v="));
+    let client = app.test_client();
+    let response = client.get(&url);
+    assert!(response.status_code == 200);
+    assert!(response.headers.get::<CacheControl>() == Some(&immutable));
+    assert!(read_body(response) == b"body {}");
+    // A stale version gets the current file with the normal policy.
+    let response = client.get("/static/app.css?v=00000000");
+    assert!(response.status_code == 200);
+    assert!(response.headers.get::<CacheControl>() == Some(&no_cache));
+    assert!(read_body(response) == b"body {}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+
+#[test]
+fn test_static_url_script_root() {
+    let mut app = Pencil::new("/test");
+    app.set_application_root("/shop");
+    app.enable_static_file_handling();
+    let mut proxy_fix = ProxyFix::new();
+    proxy_fix.x_prefix = 1;
+    proxy_fix.trust("127.0.0.1/32").unwrap();
+    app.enable_proxy_fix(proxy_fix);
+    app.get("/page", "page", |request: &mut Request| {
+        let rendered = request.app.render_template_string(r#"{{static_url "app.js"}}"#, &())?;
+        let page = request.app.static_url("app.css") + " " + &String::from_utf8(read_body(rendered)).unwrap();
+        Ok(Response::from(page))
+    });
+    assert!(app.static_url("app.css") == "/shop/static/app.css");
+    let page = app.render_template_string(r#"{{static_url "app.js"}}"#, &()).ok().unwrap();
+    assert!(read_body(page) == b"/shop/static/app.js");
+
+    let client = app.test_client();
+    assert!(read_body(client.get("/shop/page")) == b"/shop/static/app.css /shop/static/app.js");
+    let response = client.open("GET", "/shop/page", &[("X-Forwarded-Prefix", "/eu")]);
+    assert!(read_body(response) == b"/eu/shop/static/app.css /eu/shop/static/app.js");
+    assert!(app.static_url("app.css") == "/shop/static/app.css");
+}
+
+
+#[test]
+fn test_send_file_from_reader() {
+    let response = SendFile::new(Cursor::new(b"a,b\n".to_vec()))
+        .with_download_name("März report.csv")
+        .with_attachment(true)
+        .into_response().ok().unwrap();
+    let disposition = response.headers.get_raw("Content-Disposition").unwrap();
+    assert!(disposition[0] == b"attachment; filename=\"M_rz report.csv\"; filename*=UTF-8''M%C3%A4rz%20report.csv".to_vec());
+    assert!(response.headers.get::<ContentType>().unwrap().to_string() == "text/csv");
+    assert!(response.headers.get::<ContentLength>() == Some(&ContentLength(4)));
+
+    let response = SendFile::new(Cursor::new(Vec::new())).into_response().ok().unwrap();
+    assert!(response.headers.get_raw("Content-Disposition").is_none());
+    assert!(response.headers.get::<ContentType>().unwrap().to_string() == "application/octet-stream");
+}
+
+
+#[test]
+fn test_stream_and_channel_bodies() {
+    let chunks: Vec<std::io::Result<&str>> = vec![Ok("a"), Ok(""), Ok("b")];
+    let response = Response::stream(chunks);
+    let mut body = Vec::new();
+    response.body.unwrap().write_body(&mut ResponseBody::new(&mut body)).unwrap();
+    assert!(body == b"ab");
+
+    let (response, sender) = Response::channel();
+    let producer = thread::spawn(move || {
+        sender.send("a").unwrap();
+        sender.send(b"b".to_vec()).unwrap();
+    });
+    let mut body = Vec::new();
+    response.body.unwrap().write_body(&mut ResponseBody::new(&mut body)).unwrap();
+    producer.join().unwrap();
+    assert!(body == b"ab");
+
+    let (response, sender) = Response::channel();
+    drop(response);
+    assert!(sender.send("a").is_err());
+}
+
+
+#[test]
+fn test_event_stream() {
+    let event = Event::new().with_comment("hi").with_id("1\n2").with_data("a\r\nb\n");
+    assert!(event.to_string() == ": hi\nid: 12\ndata: a\ndata: b\ndata: \n\n");
+
+    let hub = EventHub::new();
+    let response = hub.subscribe();
+    assert!(response.headers.get::<ContentType>().unwrap().to_string() == "text/event-stream");
+    assert!(hub.broadcast(&Event::new().with_data("x")) == 1);
+    drop(hub);
+    let mut body = Vec::new();
+    response.body.unwrap().write_body(&mut ResponseBody::new(&mut body)).unwrap();
+    assert!(body == b"data: x\n\n");
+}
+
+
+#[test]
+fn test_event_stream_keep_alive() {
+    let (response, sender) = EventStream::new().with_keep_alive(Some(Duration::from_millis(20))).open();
+    let producer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        sender.send(&Event::new().with_data("x")).unwrap();
+    });
+    let mut body = Vec::new();
+    response.body.unwrap().write_body(&mut ResponseBody::new(&mut body)).unwrap();
+    producer.join().unwrap();
+    assert!(body.starts_with(b": keep-alive\n\n"));
+    assert!(body.ends_with(b"\n\ndata: x\n\n"));
+
+    let (response, sender) = EventStream::new().with_keep_alive(None).open();
+    let producer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        sender.send(&Event::new().with_data("x")).unwrap();
+    });
+    let mut body = Vec::new();
+    response.body.unwrap().write_body(&mut ResponseBody::new(&mut body)).unwrap();
+    producer.join().unwrap();
+    assert!(body == b"data: x\n\n");
+}
+
+
+#[test]
+fn test_event_hub_eviction() {
+    let hub = EventHub::new();
+    let behind = hub.subscribe();
+    let gone = hub.subscribe();
+    drop(gone);
+    assert!(hub.subscriber_count() == 2);
+    assert!(hub.broadcast(&Event::new().with_data("0")) == 1);
+
+    // Broadcasting never waits for a client that stopped reading.
+    let mut sent = 1;
+    while hub.broadcast(&Event::new().with_data(sent.to_string())) == 1 {
+        sent += 1;
+        assert!(sent < 1000);
+    }
+    assert!(hub.subscriber_count() == 0);
+    let mut body = Vec::new();
+    behind.body.unwrap().write_body(&mut ResponseBody::new(&mut body)).unwrap();
+    let expected: String = (0..sent).map(|n| format!("data: {}\n\n", n)).collect();
+    assert!(body == expected.into_bytes());
+}
+
+
+fn last_event(request: &mut Request) -> PencilResult {
+    Ok(Response::from(request.last_event_id().unwrap_or_default()))
+}
+
+
+#[test]
+fn test_last_event_id() {
+    let mut app = Pencil::new("/test");
+    app.get("/events", "events", last_event);
+    let client = app.test_client();
+    assert!(read_body(client.open("GET", "/events", &[("Last-Event-ID", "41")])) == b"41");
+    assert!(read_body(client.get("/events")).is_empty());
+}
+
+
+#[test]
+fn test_precompressed_static_files() {
+    use std::fs;
+
+    let root = env::temp_dir().join(format!("pencil-precompressed-{}", process::id()));
+    fs::create_dir_all(root.join("static")).unwrap();
+    fs::write(root.join("static/app.js"), "plain").unwrap();
+    fs::write(root.join("static/app.js.gz"), "gzip").unwrap();
+    fs::write(root.join("static/app.js.br"), "br").unwrap();
+    let mut app = Pencil::new(root.to_str().unwrap());
+    app.enable_static_file_handling();
+    let client = app.test_client();
+    let fetch = |accept_encoding: &str| {
+        let response = client.open("GET", "/static/app.js", &[("Accept-Encoding", accept_encoding)]);
+        let encoding = response.headers.get_raw("Content-Encoding").map(|value| value[0].clone());
+        (encoding, read_body(response))
+    };
+
+    assert!(fetch("gzip, br") == (Some(b"br".to_vec()), b"br".to_vec()));
+    assert!(fetch("gzip, br;q=0.5") == (Some(b"gzip".to_vec()), b"gzip".to_vec()));
+    assert!(fetch("gzip;q=0.5") == (None, b"plain".to_vec()));
+    assert!(fetch("gzip;q=0.5, identity;q=0.1") == (Some(b"gzip".to_vec()), b"gzip".to_vec()));
+    assert!(fetch("deflate") == (None, b"plain".to_vec()));
+    let response = client.get("/static/app.js");
+    assert!(response.headers.get_raw("Content-Encoding").is_none());
+    assert!(response.headers.get_raw("Vary").unwrap()[0] == b"Accept-Encoding".to_vec());
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// The names of the entries of a JSON directory listing.
+fn listing_names(app: &Pencil, path: &str) -> Vec<String> {
+    let response = app.test_client().open("GET", path, &[("Accept", "application/json")]);
+    assert!(response.status_code == 200);
+    let listing: Value = serde_json::from_slice(&read_body(response)).unwrap();
+    listing["entries"].as_array().unwrap().iter()
+        .map(|entry| entry["name"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[test]
+fn test_static_directory_index() {
+    use std::fs;
+
+    let root = env::temp_dir().join(format!("pencil-autoindex-{}", process::id()));
+    fs::create_dir_all(root.join("static/site")).unwrap();
+    fs::create_dir_all(root.join("static/docs/guide")).unwrap();
+    fs::write(root.join("static/site/index.html"), "<h1>site</h1>").unwrap();
+    fs::write(root.join("static/docs/a.txt"), "aaa").unwrap();
+    fs::write(root.join("static/docs/b.txt"), "b").unwrap();
+    fs::write(root.join("static/docs/c.txt"), "cc").unwrap();
+    let mut app = Pencil::new(root.to_str().unwrap());
+    app.enable_static_file_handling();
+    assert!(app.test_client().get("/static/docs/").status_code == 404);
+    app.enable_static_directory_index(false);
+    assert!(read_body(app.test_client().get("/static/site/")) == b"<h1>site</h1>");
+    assert!(app.test_client().get("/static/docs/").status_code == 404);
+
+    app.enable_static_directory_index(true);
+    let client = app.test_client();
+    let response = client.get("/static/site/");
+    assert!(response.status_code == 200);
+    assert!(read_body(response) == b"<h1>site</h1>");
+    let response = client.get("/static/docs?sort=size");
+    assert!(response.status_code == 301);
+    assert!(response.headers.get::<Location>() == Some(&Location(String::from("/static/docs/?sort=size"))));
+
+    let response = client.get("/static/docs/");
+    assert!(response.status_code == 200);
+    assert!(response.headers.get::<ContentType>().unwrap().to_string().starts_with("text/html"));
+    let page = String::from_utf8(read_body(response)).unwrap();
+    assert!(page.contains("<h1>Index of /static/docs/</h1>"));
+    assert!(page.contains(r#"<a href="a.txt">a.txt</a>"#));
+    assert!(page.contains(r#"<a href="guide/">guide/</a>"#));
+    let response = client.open("GET", "/static/docs/", &[("Accept", "application/json")]);
+    assert!(response.headers.get::<ContentType>().unwrap().to_string().starts_with("application/json"));
+    assert!(client.open("GET", "/static/docs/", &[("Accept", "image/png")]).status_code == 406);
+
+    // Directories come first in any order.
+    assert!(listing_names(&app, "/static/docs/") == ["guide", "a.txt", "b.txt", "c.txt"]);
+    assert!(listing_names(&app, "/static/docs/?order=desc") == ["guide", "c.txt", "b.txt", "a.txt"]);
+    assert!(listing_names(&app, "/static/docs/?sort=size") == ["guide", "b.txt", "c.txt", "a.txt"]);
+    assert!(listing_names(&app, "/static/docs/?sort=size&order=desc") == ["guide", "a.txt", "c.txt", "b.txt"]);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_static_directory_index_symlinks() {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    let root = env::temp_dir().join(format!("pencil-autoindex-symlinks-{}", process::id()));
+    fs::create_dir_all(root.join("static/docs")).unwrap();
+    fs::create_dir_all(root.join("private")).unwrap();
+    fs::write(root.join("static/docs/guide.txt"), "guide").unwrap();
+    fs::write(root.join("private/secret.txt"), "secret").unwrap();
+    symlink(root.join("static/docs/guide.txt"), root.join("static/docs/manual.txt")).unwrap();
+    symlink(root.join("private/secret.txt"), root.join("static/docs/secret.txt")).unwrap();
+    symlink(root.join("private"), root.join("static/docs/private")).unwrap();
+    symlink("/", root.join("static/docs/system")).unwrap();
+    let mut app = Pencil::new(root.to_str().unwrap());
+    app.enable_static_file_handling();
+    app.enable_static_directory_index(true);
+
+    assert!(listing_names(&app, "/static/docs/") == ["guide.txt", "manual.txt"]);
+    let page = String::from_utf8(read_body(app.test_client().get("/static/docs/"))).unwrap();
+    assert!(!page.contains("secret") && !page.contains("private") && !page.contains("system"));
+    assert!(app.test_client().get("/static/docs/private/").status_code == 404);
+    assert!(app.test_client().get("/static/docs/system/").status_code == 404);
+    app.set_symlink_policy(SymlinkPolicy::Deny);
+    assert!(listing_names(&app, "/static/docs/") == ["guide.txt"]);
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,84 @@
+// Test the reverse proxy support.
+
+extern crate sharp_pencil;
+
+use sharp_pencil::{Pencil, PencilResult, ProxyFix, Request, Response, ViewArgs};
+use sharp_pencil::method::Get;
+use sharp_pencil::testing::read_body;
+
+
+fn describe(request: &mut Request) -> PencilResult {
+    let description = format!("{} {} {} {} {} {}", request.remote_addr(), request.scheme(), request.host(),
+                              request.script_root, request.is_secure(),
+                              request.external_url_for("describe", &ViewArgs::new())?);
+    Ok(Response::from(description))
+}
+
+fn proxied_app(proxy_fix: ProxyFix) -> Pencil {
+    let mut app = Pencil::new("/test");
+    app.enable_proxy_fix(proxy_fix);
+    app.route("/describe", &[Get], "describe", describe);
+    app.route("/docs/", &[Get], "docs", describe);
+    app
+}
+
+fn describe_with(app: &Pencil, headers: &[(&str, &str)]) -> String {
+    let response = app.test_client().open("GET", "/describe", headers);
+    assert!(response.status_code == 200);
+    String::from_utf8(read_body(response)).unwrap()
+}
+
+const FORWARDED_HEADERS: &[(&str, &str)] = &[
+    ("X-Forwarded-For", "203.0.113.7, 10.0.0.1"),
+    ("X-Forwarded-Proto", "https"),
+    ("X-Forwarded-Host", "shop.example.com"),
+    ("X-Forwarded-Prefix", "/eu/"),
+];
+
+
+#[test]
+fn test_proxy_fix_rewrites_request() {
+    let mut proxy_fix = ProxyFix::new();
+    proxy_fix.x_host = 1;
+    proxy_fix.x_prefix = 1;
+    let app = proxied_app(proxy_fix);
+    assert!(describe_with(&app, &[]) == "127.0.0.1:40000 http localhost  false http://localhost/describe");
+    assert!(describe_with(&app, FORWARDED_HEADERS) ==
+            "10.0.0.1:0 https shop.example.com /eu true https://shop.example.com/eu/describe");
+
+    // Routing redirects go to the URL the client requested.
+    let response = app.test_client().open("GET", "/docs", FORWARDED_HEADERS);
+    assert!(response.status_code == 301);
+    assert!(response.headers.get_raw("Location").unwrap()[0] == b"https://shop.example.com/eu/docs/".to_vec());
+}
+
+#[test]
+fn test_proxy_fix_forwarded_header() {
+    let mut proxy_fix = ProxyFix::new();
+    proxy_fix.forwarded = 1;
+    proxy_fix.x_port = 1;
+    let app = proxied_app(proxy_fix);
+    let headers = [("Forwarded", "for=\"[2001:db8::17]:4711\";proto=https;host=api.example.com"),
+                   ("X-Forwarded-Port", "8443"),
+                   ("X-Forwarded-Proto", "http")];
+    assert!(describe_with(&app, &headers) ==
+            "[2001:db8::17]:4711 https api.example.com:8443  true https://api.example.com:8443/describe");
+}
+
+#[test]
+fn test_proxy_fix_ignores_untrusted_peers() {
+    // The test client connects from 127.0.0.1.
+    let mut proxy_fix = ProxyFix::new();
+    proxy_fix.x_host = 1;
+    proxy_fix.x_prefix = 1;
+    proxy_fix.trust("10.0.0.0/8").unwrap();
+    let app = proxied_app(proxy_fix);
+    assert!(describe_with(&app, FORWARDED_HEADERS) == "127.0.0.1:40000 http localhost  false http://localhost/describe");
+    let response = app.test_client().open("GET", "/docs", FORWARDED_HEADERS);
+    assert!(response.headers.get_raw("Location").unwrap()[0] == b"http://localhost/docs/".to_vec());
+
+    let mut proxy_fix = ProxyFix::new();
+    proxy_fix.trust("127.0.0.0/8").unwrap();
+    let app = proxied_app(proxy_fix);
+    assert!(describe_with(&app, FORWARDED_HEADERS).starts_with("10.0.0.1:0 https localhost  true"));
+}